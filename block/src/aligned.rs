@@ -0,0 +1,55 @@
+use std::alloc::{self, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+// A zeroed heap buffer whose start address is a multiple of `align`,
+// as `O_DIRECT` requires of user memory.
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// The buffer is uniquely owned plain memory.
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    pub fn new(len: usize, align: usize) -> Self {
+        // A zero sized allocation is not allowed, so always keep at
+        // least one aligned unit around.
+        let layout = Layout::from_size_align(len.max(align), align)
+            .expect("alignment must be a power of two");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = match NonNull::new(ptr) {
+            Some(ptr) => ptr,
+            None => alloc::handle_alloc_error(layout),
+        };
+
+        AlignedBuf { ptr, len, layout }
+    }
+
+    pub fn is_aligned(buf: &[u8], align: usize) -> bool {
+        (buf.as_ptr() as usize).is_multiple_of(align)
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt::Debug;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::extent::{Allocation, Extent};
+use crate::info::DeviceInfo;
+
+// Storage behind a `BlockDevice`. Offsets and lengths are in bytes
+// from the start of the backend, and `size` never changes while the
+// backend is open.
+pub trait Backend: Debug + Send + Sync {
+    fn path(&self) -> &str;
+
+    fn size(&self) -> u64;
+
+    // Identity and topology for reports.
+    fn info(&self) -> DeviceInfo;
+
+    // Smallest unit the device can address.
+    fn logical_block_size(&self) -> u64 {
+        512
+    }
+
+    // Smallest unit the device can write without a read-modify-write
+    // cycle internally, e.g. 4096 on 512e disks.
+    fn physical_block_size(&self) -> u64 {
+        self.logical_block_size()
+    }
+
+    // Offsets and lengths of direct I/O must be multiples of this.
+    fn alignment(&self) -> u64 {
+        1
+    }
+
+    // Descriptor that asynchronous I/O engines such as io_uring can
+    // submit direct I/O to, if the backend is a plain file or device.
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    // Number of requests the backend keeps in flight at once.
+    fn queue_depth(&self) -> usize {
+        1
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    // Reads that should bypass the page cache. Backends without a
+    // cache to bypass just do a normal read.
+    fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.read_at(buf, offset)
+    }
+
+    fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize>;
+
+    // Read consecutive bytes starting at `offset` into `bufs`, in
+    // order. Stops early on a short read.
+    fn read_vectored_at(&self, bufs: &mut [&mut [u8]], mut offset: u64) -> io::Result<usize> {
+        let mut total = 0;
+
+        for buf in bufs.iter_mut() {
+            let size = self.read_direct_at(buf, offset)?;
+            total += size;
+            offset += size as u64;
+            if size != buf.len() {
+                break;
+            }
+        }
+
+        Ok(total)
+    }
+
+    fn write_vectored_at(&self, bufs: &[&[u8]], mut offset: u64) -> io::Result<usize> {
+        let mut total = 0;
+
+        for buf in bufs.iter() {
+            let size = self.write_direct_at(buf, offset)?;
+            total += size;
+            offset += size as u64;
+            if size != buf.len() {
+                break;
+            }
+        }
+
+        Ok(total)
+    }
+
+    // Independent direct reads, each into its own buffer. Results are
+    // in request order.
+    fn read_batch(&self, reqs: &mut [(u64, &mut [u8])]) -> Vec<io::Result<usize>> {
+        reqs.iter_mut()
+            .map(|(offset, buf)| self.read_direct_at(buf, *offset))
+            .collect()
+    }
+
+    fn write_batch(&self, reqs: &[(u64, &[u8])]) -> Vec<io::Result<usize>> {
+        reqs.iter()
+            .map(|(offset, buf)| self.write_direct_at(buf, *offset))
+            .collect()
+    }
+
+    // Tell the device `len` bytes at `offset` are no longer in use.
+    fn discard(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // Like `discard`, but the old data must be erased for good.
+    fn secure_discard(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // Whether discarded ranges are guaranteed to read back as zeroes.
+    fn discard_zeroes_data(&self) -> bool {
+        false
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> io::Result<()> {
+        fill_zeroes(self, offset, len)
+    }
+
+    // Which parts of `len` bytes at `offset` hold data, as sorted
+    // extents covering the range. Backends that can't tell report it
+    // all as data.
+    fn allocation(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        Ok(vec![Extent::new(offset, len, Allocation::Data)])
+    }
+
+    // Make completed writes durable.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Largest write issued by `fill_zeroes`.
+const ZEROES_CHUNK: u64 = 1024 * 1024;
+
+// Write zeroes with ordinary writes, for backends that have no
+// cheaper way.
+pub(crate) fn fill_zeroes<B: Backend + ?Sized>(
+    backend: &B,
+    mut offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    let end = offset + len;
+    let zeroes = vec![0; std::cmp::min(len, ZEROES_CHUNK) as usize];
+
+    while offset < end {
+        let n = std::cmp::min(end - offset, zeroes.len() as u64) as usize;
+        let size = backend.write_direct_at(&zeroes[..n], offset)?;
+        if size != n {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        offset += n as u64;
+    }
+
+    Ok(())
+}
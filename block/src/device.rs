@@ -1,152 +1,871 @@
-use libc;
 use std::fs;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Read;
-use std::os::unix::fs::FileExt;
 use std::os::unix::fs::FileTypeExt;
-use std::os::unix::fs::OpenOptionsExt;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-#[derive(Default, Debug, Clone)]
+use crate::aligned::AlignedBuf;
+use crate::backend::Backend;
+use crate::error::BlockError;
+use crate::extent::Extent;
+use crate::guard::{self, InUse};
+use crate::hotplug::{HotplugDevice, Timeline};
+use crate::image::ImageFile;
+use crate::info::DeviceInfo;
+use crate::lock::DeviceLock;
+use crate::memory::MemoryDisk;
+use crate::nbd::{self, NbdDevice};
+use crate::partition;
+use crate::qcow2::{self, Qcow2Image};
+use crate::raw::RawDevice;
+use crate::slice::SliceDevice;
+use crate::stats::{IoStats, Op};
+use crate::throttle::{Limits, Throttle, ThrottleStats};
+use crate::uring::UringDevice;
+
+#[derive(Debug)]
 pub struct BlockDevice {
-    dev_path: String,
-    size: u64,
+    backend: Arc<dyn Backend>,
+    force: bool,
+    in_use: OnceLock<Vec<InUse>>,
+    lock: Option<Arc<DeviceLock>>,
+    throttle: Arc<Throttle>,
+    stats: Mutex<IoStats>,
+    rmw: Arc<Mutex<()>>,
+    timeline: Option<Arc<Timeline>>,
 }
 
-const CHUNK_SIZE: u64 = 4096;
+impl BlockDevice {
+    // Open `path` as a raw block device or, if it is a regular file,
+    // as a qcow2 or raw disk image. NBD URIs such as `nbd://host/export` or
+    // `nbd+unix:///export?socket=path` connect to an NBD server.
+    pub fn new(path: &str) -> Result<Self, BlockError> {
+        BlockDevice::open(path, false)
+    }
+
+    // Like `new`, but fails if another virt-tools run holds the device
+    // lock, or if a block device is mounted or opened exclusively
+    // elsewhere. Both are held until the BlockDevice is dropped.
+    pub fn new_exclusive(path: &str) -> Result<Self, BlockError> {
+        // Sharing an export is up to the NBD server.
+        if nbd::is_uri(path) {
+            return BlockDevice::open(path, true);
+        }
 
-// `O_DIRECT` requires all reads and writes
-// to be aligned to the block device's block
-// size. 4096 might not be the best, or even
-// a valid one, for yours!
-#[repr(align(4096))]
-struct Aligned([u8; CHUNK_SIZE as usize]);
+        let lock = DeviceLock::acquire(path)?;
+        let mut blk = BlockDevice::open(path, true)?;
+        blk.lock = Some(Arc::new(lock));
 
-impl BlockDevice {
-    pub fn new(path: &str) -> Result<Self, String> {
-        let meta = fs::metadata(path).unwrap();
+        Ok(blk)
+    }
+
+    fn open(path: &str, exclusive: bool) -> Result<Self, BlockError> {
+        if nbd::is_uri(path) {
+            let backend: Arc<dyn Backend> = Arc::new(NbdDevice::connect(path)?);
+            return Ok(BlockDevice::from_backend(backend));
+        }
+
+        let meta = fs::metadata(path).map_err(|e| BlockError::open(path, e))?;
         let file_type = meta.file_type();
 
-        if !file_type.is_block_device() {
-            return Err(format!("{} is not a block device", path));
+        let backend: Arc<dyn Backend> = if file_type.is_block_device() {
+            Arc::new(RawDevice::open(path, exclusive)?)
+        } else if file_type.is_file() && qcow2::is_qcow2(path) {
+            Arc::new(Qcow2Image::open(path)?)
+        } else if file_type.is_file() {
+            Arc::new(ImageFile::open(path)?)
+        } else {
+            return Err(BlockError::Unsupported(format!(
+                "{} is not a block device or image file",
+                path
+            )));
+        };
+
+        Ok(BlockDevice::from_backend(backend))
+    }
+
+    pub fn from_backend(backend: Arc<dyn Backend>) -> Self {
+        BlockDevice {
+            backend,
+            force: false,
+            in_use: OnceLock::new(),
+            lock: None,
+            throttle: Arc::new(Throttle::default()),
+            stats: Mutex::default(),
+            rmw: Arc::default(),
+            timeline: None,
+        }
+    }
+
+    pub fn memory(size: u64) -> Self {
+        BlockDevice::from_backend(Arc::new(MemoryDisk::new(size)))
+    }
+
+    // Keep up to `depth` requests in flight through io_uring. Falls
+    // back to one synchronous request at a time where io_uring is
+    // unavailable. Backends that already keep requests in flight, such
+    // as NBD, are left as they are.
+    pub fn with_queue_depth(self, depth: usize) -> Self {
+        if depth <= 1 || self.backend.queue_depth() > 1 {
+            return self;
+        }
+
+        let backend: Arc<dyn Backend> = match UringDevice::new(self.backend, depth) {
+            Ok(uring) => Arc::new(uring),
+            Err((backend, e)) => {
+                println!(">>> io_uring unavailable ({}), using pread", e);
+                backend
+            }
+        };
+
+        BlockDevice { backend, ..self }
+    }
+
+    // If the block device is unplugged during the run, wait up to
+    // `timeout` for it to come back, found by serial or WWN, and carry
+    // on where it stopped. A disk with neither is refused unless
+    // `same_path`, see `HotplugDevice::block_device`. Apply after
+    // `with_queue_depth` and `with_force`, so the device is reopened
+    // with the same depth and held to the same checks.
+    pub fn with_reattach(self, timeout: Duration, same_path: bool) -> Self {
+        let exclusive = self.is_exclusive();
+        let hotplug = HotplugDevice::block_device(
+            Arc::clone(&self.backend),
+            exclusive,
+            self.lock.clone(),
+            self.force,
+            timeout,
+            same_path,
+        );
+        match hotplug {
+            Ok(hotplug) => BlockDevice {
+                timeline: Some(hotplug.get_timeline()),
+                backend: Arc::new(hotplug),
+                ..self
+            },
+            Err(e) => {
+                println!(">>> cannot reattach: {}", e);
+                self
+            }
         }
+    }
+
+    // What happened to the device during the run, if it is watched
+    // for hotplug by `with_reattach`.
+    pub fn get_timeline(&self) -> Option<Arc<Timeline>> {
+        self.timeline.clone()
+    }
+
+    // See `set_limits`.
+    pub fn with_limits(self, limits: Limits) -> Self {
+        self.set_limits(limits);
+
+        self
+    }
+
+    // Throttle requests to this device and all views of it. Can be
+    // called while I/O is in progress, e.g. to change the dirty rate
+    // of a guest during migration.
+    pub fn set_limits(&self, limits: Limits) {
+        self.throttle.set_limits(limits);
+    }
+
+    pub fn get_limits(&self) -> Limits {
+        self.throttle.get_limits()
+    }
+
+    pub fn get_throttle_stats(&self) -> ThrottleStats {
+        self.throttle.get_stats()
+    }
+
+    // Counts and latencies of the requests made through this
+    // BlockDevice. Views made by `slice` keep their own.
+    pub fn get_stats(&self) -> IoStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn reset_stats(&self) {
+        *self.stats.lock().unwrap() = IoStats::default();
+    }
+
+    // Run one request of `bytes` and account for it under `op`.
+    fn timed<T, F>(&self, op: Op, bytes: u64, f: F) -> Result<T, BlockError>
+    where
+        F: FnOnce() -> Result<T, BlockError>,
+    {
+        let start = Instant::now();
+        let ret = f();
+        let latency = start.elapsed();
 
-        let path_string = path.to_string();
+        self.stats
+            .lock()
+            .unwrap()
+            .record(op, bytes, latency, ret.is_ok());
 
-        let pos: Vec<&str> = path_string.split("/").collect();
-        let size_file = format!("/sys/class/block/{}/size", pos[pos.len() - 1]);
-        let mut f = File::open(size_file.to_string()).unwrap();
-        let mut buf = String::new();
-        f.read_to_string(&mut buf).unwrap();
+        ret
+    }
+
+    // Write even if the device is mounted, in use or holds a
+    // filesystem or partition table.
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+
+        self
+    }
+
+    // Reasons not to write to this device, looked up on first use.
+    pub fn get_in_use(&self) -> &[InUse] {
+        self.in_use
+            .get_or_init(|| guard::inspect(self.backend.as_ref()))
+    }
+
+    fn check_writable(&self) -> Result<(), BlockError> {
+        if self.force {
+            return Ok(());
+        }
+
+        guard::refuse(self.get_path(), self.get_in_use())
+    }
+
+    // A view of `len` bytes at `offset` of this device, with offsets
+    // translated and I/O outside of the window refused. The view
+    // shares the device, its lock and force setting, and refuses
+    // writes while the whole device is mounted or otherwise in use.
+    pub fn slice(&self, offset: u64, len: u64) -> Result<BlockDevice, BlockError> {
+        self.check_request(len as usize, offset)?;
+
+        let backend: Arc<dyn Backend> =
+            Arc::new(SliceDevice::new(Arc::clone(&self.backend), offset, len));
+
+        let mut in_use: Vec<InUse> = self
+            .get_in_use()
+            .iter()
+            .filter(|r| !matches!(r, InUse::Signature(_)))
+            .cloned()
+            .collect();
+        in_use.extend(guard::check_signatures(backend.as_ref()));
 
-        let buf = buf.as_str().trim();
         Ok(BlockDevice {
-            dev_path: path.to_string(),
-            size: 512 * buf.to_string().parse::<u64>().unwrap(),
+            backend,
+            force: self.force,
+            in_use: OnceLock::from(in_use),
+            lock: self.lock.clone(),
+            throttle: Arc::clone(&self.throttle),
+            stats: Mutex::default(),
+            rmw: Arc::clone(&self.rmw),
+            timeline: self.timeline.clone(),
         })
     }
 
+    // A view of partition `index` as numbered by the kernel, e.g. 3
+    // for /dev/sda3.
+    pub fn partition(&self, index: u32) -> Result<BlockDevice, BlockError> {
+        let part = partition::read_partitions(self)?
+            .into_iter()
+            .find(|p| p.index == index)
+            .ok_or_else(|| BlockError::NoPartition {
+                path: self.get_path().to_string(),
+                index,
+            })?;
+
+        self.slice(part.start, part.len)
+    }
+
+    pub fn get_queue_depth(&self) -> usize {
+        self.backend.queue_depth()
+    }
+
+    pub fn get_path(&self) -> &str {
+        self.backend.path()
+    }
+
     pub fn get_disk_size(&self) -> u64 {
-        self.size
+        self.backend.size()
+    }
+
+    pub fn get_logical_block_size(&self) -> u64 {
+        self.backend.logical_block_size()
+    }
+
+    pub fn get_physical_block_size(&self) -> u64 {
+        self.backend.physical_block_size()
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> usize {
-        let mut options = OpenOptions::new();
-        options.read(true);
-        if cfg!(unix) {
-            options.custom_flags(libc::O_RDONLY);
+    pub fn get_alignment(&self) -> u64 {
+        self.backend.alignment()
+    }
+
+    fn check_range(&self, len: usize, offset: u64) -> Result<(), BlockError> {
+        let size = self.get_disk_size();
+        let len = len as u64;
+
+        if offset > size || len > size - offset {
+            return Err(BlockError::OutOfRange { offset, len, size });
         }
 
-        let mut f = options.open(self.dev_path.as_str()).unwrap();
-        let size = f.read(buf).unwrap();
-        size
+        Ok(())
     }
 
-    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> usize {
-        let mut options = OpenOptions::new();
-        options.read(true);
-        if cfg!(unix) {
-            options.custom_flags(libc::O_RDONLY);
+    fn check_aligned(&self, len: usize, offset: u64) -> Result<(), BlockError> {
+        let align = self.backend.alignment();
+
+        if !(len as u64).is_multiple_of(align) || !offset.is_multiple_of(align) {
+            return Err(BlockError::Misaligned { offset, len, align });
         }
 
-        let f = options.open(self.dev_path.as_str()).unwrap();
-        let size = f.read_at(buf, offset).unwrap();
-        size
+        Ok(())
     }
 
-    pub fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> usize {
-        if (buf.len() as u64) % CHUNK_SIZE != 0 {
-            return 0;
+    fn check_read(
+        &self,
+        ret: std::io::Result<usize>,
+        len: usize,
+        offset: u64,
+    ) -> Result<usize, BlockError> {
+        let size = ret.map_err(|e| BlockError::io(self.get_path(), offset, e))?;
+        if size != len {
+            return Err(BlockError::ShortRead {
+                offset,
+                expected: len,
+                actual: size,
+            });
         }
 
-        if offset % CHUNK_SIZE != 0 {
-            return 0;
+        Ok(size)
+    }
+
+    fn check_write(
+        &self,
+        ret: std::io::Result<usize>,
+        len: usize,
+        offset: u64,
+    ) -> Result<usize, BlockError> {
+        let size = ret.map_err(|e| BlockError::io(self.get_path(), offset, e))?;
+        if size != len {
+            return Err(BlockError::ShortWrite {
+                offset,
+                expected: len,
+                actual: size,
+            });
         }
 
-        let mut options = OpenOptions::new();
-        options.read(true);
-        if cfg!(unix) {
-            options.custom_flags(libc::O_DIRECT);
+        Ok(size)
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, BlockError> {
+        self.read_at(buf, 0)
+    }
+
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize, BlockError> {
+        self.check_range(buf.len(), offset)?;
+        self.throttle.read(1, buf.len() as u64);
+
+        let len = buf.len();
+        self.timed(Op::Read, len as u64, || {
+            let ret = self.backend.read_at(buf, offset);
+            self.check_read(ret, len, offset)
+        })
+    }
+
+    // Ranges that are not aligned to `get_alignment` are read through
+    // a bounce buffer covering the whole blocks around them.
+    pub fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> Result<usize, BlockError> {
+        self.check_range(buf.len(), offset)?;
+        self.throttle.read(1, buf.len() as u64);
+
+        let len = buf.len();
+        self.timed(Op::Read, len as u64, || {
+            let ret = if self.is_aligned(len, offset) {
+                self.backend.read_direct_at(buf, offset)
+            } else {
+                self.read_unaligned(buf, offset)
+            };
+            self.check_read(ret, len, offset)
+        })
+    }
+
+    // Ranges that are not aligned to `get_alignment` are written with
+    // a read-modify-write cycle of the whole blocks around them.
+    pub fn write_direct_at(&self, buf: &[u8], offset: u64) -> Result<usize, BlockError> {
+        self.check_writable()?;
+        self.check_range(buf.len(), offset)?;
+        self.throttle.write(1, buf.len() as u64);
+
+        self.timed(Op::Write, buf.len() as u64, || {
+            let ret = if self.is_aligned(buf.len(), offset) {
+                self.backend.write_direct_at(buf, offset)
+            } else {
+                self.write_unaligned(buf, offset)
+            };
+            self.check_write(ret, buf.len(), offset)
+        })
+    }
+
+    fn is_aligned(&self, len: usize, offset: u64) -> bool {
+        self.check_aligned(len, offset).is_ok()
+    }
+
+    // The block aligned range around `len` bytes at `offset`, and a
+    // buffer for it that direct I/O accepts.
+    fn bounce(&self, len: usize, offset: u64) -> (u64, AlignedBuf) {
+        let align = self.backend.alignment();
+        let start = offset - offset % align;
+        let end = (offset + len as u64).next_multiple_of(align);
+
+        let buf = AlignedBuf::new((end - start) as usize, align.next_power_of_two() as usize);
+
+        (start, buf)
+    }
+
+    fn read_unaligned(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let (start, mut bounce) = self.bounce(buf.len(), offset);
+        let size = self.backend.read_direct_at(&mut bounce, start)?;
+
+        let skip = (offset - start) as usize;
+        let n = size.saturating_sub(skip).min(buf.len());
+        buf[..n].copy_from_slice(&bounce[skip..(skip + n)]);
+
+        Ok(n)
+    }
+
+    // Only the partial blocks at either end are read back. Cycles are
+    // serialized across the device and its views, but an aligned write
+    // racing with one to the same block can still be lost.
+    fn write_unaligned(&self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+        let _rmw = self.rmw.lock().unwrap();
+
+        let align = self.backend.alignment() as usize;
+        let (start, mut bounce) = self.bounce(buf.len(), offset);
+        let len = bounce.len();
+        let skip = (offset - start) as usize;
+
+        let mut edges = vec![0];
+        if !(skip + buf.len()).is_multiple_of(align) && len > align {
+            edges.push(len - align);
+        }
+        for pos in edges {
+            let size = self
+                .backend
+                .read_direct_at(&mut bounce[pos..(pos + align)], start + pos as u64)?;
+            if size != align {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+            }
         }
 
-        let nr_block = (buf.len() as u64) / CHUNK_SIZE;
-        let mut read_size = 0;
-        let mut out_buf = Aligned([0; CHUNK_SIZE as usize]);
+        bounce[skip..(skip + buf.len())].copy_from_slice(buf);
+        let size = self.backend.write_direct_at(&bounce, start)?;
+
+        Ok(size.saturating_sub(skip).min(buf.len()))
+    }
 
-        let f = options.open(self.dev_path.as_str()).unwrap();
-        let out_slice: &mut [u8] = &mut out_buf.0;
-        for n in 0..nr_block {
-            let size = f.read_at(out_slice, offset + n * CHUNK_SIZE).unwrap();
+    // Read `bufs` back to back starting at `offset`, in a single
+    // request where the backend supports it.
+    pub fn read_vectored_at(
+        &self,
+        bufs: &mut [&mut [u8]],
+        offset: u64,
+    ) -> Result<usize, BlockError> {
+        let len = bufs.iter().map(|b| b.len()).sum();
+        if !bufs.iter().all(|b| self.is_aligned(b.len(), offset)) {
+            let mut tmp = vec![0; len];
+            let size = self.read_direct_at(&mut tmp, offset)?;
 
-            let start = (n * CHUNK_SIZE) as usize;
-            let end = ((n + 1) * CHUNK_SIZE) as usize;
-            for i in start..end {
-                buf[i] = out_slice[i - start];
+            let mut pos = 0;
+            for buf in bufs.iter_mut() {
+                buf.copy_from_slice(&tmp[pos..(pos + buf.len())]);
+                pos += buf.len();
             }
 
-            read_size += size;
+            return Ok(size);
         }
 
-        read_size
+        self.check_range(len, offset)?;
+        self.throttle.read(1, len as u64);
+
+        self.timed(Op::Read, len as u64, || {
+            let ret = self.backend.read_vectored_at(bufs, offset);
+            self.check_read(ret, len, offset)
+        })
     }
 
-    pub fn write_direct_at(&self, buf: &[u8], offset: u64) -> usize {
-        if (buf.len() as u64) % CHUNK_SIZE != 0 {
-            return 0;
+    pub fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> Result<usize, BlockError> {
+        self.check_writable()?;
+        let len = bufs.iter().map(|b| b.len()).sum();
+        if !bufs.iter().all(|b| self.is_aligned(b.len(), offset)) {
+            return self.write_direct_at(&bufs.concat(), offset);
         }
 
-        if offset % CHUNK_SIZE != 0 {
-            return 0;
-        }
+        self.check_range(len, offset)?;
+        self.throttle.write(1, len as u64);
+
+        self.timed(Op::Write, len as u64, || {
+            let ret = self.backend.write_vectored_at(bufs, offset);
+            self.check_write(ret, len, offset)
+        })
+    }
+
+    fn check_request(&self, len: usize, offset: u64) -> Result<(), BlockError> {
+        self.check_range(len, offset)?;
+        self.check_aligned(len, offset)
+    }
+
+    // Direct reads of independent `(offset, buf)` requests, submitted
+    // together so that up to `get_queue_depth` are in flight. Results
+    // are in request order. Unaligned requests are done one by one
+    // as by `read_direct_at`.
+    pub fn read_batch(&self, reqs: &mut [(u64, &mut [u8])]) -> Vec<Result<usize, BlockError>> {
+        let results: Vec<Option<Result<usize, BlockError>>> = reqs
+            .iter_mut()
+            .map(|(offset, buf)| {
+                if self.is_aligned(buf.len(), *offset) {
+                    self.check_range(buf.len(), *offset).err().map(Err)
+                } else {
+                    Some(self.read_direct_at(buf, *offset))
+                }
+            })
+            .collect();
+
+        let mut valid: Vec<(u64, &mut [u8])> = reqs
+            .iter_mut()
+            .zip(results.iter())
+            .filter(|(_, r)| r.is_none())
+            .map(|((offset, buf), _)| (*offset, &mut **buf))
+            .collect();
+        let lens: Vec<(u64, usize)> = valid.iter().map(|(o, b)| (*o, b.len())).collect();
+        let bytes = lens.iter().map(|(_, len)| *len as u64).sum();
+        self.throttle.read(lens.len() as u64, bytes);
+
+        let start = Instant::now();
+        let ret = self.backend.read_batch(&mut valid);
+        let mut ret = self.record_batch(Op::Read, start, ret, lens);
+
+        results
+            .into_iter()
+            .map(|r| r.unwrap_or_else(|| ret.next().unwrap_or_else(|| Err(self.lost()))))
+            .collect()
+    }
+
+    pub fn write_batch(&self, reqs: &[(u64, &[u8])]) -> Vec<Result<usize, BlockError>> {
+        let results: Vec<Option<Result<usize, BlockError>>> = reqs
+            .iter()
+            .map(|(offset, buf)| {
+                if self.is_aligned(buf.len(), *offset) {
+                    self.check_writable()
+                        .and_then(|_| self.check_range(buf.len(), *offset))
+                        .err()
+                        .map(Err)
+                } else {
+                    Some(self.write_direct_at(buf, *offset))
+                }
+            })
+            .collect();
+
+        let valid: Vec<(u64, &[u8])> = reqs
+            .iter()
+            .zip(results.iter())
+            .filter(|(_, r)| r.is_none())
+            .map(|((offset, buf), _)| (*offset, *buf))
+            .collect();
+        let bytes = valid.iter().map(|(_, buf)| buf.len() as u64).sum();
+        self.throttle.write(valid.len() as u64, bytes);
 
-        let nr_block = (buf.len() as u64) / CHUNK_SIZE;
-        let mut write_size = 0;
+        let lens: Vec<(u64, usize)> = valid.iter().map(|(o, b)| (*o, b.len())).collect();
 
-        let mut options = OpenOptions::new();
-        options.write(true);
-        if cfg!(unix) {
-            options.custom_flags(libc::O_DIRECT);
+        let start = Instant::now();
+        let ret = self.backend.write_batch(&valid);
+        let mut ret = self.record_batch(Op::Write, start, ret, lens);
+
+        results
+            .into_iter()
+            .map(|r| r.unwrap_or_else(|| ret.next().unwrap_or_else(|| Err(self.lost()))))
+            .collect()
+    }
+
+    // Check the results of a batch started at `start` and account for
+    // them. Requests in a batch complete together as far as we can
+    // tell, so each gets the latency of the whole batch.
+    fn record_batch(
+        &self,
+        op: Op,
+        start: Instant,
+        results: Vec<std::io::Result<usize>>,
+        lens: Vec<(u64, usize)>,
+    ) -> std::vec::IntoIter<Result<usize, BlockError>> {
+        let latency = start.elapsed();
+
+        let results: Vec<Result<usize, BlockError>> = results
+            .into_iter()
+            .zip(lens)
+            .map(|(r, (offset, len))| match op {
+                Op::Read => self.check_read(r, len, offset),
+                _ => self.check_write(r, len, offset),
+            })
+            .collect();
+
+        let mut stats = self.stats.lock().unwrap();
+        for r in results.iter() {
+            let bytes = *r.as_ref().unwrap_or(&0) as u64;
+            stats.record(op, bytes, latency, r.is_ok());
         }
-        let file = options.open(self.dev_path.as_str()).unwrap();
 
-        let mut in_buf = Aligned([0; CHUNK_SIZE as usize]);
-        for n in 0..nr_block {
-            let start = (n * CHUNK_SIZE) as usize;
-            let end = ((n + 1) * CHUNK_SIZE) as usize;
+        results.into_iter()
+    }
+
+    // A request the backend returned no result for, as happens when
+    // the device goes away under it.
+    fn lost(&self) -> BlockError {
+        BlockError::Vanished(self.get_path().to_string())
+    }
+
+    pub fn discard(&self, offset: u64, len: u64) -> Result<(), BlockError> {
+        self.check_writable()?;
+        self.check_request(len as usize, offset)?;
+        self.throttle.write(1, 0);
+
+        self.timed(Op::Discard, len, || {
+            self.backend
+                .discard(offset, len)
+                .map_err(|e| BlockError::io(self.get_path(), offset, e))
+        })
+    }
+
+    pub fn secure_discard(&self, offset: u64, len: u64) -> Result<(), BlockError> {
+        self.check_writable()?;
+        self.check_request(len as usize, offset)?;
+        self.throttle.write(1, 0);
+
+        self.timed(Op::Discard, len, || {
+            self.backend
+                .secure_discard(offset, len)
+                .map_err(|e| BlockError::io(self.get_path(), offset, e))
+        })
+    }
+
+    pub fn write_zeroes(&self, offset: u64, len: u64) -> Result<(), BlockError> {
+        self.check_writable()?;
+        self.check_request(len as usize, offset)?;
+        self.throttle.write(1, len);
+
+        self.timed(Op::Write, len, || {
+            self.backend
+                .write_zeroes(offset, len)
+                .map_err(|e| BlockError::io(self.get_path(), offset, e))
+        })
+    }
+
+    pub fn discard_zeroes_data(&self) -> bool {
+        self.backend.discard_zeroes_data()
+    }
+
+    // Which parts of `len` bytes at `offset` hold data, see
+    // `Backend::allocation`.
+    pub fn get_allocation(&self, offset: u64, len: u64) -> Result<Vec<Extent>, BlockError> {
+        self.check_range(len as usize, offset)?;
 
-            for i in start..end {
-                in_buf.0[i - start] = buf[i];
+        self.backend
+            .allocation(offset, len)
+            .map_err(|e| BlockError::io(self.get_path(), offset, e))
+    }
+
+    pub fn flush(&self) -> Result<(), BlockError> {
+        self.timed(Op::Flush, 0, || {
+            self.backend
+                .flush()
+                .map_err(|e| BlockError::io(self.get_path(), 0, e))
+        })
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    pub fn get_info(&self) -> DeviceInfo {
+        self.backend.info()
+    }
+
+    pub fn show_info(&self) {
+        self.get_info().show_info();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lock::LOCK_DIR;
+    use crate::temp::TempPath;
+
+    #[test]
+    fn vectored_round_trip() {
+        let blk = BlockDevice::memory(64 * 1024);
+
+        let a = vec![0xaa; 4096];
+        let b = vec![0xbb; 8192];
+        assert_eq!(blk.write_vectored_at(&[&a, &b], 4096).unwrap(), 12288);
+
+        let mut x = AlignedBuf::new(8192, 4096);
+        let mut y = vec![0; 4096];
+        blk.read_vectored_at(&mut [&mut x, &mut y], 4096).unwrap();
+        assert!(AlignedBuf::is_aligned(&x, 4096));
+        assert!(x[..4096].iter().all(|v| *v == 0xaa));
+        assert!(x[4096..].iter().all(|v| *v == 0xbb));
+        assert!(y.iter().all(|v| *v == 0xbb));
+
+        assert!(blk.read_vectored_at(&mut [&mut y], 64 * 1024).is_err());
+    }
+
+    #[test]
+    fn refuse_to_overwrite_filesystem() {
+        let blk = BlockDevice::memory(128 * 1024);
+        blk.backend.write_direct_at(b"XFSB", 0).unwrap();
+
+        let buf = vec![0; 4096];
+        assert!(matches!(
+            blk.write_direct_at(&buf, 4096),
+            Err(BlockError::InUse { .. })
+        ));
+        assert!(blk.discard(0, 4096).is_err());
+
+        let blk = blk.with_force(true);
+        assert_eq!(blk.write_direct_at(&buf, 4096).unwrap(), 4096);
+    }
+
+    #[test]
+    fn exclusive_lock() {
+        let tmp = TempPath::new("lock.img");
+        let path = &*tmp;
+        ImageFile::create(path, 64 * 1024).unwrap();
+
+        let blk = BlockDevice::new_exclusive(path).unwrap();
+        assert!(blk.is_exclusive());
+        match BlockDevice::new_exclusive(path) {
+            Err(BlockError::Locked { pid, lock, .. }) => {
+                assert_eq!(pid, std::process::id());
+                assert!(lock.starts_with(LOCK_DIR), "{}", lock);
             }
+            other => panic!("expected a lock error, got {:?}", other),
+        }
+        assert!(BlockDevice::new(path).is_ok());
+
+        drop(blk);
+        assert!(BlockDevice::new_exclusive(path).is_ok());
+    }
+
+    #[test]
+    fn slice_window() {
+        let blk = BlockDevice::memory(64 * 1024);
+        let part = blk.slice(8192, 16384).unwrap();
+        assert_eq!(part.get_disk_size(), 16384);
+
+        let buf = vec![0xcc; 4096];
+        part.write_direct_at(&buf, 0).unwrap();
+        assert!(part.write_direct_at(&buf, 16384).is_err());
+        assert!(blk.slice(61440, 8192).is_err());
+
+        let mut out = vec![0; 4096];
+        blk.read_at(&mut out, 8192).unwrap();
+        assert_eq!(out, buf);
+        blk.read_at(&mut out, 4096).unwrap();
+        assert!(out.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn throttled_writes() {
+        let blk = BlockDevice::memory(64 * 1024).with_limits(Limits {
+            write_iops: 40,
+            ..Default::default()
+        });
+        let part = blk.slice(0, 32 * 1024).unwrap();
 
-            let out_slice = in_buf.0.as_ref();
-            let size = file.write_at(&out_slice, offset + n * CHUNK_SIZE).unwrap();
-            write_size += size;
+        // The first second's worth passes at once, the rest is paced
+        // across the device and its views.
+        let buf = vec![0; 512];
+        let start = std::time::Instant::now();
+        for i in 0..50 {
+            part.write_direct_at(&buf, i * 512).unwrap();
         }
+        assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+
+        let stats = blk.get_throttle_stats();
+        assert_eq!(stats.write_ops, 50);
+        assert_eq!(stats.write_bytes, 50 * 512);
+        assert!(stats.throttled >= 10);
 
-        write_size
+        blk.set_limits(Limits::default());
+        assert_eq!(part.get_limits(), Limits::default());
     }
 
-    pub fn show_info(&self) {
-        println!("{:?}", self);
+    #[test]
+    fn io_stats() {
+        let blk = BlockDevice::memory(64 * 1024);
+        let mut buf = vec![0; 4096];
+
+        blk.write_direct_at(&buf, 0).unwrap();
+        blk.read_direct_at(&mut buf, 4096).unwrap();
+        assert!(blk.read_direct_at(&mut buf, 64 * 1024).is_err());
+        let mut reqs: Vec<(u64, &mut [u8])> = vec![(8192, &mut buf[..])];
+        assert!(blk.read_batch(&mut reqs)[0].is_ok());
+
+        let stats = blk.get_stats();
+        assert_eq!(stats.write.ops, 1);
+        assert_eq!(stats.write.bytes, 4096);
+        // The out of range read is refused before it is issued.
+        assert_eq!(stats.read.ops, 2);
+        assert_eq!(stats.read.bytes, 8192);
+        assert_eq!(stats.read.latency.count(), 2);
+
+        blk.reset_stats();
+        assert_eq!(blk.get_stats().read.ops, 0);
+    }
+
+    #[test]
+    fn unaligned_read_modify_write() {
+        let mem = MemoryDisk::new(64 * 1024).with_alignment(4096);
+        let blk = BlockDevice::from_backend(Arc::new(mem));
+        blk.write_direct_at(&[0x11; 12288], 0).unwrap();
+
+        // Spans the end of one block and the start of the next.
+        blk.write_direct_at(&[0x22; 100], 4050).unwrap();
+        blk.write_vectored_at(&[&[0x33; 10], &[0x44; 10]], 9000)
+            .unwrap();
+
+        let mut buf = vec![0; 12288];
+        blk.read_direct_at(&mut buf[..1000], 3500).unwrap();
+        assert!(buf[..550].iter().all(|b| *b == 0x11));
+        assert!(buf[550..650].iter().all(|b| *b == 0x22));
+        assert!(buf[650..1000].iter().all(|b| *b == 0x11));
+
+        let mut reqs: Vec<(u64, &mut [u8])> = vec![(0, &mut buf[..])];
+        assert!(blk.read_batch(&mut reqs)[0].is_ok());
+        assert_eq!(&buf[9000..9010], &[0x33; 10]);
+        assert_eq!(&buf[9010..9020], &[0x44; 10]);
+        assert_eq!(buf[8999], 0x11);
+        assert_eq!(buf[9020], 0x11);
+
+        let mut out = [0; 3];
+        let mut rest = [0; 7];
+        blk.read_vectored_at(&mut [&mut out, &mut rest], 4049)
+            .unwrap();
+        assert_eq!(out, [0x11, 0x22, 0x22]);
+    }
+
+    #[test]
+    fn image_file_direct_and_buffered() {
+        let tmp = TempPath::new("dio.img");
+        let path = &*tmp;
+        ImageFile::create(path, 64 * 1024 + 100).unwrap();
+
+        // Aligned requests go through O_DIRECT where the filesystem
+        // allows it, the rest and the unaligned tail through the
+        // cache.
+        let blk = BlockDevice::new(path).unwrap();
+        blk.write_direct_at(&[0x55; 8192], 4096).unwrap();
+        blk.write_direct_at(&[0x66; 200], 64 * 1024 - 100).unwrap();
+
+        let mut buf = vec![0; 8192];
+        blk.read_direct_at(&mut buf, 4096).unwrap();
+        assert!(buf.iter().all(|b| *b == 0x55));
+        blk.read_direct_at(&mut buf[..300], 64 * 1024 - 200)
+            .unwrap();
+        assert!(buf[..100].iter().all(|b| *b == 0));
+        assert!(buf[100..300].iter().all(|b| *b == 0x66));
     }
 }
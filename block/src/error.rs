@@ -0,0 +1,117 @@
+use std::io;
+
+#[derive(thiserror::Error, Debug)]
+pub enum BlockError {
+    #[error("offset {offset} or length {len} is not aligned to {align} bytes")]
+    Misaligned { offset: u64, len: usize, align: u64 },
+
+    #[error("range {offset}+{len} is outside of the {size} byte disk")]
+    OutOfRange { offset: u64, len: u64, size: u64 },
+
+    #[error("short read at offset {offset}: {actual} of {expected} bytes")]
+    ShortRead {
+        offset: u64,
+        expected: usize,
+        actual: usize,
+    },
+
+    #[error("short write at offset {offset}: {actual} of {expected} bytes")]
+    ShortWrite {
+        offset: u64,
+        expected: usize,
+        actual: usize,
+    },
+
+    #[error("I/O error at offset {offset}: {source}")]
+    Io { offset: u64, source: io::Error },
+
+    #[error("{0}: device vanished")]
+    Vanished(String),
+
+    #[error("{0}: permission denied")]
+    Permission(String),
+
+    #[error("{path}: {source}")]
+    Open { path: String, source: io::Error },
+
+    #[error("{path}: locked by pid {pid} ({lock})")]
+    Locked {
+        path: String,
+        lock: String,
+        pid: u32,
+    },
+
+    #[error("{path}: cannot create a lock in {dir}: {source}")]
+    LockDir {
+        path: String,
+        dir: String,
+        source: io::Error,
+    },
+
+    #[error("{path}: device busy, opened by pids {pids:?}")]
+    Busy { path: String, pids: Vec<u32> },
+
+    #[error("{path}: {reasons}, refusing to write without force")]
+    InUse { path: String, reasons: String },
+
+    #[error("{path}: no partition {index}")]
+    NoPartition { path: String, index: u32 },
+
+    #[error("{0}")]
+    Unsupported(String),
+}
+
+impl BlockError {
+    // Classify an error from opening `path`.
+    pub fn open(path: &str, e: io::Error) -> Self {
+        match e.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EPERM) | Some(libc::EROFS) => {
+                Self::Permission(path.to_string())
+            }
+            Some(libc::ENODEV) | Some(libc::ENXIO) => Self::Vanished(path.to_string()),
+            _ => Self::Open {
+                path: path.to_string(),
+                source: e,
+            },
+        }
+    }
+
+    // Classify an error from I/O on `path` at `offset`.
+    pub fn io(path: &str, offset: u64, e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::Unsupported {
+            return Self::Unsupported(format!("{}: operation not supported", path));
+        }
+
+        match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) => {
+                Self::Unsupported(format!("{}: operation not supported", path))
+            }
+            Some(libc::EACCES) | Some(libc::EPERM) | Some(libc::EROFS) => {
+                Self::Permission(path.to_string())
+            }
+            Some(libc::ENODEV) | Some(libc::ENXIO) => Self::Vanished(path.to_string()),
+            _ => Self::Io { offset, source: e },
+        }
+    }
+
+    // Whether the device is gone, so there is no point in issuing
+    // more I/O to it.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Self::Vanished(_) | Self::Permission(_))
+    }
+}
+
+// For backends built on other BlockDevices, such as groups. The errno
+// is kept where there is one, so that `BlockError::io` classifies the
+// error the same way again.
+impl From<BlockError> for io::Error {
+    fn from(e: BlockError) -> Self {
+        match e {
+            BlockError::Io { source, .. } | BlockError::Open { source, .. } => source,
+            BlockError::Vanished(_) => io::Error::from_raw_os_error(libc::ENODEV),
+            BlockError::Permission(_) => io::Error::from_raw_os_error(libc::EACCES),
+            BlockError::Unsupported(_) => io::Error::new(io::ErrorKind::Unsupported, e),
+            e => io::Error::other(e),
+        }
+    }
+}
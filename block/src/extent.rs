@@ -0,0 +1,262 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    // Stored data.
+    Data,
+    // Allocated, or marked by the format, as reading back as zeroes.
+    Zero,
+    // Not allocated at all; reads back as zeroes.
+    Hole,
+}
+
+impl Allocation {
+    pub fn is_data(&self) -> bool {
+        *self == Allocation::Data
+    }
+}
+
+// `len` bytes at `offset` in the same state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+    pub state: Allocation,
+}
+
+impl Extent {
+    pub fn new(offset: u64, len: u64, state: Allocation) -> Self {
+        Extent { offset, len, state }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+// Turn `extents` into sorted extents that cover `len` bytes at
+// `offset` exactly: clipped to the range, gaps filled with `gap` and
+// neighbours in the same state merged. Overlaps are resolved in favour
+// of the extent that comes first.
+pub fn normalize(mut extents: Vec<Extent>, offset: u64, len: u64, gap: Allocation) -> Vec<Extent> {
+    let end = offset + len;
+    extents.sort_by_key(|e| e.offset);
+
+    let mut ret: Vec<Extent> = Vec::new();
+    let mut pos = offset;
+    fn push(ret: &mut Vec<Extent>, start: u64, end: u64, state: Allocation) {
+        if start >= end {
+            return;
+        }
+        match ret.last_mut() {
+            Some(last) if last.state == state && last.end() == start => {
+                last.len = end - last.offset
+            }
+            _ => ret.push(Extent::new(start, end - start, state)),
+        }
+    }
+
+    for e in extents {
+        let start = e.offset.max(pos);
+        let stop = e.end().min(end);
+        if start >= stop {
+            continue;
+        }
+
+        push(&mut ret, pos, start, gap);
+        push(&mut ret, start, stop, e.state);
+        pos = stop;
+    }
+    push(&mut ret, pos, end, gap);
+
+    ret
+}
+
+// Bytes of each state in `extents`: data, zero and holes.
+pub fn totals(extents: &[Extent]) -> (u64, u64, u64) {
+    extents
+        .iter()
+        .fold((0, 0, 0), |(data, zero, hole), e| match e.state {
+            Allocation::Data => (data + e.len, zero, hole),
+            Allocation::Zero => (data, zero + e.len, hole),
+            Allocation::Hole => (data, zero, hole + e.len),
+        })
+}
+
+// From linux/fiemap.h and linux/fs.h; not in the libc crate.
+const FS_IOC_FIEMAP: libc::c_ulong = 0xc020660b;
+const FIEMAP_FLAG_SYNC: u32 = 1 << 0;
+const FIEMAP_EXTENT_LAST: u32 = 1 << 0;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 1 << 11;
+
+// Extents asked for per ioctl.
+const FIEMAP_BATCH: usize = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+// The extents of `file` in `len` bytes at `offset`, from FIEMAP.
+// Preallocated but unwritten extents read back as zeroes.
+fn fiemap(file: &File, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+    let end = offset + len;
+    let mut extents = Vec::new();
+    let mut pos = offset;
+
+    while pos < end {
+        let mut fm = Fiemap {
+            fm_start: pos,
+            fm_length: end - pos,
+            // Delayed allocations only show up once written back.
+            fm_flags: FIEMAP_FLAG_SYNC,
+            fm_mapped_extents: 0,
+            fm_extent_count: FIEMAP_BATCH as u32,
+            fm_reserved: 0,
+            fm_extents: [FiemapExtent::default(); FIEMAP_BATCH],
+        };
+
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fm) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mapped = &fm.fm_extents[..fm.fm_mapped_extents as usize];
+        for fe in mapped {
+            let state = if fe.fe_flags & FIEMAP_EXTENT_UNWRITTEN != 0 {
+                Allocation::Zero
+            } else {
+                Allocation::Data
+            };
+            extents.push(Extent::new(fe.fe_logical, fe.fe_length, state));
+        }
+
+        match mapped.last() {
+            Some(fe) if fe.fe_flags & FIEMAP_EXTENT_LAST == 0 => {
+                pos = fe.fe_logical + fe.fe_length;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(normalize(extents, offset, len, Allocation::Hole))
+}
+
+// The data regions of `file` in `len` bytes at `offset`, from
+// SEEK_DATA and SEEK_HOLE, which unlike FIEMAP tmpfs supports too.
+fn seek_map(file: &File, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+    let fd = file.as_raw_fd();
+    let end = offset + len;
+    let mut extents = Vec::new();
+    let mut pos = offset;
+
+    // Moving the file offset is harmless, all I/O is positioned.
+    while pos < end {
+        let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let e = io::Error::last_os_error();
+            // No data after `pos`.
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(e);
+        }
+        let data = data as u64;
+        if data >= end {
+            break;
+        }
+
+        let hole = unsafe { libc::lseek(fd, data as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let hole = (hole as u64).min(end);
+
+        extents.push(Extent::new(data, hole - data, Allocation::Data));
+        pos = hole;
+    }
+
+    Ok(normalize(extents, offset, len, Allocation::Hole))
+}
+
+// The allocation of `len` bytes at `offset` of a regular file. Where
+// the filesystem can't tell, it is all data.
+pub(crate) fn file_map(file: &File, offset: u64, len: u64) -> Vec<Extent> {
+    fiemap(file, offset, len)
+        .or_else(|_| seek_map(file, offset, len))
+        .unwrap_or_else(|_| vec![Extent::new(offset, len, Allocation::Data)])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::image::ImageFile;
+    use crate::temp::TempPath;
+
+    #[test]
+    fn normalize_extents() {
+        let extents = vec![
+            Extent::new(300, 100, Allocation::Data),
+            Extent::new(0, 50, Allocation::Data),
+            Extent::new(100, 200, Allocation::Data),
+            Extent::new(350, 100, Allocation::Zero),
+        ];
+
+        assert_eq!(
+            normalize(extents, 10, 480, Allocation::Hole),
+            vec![
+                Extent::new(10, 40, Allocation::Data),
+                Extent::new(50, 50, Allocation::Hole),
+                Extent::new(100, 300, Allocation::Data),
+                Extent::new(400, 50, Allocation::Zero),
+                Extent::new(450, 40, Allocation::Hole),
+            ]
+        );
+        assert_eq!(
+            normalize(Vec::new(), 0, 10, Allocation::Hole),
+            vec![Extent::new(0, 10, Allocation::Hole)]
+        );
+    }
+
+    #[test]
+    fn sparse_image_file() {
+        let tmp = TempPath::new("sparse.img");
+        let path = &*tmp;
+        let img = ImageFile::create(path, 4 * 1024 * 1024).unwrap();
+        img.write_direct_at(&[1; 65536], 1024 * 1024).unwrap();
+
+        let file = File::open(path).unwrap();
+        for map in [fiemap(&file, 0, img.size()), seek_map(&file, 0, img.size())] {
+            // Not every filesystem keeps holes.
+            let map = match map {
+                Ok(map) if map.len() > 1 => map,
+                _ => continue,
+            };
+
+            let data: Vec<&Extent> = map.iter().filter(|e| e.state.is_data()).collect();
+            assert_eq!(data.len(), 1, "{:?}", map);
+            assert!(data[0].offset <= 1024 * 1024 && data[0].end() >= 1024 * 1024 + 65536);
+            assert_eq!(totals(&map).0 + totals(&map).2, img.size());
+        }
+    }
+}
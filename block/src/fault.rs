@@ -0,0 +1,370 @@
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::backend::Backend;
+use crate::extent::Extent;
+use crate::info::DeviceInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    // The request fails with EIO.
+    Eio,
+    // Only a prefix of the data is read.
+    ShortRead,
+    // One bit of the data read is flipped.
+    BitFlip,
+    // The write reports success but never reaches the device.
+    DroppedWrite,
+    // The write reports success but lands at another offset.
+    MisdirectedWrite,
+    // Only a prefix of the write reaches the device.
+    TornWrite,
+}
+
+impl Fault {
+    fn on_read(&self) -> bool {
+        matches!(self, Fault::Eio | Fault::ShortRead | Fault::BitFlip)
+    }
+
+    fn on_write(&self) -> bool {
+        !matches!(self, Fault::ShortRead | Fault::BitFlip)
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Fault::Eio => "EIO",
+            Fault::ShortRead => "short read",
+            Fault::BitFlip => "bit flip",
+            Fault::DroppedWrite => "dropped write",
+            Fault::MisdirectedWrite => "misdirected write",
+            Fault::TornWrite => "torn write",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+// A fault that was injected into the request of `len` bytes at
+// `offset`. `at` is where it struck: the byte offset of the flipped
+// bit, the offset a misdirected write landed at, or the number of
+// bytes a short read or torn write got through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Injected {
+    pub fault: Fault,
+    pub offset: u64,
+    pub len: usize,
+    pub at: u64,
+}
+
+#[derive(Debug)]
+struct Rule {
+    fault: Fault,
+    rate: f64,
+}
+
+#[derive(Debug)]
+struct State {
+    rng: u64,
+    injected: Vec<Injected>,
+}
+
+// Wraps another backend and corrupts its I/O on a schedule drawn from
+// `seed`, so that the same requests in the same order always see the
+// same faults. Meant for testing that verification catches them.
+#[derive(Debug)]
+pub struct FaultDevice {
+    inner: Arc<dyn Backend>,
+    rules: Vec<Rule>,
+    range: Option<(u64, u64)>,
+    limit: Option<usize>,
+    state: Mutex<State>,
+}
+
+impl FaultDevice {
+    pub fn new(inner: Arc<dyn Backend>, seed: u64) -> Self {
+        FaultDevice {
+            inner,
+            rules: Vec::new(),
+            range: None,
+            limit: None,
+            state: Mutex::new(State {
+                rng: seed,
+                injected: Vec::new(),
+            }),
+        }
+    }
+
+    // Inject `fault` into each request it applies to with probability
+    // `rate`. Rules are tried in the order they were added and at most
+    // one fault is injected per request.
+    pub fn with_fault(mut self, fault: Fault, rate: f64) -> Self {
+        self.rules.push(Rule { fault, rate });
+
+        self
+    }
+
+    // Only inject into requests overlapping `len` bytes at `offset`.
+    pub fn with_range(mut self, offset: u64, len: u64) -> Self {
+        self.range = Some((offset, len));
+
+        self
+    }
+
+    // Stop injecting after `limit` faults.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+
+        self
+    }
+
+    // Faults injected so far, in request order.
+    pub fn get_injected(&self) -> Vec<Injected> {
+        self.state.lock().unwrap().injected.clone()
+    }
+
+    fn in_range(&self, len: usize, offset: u64) -> bool {
+        match self.range {
+            Some((start, range_len)) => offset < start + range_len && start < offset + len as u64,
+            None => true,
+        }
+    }
+
+    // Pick the fault to inject into a request, if any, and draw where
+    // it strikes with `strike`, which gets a random number.
+    fn schedule<F>(&self, write: bool, len: usize, offset: u64, strike: F) -> Option<Injected>
+    where
+        F: FnOnce(Fault, u64) -> u64,
+    {
+        if !self.in_range(len, offset) {
+            return None;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(limit) = self.limit {
+            if state.injected.len() >= limit {
+                return None;
+            }
+        }
+
+        for rule in self.rules.iter() {
+            let applies = if write {
+                rule.fault.on_write()
+            } else {
+                rule.fault.on_read()
+            };
+            if !applies {
+                continue;
+            }
+
+            // 53 random bits make a uniform float in [0, 1).
+            let p = (next_u64(&mut state.rng) >> 11) as f64 / (1u64 << 53) as f64;
+            if p < rule.rate {
+                let at = strike(rule.fault, next_u64(&mut state.rng));
+                let injected = Injected {
+                    fault: rule.fault,
+                    offset,
+                    len,
+                    at,
+                };
+                state.injected.push(injected.clone());
+
+                return Some(injected);
+            }
+        }
+
+        None
+    }
+
+    // A random prefix of `len` bytes, in whole logical blocks and
+    // shorter than `len`.
+    fn prefix(&self, len: usize, r: u64) -> u64 {
+        let block = self.inner.logical_block_size();
+        let blocks = len as u64 / block;
+        if blocks <= 1 {
+            return 0;
+        }
+
+        (r % blocks) * block
+    }
+
+    // Another block-aligned offset that `len` bytes fit at.
+    fn misdirect(&self, len: usize, offset: u64, r: u64) -> u64 {
+        let block = self.inner.logical_block_size();
+        let slots = self.inner.size().saturating_sub(len as u64) / block + 1;
+        if slots <= 1 {
+            return offset;
+        }
+
+        let target = (r % slots) * block;
+        if target == offset {
+            (target + block) % (slots * block)
+        } else {
+            target
+        }
+    }
+
+    fn read_faulty(
+        &self,
+        buf: &mut [u8],
+        ret: io::Result<usize>,
+        offset: u64,
+    ) -> io::Result<usize> {
+        let size = ret?;
+
+        let fault = self.schedule(false, buf.len(), offset, |fault, r| match fault {
+            Fault::ShortRead => self.prefix(size, r),
+            Fault::BitFlip if size > 0 => r % (size as u64 * 8),
+            _ => 0,
+        });
+
+        match fault {
+            Some(f) => match f.fault {
+                Fault::Eio => Err(io::Error::from_raw_os_error(libc::EIO)),
+                Fault::ShortRead => Ok(f.at as usize),
+                Fault::BitFlip if size > 0 => {
+                    buf[(f.at / 8) as usize] ^= 1 << (f.at % 8);
+                    Ok(size)
+                }
+                _ => Ok(size),
+            },
+            None => Ok(size),
+        }
+    }
+}
+
+impl Backend for FaultDevice {
+    fn path(&self) -> &str {
+        self.inner.path()
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn info(&self) -> DeviceInfo {
+        let mut info = self.inner.info();
+        info.backing.push("fault injection".to_string());
+
+        info
+    }
+
+    fn logical_block_size(&self) -> u64 {
+        self.inner.logical_block_size()
+    }
+
+    fn physical_block_size(&self) -> u64 {
+        self.inner.physical_block_size()
+    }
+
+    fn alignment(&self) -> u64 {
+        self.inner.alignment()
+    }
+
+    // No `raw_fd`: I/O submitted to the descriptor directly would
+    // escape the faults.
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let ret = self.inner.read_at(buf, offset);
+        self.read_faulty(buf, ret, offset)
+    }
+
+    fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let ret = self.inner.read_direct_at(buf, offset);
+        self.read_faulty(buf, ret, offset)
+    }
+
+    fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let fault = self.schedule(true, buf.len(), offset, |fault, r| match fault {
+            Fault::MisdirectedWrite => self.misdirect(buf.len(), offset, r),
+            Fault::TornWrite => self.prefix(buf.len(), r),
+            _ => 0,
+        });
+
+        let f = match fault {
+            Some(f) => f,
+            None => return self.inner.write_direct_at(buf, offset),
+        };
+
+        match f.fault {
+            Fault::Eio => Err(io::Error::from_raw_os_error(libc::EIO)),
+            Fault::DroppedWrite => Ok(buf.len()),
+            Fault::MisdirectedWrite => {
+                self.inner.write_direct_at(buf, f.at)?;
+                Ok(buf.len())
+            }
+            Fault::TornWrite => {
+                self.inner.write_direct_at(&buf[..f.at as usize], offset)?;
+                Ok(buf.len())
+            }
+            _ => self.inner.write_direct_at(buf, offset),
+        }
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.inner.discard(offset, len)
+    }
+
+    fn secure_discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.inner.secure_discard(offset, len)
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        self.inner.discard_zeroes_data()
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.inner.write_zeroes(offset, len)
+    }
+
+    fn allocation(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        self.inner.allocation(offset, len)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// splitmix64, so a schedule only depends on the seed and not on the
+// platform or a crate version.
+fn next_u64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDisk;
+
+    fn faulty(seed: u64) -> FaultDevice {
+        FaultDevice::new(Arc::new(MemoryDisk::new(64 * 1024)), seed)
+            .with_fault(Fault::BitFlip, 0.3)
+            .with_fault(Fault::TornWrite, 0.3)
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let run = |dev: &FaultDevice| {
+            let data = vec![0xa5; 4096];
+            let mut buf = vec![0; 4096];
+            for i in 0..16 {
+                dev.write_direct_at(&data, i * 4096).unwrap();
+                dev.read_direct_at(&mut buf, i * 4096).unwrap();
+            }
+            dev.get_injected()
+        };
+
+        let a = run(&faulty(7));
+        assert!(!a.is_empty());
+        assert_eq!(a, run(&faulty(7)));
+        assert_ne!(a, run(&faulty(8)));
+    }
+}
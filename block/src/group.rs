@@ -0,0 +1,618 @@
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::backend::Backend;
+use crate::device::BlockDevice;
+use crate::error::BlockError;
+use crate::extent::{self, Allocation, Extent};
+use crate::image::clamp_len;
+use crate::info::DeviceInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    // The members one after another.
+    Concat,
+    // RAID0: `chunk` bytes of each member in turn.
+    Stripe { chunk: u64 },
+    // RAID1: every member holds all of the data.
+    Mirror,
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layout::Concat => write!(f, "concat"),
+            Layout::Stripe { chunk } => write!(f, "stripe/{}", chunk),
+            Layout::Mirror => write!(f, "mirror"),
+        }
+    }
+}
+
+// `len` bytes at `offset` of mirror member `member` that differ from
+// the copy the read returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub offset: u64,
+    pub len: u64,
+    pub member: usize,
+}
+
+// Part of a request that goes to one member: `len` bytes at `offset`
+// of the member, and at `start` of the request's buffer.
+#[derive(Debug, Clone, Copy)]
+struct Piece {
+    member: usize,
+    offset: u64,
+    start: usize,
+    len: usize,
+}
+
+// Several devices used as one, so that one workload exercises all of
+// them. Each member keeps its own lock, in-use checks, queue depth and
+// stats, and requests to different members run in parallel. Mirror
+// reads go to the members in turn or, with `with_compare`, to all of
+// them, so that divergence between members is found.
+#[derive(Debug)]
+pub struct DeviceGroup {
+    name: String,
+    layout: Layout,
+    members: Vec<BlockDevice>,
+    starts: Vec<u64>,
+    size: u64,
+    compare: bool,
+    next: AtomicUsize,
+    divergences: Mutex<Vec<Divergence>>,
+}
+
+impl DeviceGroup {
+    pub fn new(layout: Layout, members: Vec<BlockDevice>) -> Result<Self, BlockError> {
+        if members.is_empty() {
+            return Err(BlockError::Unsupported("empty device group".to_string()));
+        }
+
+        let paths: Vec<&str> = members.iter().map(|m| m.get_path()).collect();
+        let name = format!("{}:{}", layout, paths.join(","));
+
+        let sizes: Vec<u64> = members.iter().map(|m| m.get_disk_size()).collect();
+        let smallest = *sizes.iter().min().unwrap();
+        let nr = members.len() as u64;
+
+        let size = match layout {
+            Layout::Concat => sizes.iter().sum(),
+            Layout::Stripe { chunk } => {
+                let block = members
+                    .iter()
+                    .map(|m| m.get_logical_block_size())
+                    .max()
+                    .unwrap();
+                if chunk == 0 || !chunk.is_multiple_of(block) {
+                    return Err(BlockError::Unsupported(format!(
+                        "{}: chunk size must be a multiple of {}",
+                        name, block
+                    )));
+                }
+                smallest / chunk * chunk * nr
+            }
+            Layout::Mirror => smallest,
+        };
+
+        let starts = sizes
+            .iter()
+            .scan(0, |start, size| {
+                let s = *start;
+                *start += size;
+                Some(s)
+            })
+            .collect();
+
+        Ok(DeviceGroup {
+            name,
+            layout,
+            members,
+            starts,
+            size,
+            compare: false,
+            next: AtomicUsize::new(0),
+            divergences: Mutex::new(Vec::new()),
+        })
+    }
+
+    // Read every member of a mirror and compare the copies. The copy
+    // most members agree on is returned, the first one on a tie.
+    pub fn with_compare(mut self, compare: bool) -> Self {
+        self.compare = compare;
+
+        self
+    }
+
+    pub fn get_layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn get_members(&self) -> &[BlockDevice] {
+        &self.members
+    }
+
+    // Differences between mirror members found so far.
+    pub fn get_divergences(&self) -> Vec<Divergence> {
+        self.divergences.lock().unwrap().clone()
+    }
+
+    pub fn show_divergences(&self) {
+        let divergences = self.divergences.lock().unwrap();
+        println!(
+            ">>> {}: {} divergences between members",
+            self.name,
+            divergences.len()
+        );
+
+        for d in divergences.iter() {
+            println!(
+                ">>> member {} ({}): {} bytes differ at {}",
+                d.member,
+                self.members[d.member].get_path(),
+                d.len,
+                d.offset
+            );
+        }
+    }
+
+    // Where `len` bytes at `offset` are read from, in order. Mirror
+    // reads take turns.
+    fn map(&self, offset: u64, len: u64) -> Vec<Piece> {
+        let mut pieces = Vec::new();
+        let mut done = 0;
+
+        while done < len {
+            let pos = offset + done;
+            let (member, member_offset, n) = match self.layout {
+                Layout::Concat => {
+                    let member = self.starts.partition_point(|s| *s <= pos) - 1;
+                    let member_offset = pos - self.starts[member];
+                    let n = self.members[member].get_disk_size() - member_offset;
+                    (member, member_offset, n)
+                }
+                Layout::Stripe { chunk } => {
+                    let index = pos / chunk;
+                    let nr = self.members.len() as u64;
+                    let member_offset = index / nr * chunk + pos % chunk;
+                    ((index % nr) as usize, member_offset, chunk - pos % chunk)
+                }
+                Layout::Mirror => {
+                    let member = self.next.fetch_add(1, Ordering::Relaxed) % self.members.len();
+                    (member, pos, len - done)
+                }
+            };
+            let n = std::cmp::min(n, len - done);
+
+            pieces.push(Piece {
+                member,
+                offset: member_offset,
+                start: done as usize,
+                len: n as usize,
+            });
+            done += n;
+        }
+
+        pieces
+    }
+
+    // Where `len` bytes at `offset` are written to: every member of a
+    // mirror, or as they are read.
+    fn targets(&self, offset: u64, len: u64) -> Vec<Piece> {
+        if self.layout != Layout::Mirror {
+            return self.map(offset, len);
+        }
+
+        (0..self.members.len())
+            .map(|member| Piece {
+                member,
+                offset,
+                start: 0,
+                len: len as usize,
+            })
+            .collect()
+    }
+
+    // Run `f` on the work for each member, in parallel when more than
+    // one member has any. Results are per member, in the order of the
+    // work.
+    fn each<T, R, F>(&self, work: Vec<Vec<T>>, f: F) -> Vec<Vec<R>>
+    where
+        T: Send,
+        R: Send,
+        F: Fn(&BlockDevice, Vec<T>) -> Vec<R> + Sync,
+    {
+        let busy = work.iter().filter(|w| !w.is_empty()).count();
+        if busy <= 1 {
+            return work
+                .into_iter()
+                .zip(self.members.iter())
+                .map(|(w, blk)| if w.is_empty() { Vec::new() } else { f(blk, w) })
+                .collect();
+        }
+
+        let f = &f;
+        thread::scope(|s| {
+            let handles: Vec<_> = work
+                .into_iter()
+                .zip(self.members.iter())
+                .map(|(w, blk)| (!w.is_empty()).then(|| s.spawn(move || f(blk, w))))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|h| h.map(|h| h.join().unwrap()).unwrap_or_default())
+                .collect()
+        })
+    }
+
+    fn read_compare(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = clamp_len(buf.len(), offset, self.size);
+
+        let mut copies: Vec<Vec<u8>> = self.members.iter().map(|_| vec![0; len]).collect();
+        let work: Vec<Vec<&mut [u8]>> = copies.iter_mut().map(|c| vec![c.as_mut_slice()]).collect();
+        let results = self.each(work, |blk, bufs| {
+            bufs.into_iter()
+                .map(|buf| blk.read_direct_at(buf, offset))
+                .collect()
+        });
+        for r in results.into_iter().flatten() {
+            r?;
+        }
+
+        let votes = |m: usize| copies.iter().filter(|c| **c == copies[m]).count();
+        let best = (0..copies.len()).rev().max_by_key(|m| votes(*m)).unwrap();
+
+        let mut divergences = self.divergences.lock().unwrap();
+        for (member, copy) in copies.iter().enumerate() {
+            let differs = |i: &usize| copy[*i] != copies[best][*i];
+            if let Some(first) = (0..len).find(differs) {
+                let last = (0..len).rfind(differs).unwrap();
+                divergences.push(Divergence {
+                    offset: offset + first as u64,
+                    len: (last - first + 1) as u64,
+                    member,
+                });
+            }
+        }
+
+        buf[..len].copy_from_slice(&copies[best]);
+        Ok(len)
+    }
+
+    // Apply `f` to each member range of `len` bytes at `offset`, as
+    // for a write.
+    fn each_range<F>(&self, offset: u64, len: u64, f: F) -> io::Result<()>
+    where
+        F: Fn(&BlockDevice, u64, u64) -> Result<(), BlockError> + Sync,
+    {
+        let len = clamp_len(len as usize, offset, self.size) as u64;
+
+        let mut work: Vec<Vec<Piece>> = self.members.iter().map(|_| Vec::new()).collect();
+        for p in self.targets(offset, len) {
+            work[p.member].push(p);
+        }
+
+        let results = self.each(work, |blk, pieces| {
+            pieces
+                .into_iter()
+                .map(|p| f(blk, p.offset, p.len as u64))
+                .collect()
+        });
+        for r in results.into_iter().flatten() {
+            r?;
+        }
+
+        Ok(())
+    }
+}
+
+// One result per request from the results of the pieces, which came
+// from the requests in `owners`.
+fn gather(
+    lens: Vec<usize>,
+    owners: Vec<Vec<usize>>,
+    results: Vec<Vec<Result<usize, BlockError>>>,
+) -> Vec<io::Result<usize>> {
+    let mut ret: Vec<io::Result<usize>> = lens.into_iter().map(Ok).collect();
+
+    for (owners, results) in owners.iter().zip(results) {
+        for (i, r) in owners.iter().zip(results) {
+            if let Err(e) = r {
+                if ret[*i].is_ok() {
+                    ret[*i] = Err(e.into());
+                }
+            }
+        }
+    }
+
+    ret
+}
+
+impl Backend for DeviceGroup {
+    fn path(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn info(&self) -> DeviceInfo {
+        let kind = format!("{} of {} devices", self.layout, self.members.len());
+        let mut info = DeviceInfo::new(self, &kind);
+        info.backing = self
+            .members
+            .iter()
+            .map(|m| format!("{} ({})", m.get_path(), m.get_info().kind))
+            .collect();
+
+        info
+    }
+
+    fn logical_block_size(&self) -> u64 {
+        self.members
+            .iter()
+            .map(|m| m.get_logical_block_size())
+            .max()
+            .unwrap()
+    }
+
+    fn physical_block_size(&self) -> u64 {
+        self.members
+            .iter()
+            .map(|m| m.get_physical_block_size())
+            .max()
+            .unwrap()
+    }
+
+    // Members take requests at any alignment; see
+    // `BlockDevice::read_direct_at`.
+
+    // Enough requests to keep every member busy.
+    fn queue_depth(&self) -> usize {
+        self.members.iter().map(|m| m.get_queue_depth()).sum()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.read_batch(&mut [(offset, buf)]).pop().unwrap()
+    }
+
+    fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.read_batch(&mut [(offset, buf)]).pop().unwrap()
+    }
+
+    fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.write_batch(&[(offset, buf)]).pop().unwrap()
+    }
+
+    fn read_batch(&self, reqs: &mut [(u64, &mut [u8])]) -> Vec<io::Result<usize>> {
+        if self.compare && self.layout == Layout::Mirror {
+            return reqs
+                .iter_mut()
+                .map(|(offset, buf)| self.read_compare(buf, *offset))
+                .collect();
+        }
+
+        let mut work: Vec<Vec<(u64, &mut [u8])>> =
+            self.members.iter().map(|_| Vec::new()).collect();
+        let mut owners: Vec<Vec<usize>> = self.members.iter().map(|_| Vec::new()).collect();
+        let mut lens = Vec::with_capacity(reqs.len());
+
+        for (i, (offset, buf)) in reqs.iter_mut().enumerate() {
+            let len = clamp_len(buf.len(), *offset, self.size);
+            lens.push(len);
+
+            // Pieces are in buffer order, so they can be split off one
+            // after another.
+            let mut rest = &mut buf[..len];
+            for p in self.map(*offset, len as u64) {
+                let (head, tail) = std::mem::take(&mut rest).split_at_mut(p.len);
+                work[p.member].push((p.offset, head));
+                owners[p.member].push(i);
+                rest = tail;
+            }
+        }
+
+        let results = self.each(work, |blk, mut reqs| blk.read_batch(&mut reqs));
+        gather(lens, owners, results)
+    }
+
+    fn write_batch(&self, reqs: &[(u64, &[u8])]) -> Vec<io::Result<usize>> {
+        let mut work: Vec<Vec<(u64, &[u8])>> = self.members.iter().map(|_| Vec::new()).collect();
+        let mut owners: Vec<Vec<usize>> = self.members.iter().map(|_| Vec::new()).collect();
+        let mut lens = Vec::with_capacity(reqs.len());
+
+        for (i, (offset, buf)) in reqs.iter().enumerate() {
+            let len = clamp_len(buf.len(), *offset, self.size);
+            lens.push(len);
+
+            for p in self.targets(*offset, len as u64) {
+                work[p.member].push((p.offset, &buf[p.start..p.start + p.len]));
+                owners[p.member].push(i);
+            }
+        }
+
+        let results = self.each(work, |blk, reqs| blk.write_batch(&reqs));
+        gather(lens, owners, results)
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.each_range(offset, len, |blk, offset, len| blk.discard(offset, len))
+    }
+
+    fn secure_discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.each_range(offset, len, |blk, offset, len| {
+            blk.secure_discard(offset, len)
+        })
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        self.members.iter().all(|m| m.discard_zeroes_data())
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.each_range(offset, len, |blk, offset, len| {
+            blk.write_zeroes(offset, len)
+        })
+    }
+
+    // Mirrors are reported as all data, so that checks read every
+    // cluster and members that differ in allocation are compared too.
+    fn allocation(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let len = clamp_len(len as usize, offset, self.size) as u64;
+        if self.layout == Layout::Mirror {
+            return Ok(vec![Extent::new(offset, len, Allocation::Data)]);
+        }
+
+        let pieces = self.map(offset, len);
+
+        // One map per member, over all it holds of the range.
+        let mut maps = Vec::new();
+        for (member, blk) in self.members.iter().enumerate() {
+            let mine = pieces.iter().filter(|p| p.member == member);
+            let start = mine.clone().map(|p| p.offset).min();
+            let end = mine.map(|p| p.offset + p.len as u64).max();
+            maps.push(match (start, end) {
+                (Some(start), Some(end)) => blk.get_allocation(start, end - start)?,
+                _ => Vec::new(),
+            });
+        }
+
+        let mut extents = Vec::new();
+        let mut next = vec![0; self.members.len()];
+        for p in pieces {
+            let map = &maps[p.member];
+            let (start, end) = (p.offset, p.offset + p.len as u64);
+            let k = &mut next[p.member];
+            while *k < map.len() && map[*k].end() <= start {
+                *k += 1;
+            }
+
+            for e in map[*k..].iter().take_while(|e| e.offset < end) {
+                let (from, to) = (e.offset.max(start), e.end().min(end));
+                let at = offset + p.start as u64 + (from - start);
+                extents.push(Extent::new(at, to - from, e.state));
+            }
+        }
+
+        Ok(extent::normalize(extents, offset, len, Allocation::Hole))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let work: Vec<Vec<()>> = self.members.iter().map(|_| vec![()]).collect();
+        let results = self.each(work, |blk, _| vec![blk.flush()]);
+        for r in results.into_iter().flatten() {
+            r?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn group(layout: Layout, sizes: &[u64]) -> Arc<DeviceGroup> {
+        let members = sizes.iter().map(|s| BlockDevice::memory(*s)).collect();
+        Arc::new(DeviceGroup::new(layout, members).unwrap())
+    }
+
+    fn member_data(group: &DeviceGroup, member: usize) -> Vec<u8> {
+        let blk = &group.get_members()[member];
+        let mut buf = vec![0; blk.get_disk_size() as usize];
+        blk.read_at(&mut buf, 0).unwrap();
+        buf
+    }
+
+    #[test]
+    fn concat_and_stripe() {
+        let concat = group(Layout::Concat, &[64 * 1024, 128 * 1024]);
+        let data = pattern(192 * 1024);
+        let blk = BlockDevice::from_backend(concat.clone());
+        assert_eq!(blk.get_disk_size(), 192 * 1024);
+        blk.write_direct_at(&data, 0).unwrap();
+        assert_eq!(member_data(&concat, 0), &data[..64 * 1024]);
+        assert_eq!(member_data(&concat, 1), &data[64 * 1024..]);
+
+        // The last member is cut to whole chunks.
+        let stripe = group(
+            Layout::Stripe { chunk: 4096 },
+            &[64 * 1024, 64 * 1024, 66 * 1024],
+        );
+        let data = pattern(192 * 1024);
+        let blk = BlockDevice::from_backend(stripe.clone());
+        assert_eq!(blk.get_disk_size(), 192 * 1024);
+        blk.write_direct_at(&data, 0).unwrap();
+        for m in 0..3 {
+            let member = member_data(&stripe, m);
+            for j in 0..16 {
+                let chunk = (j * 3 + m) * 4096;
+                assert_eq!(
+                    &member[j * 4096..(j + 1) * 4096],
+                    &data[chunk..chunk + 4096]
+                );
+            }
+        }
+
+        // Unaligned requests across chunks and members.
+        let mut a = vec![0; 10000];
+        let mut b = vec![0; 100];
+        let mut reqs: Vec<(u64, &mut [u8])> = vec![(1000, &mut a), (12250, &mut b)];
+        for r in blk.read_batch(&mut reqs) {
+            r.unwrap();
+        }
+        assert_eq!(a, &data[1000..11000]);
+        assert_eq!(b, &data[12250..12350]);
+    }
+
+    #[test]
+    fn mirror_divergence() {
+        let members = [64 * 1024, 64 * 1024, 80 * 1024]
+            .iter()
+            .map(|s| BlockDevice::memory(*s))
+            .collect();
+        let mirror = Arc::new(
+            DeviceGroup::new(Layout::Mirror, members)
+                .unwrap()
+                .with_compare(true),
+        );
+        let blk = BlockDevice::from_backend(mirror.clone());
+        assert_eq!(blk.get_disk_size(), 64 * 1024);
+
+        let data = pattern(64 * 1024);
+        blk.write_direct_at(&data, 0).unwrap();
+        for m in 0..3 {
+            assert_eq!(&member_data(&mirror, m)[..64 * 1024], &data[..]);
+        }
+
+        let mut buf = vec![0; 64 * 1024];
+        blk.read_direct_at(&mut buf, 0).unwrap();
+        assert!(mirror.get_divergences().is_empty());
+
+        // Member 1 goes its own way; the other two outvote it.
+        mirror.get_members()[1]
+            .write_direct_at(&[0xff; 10], 5000)
+            .unwrap();
+        blk.read_direct_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(
+            mirror.get_divergences(),
+            vec![Divergence {
+                offset: 5000,
+                len: 10,
+                member: 1,
+            }]
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::mem;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::backend::Backend;
+use crate::error::BlockError;
+use crate::sysfs;
+
+// Why a device looks like it holds data someone cares about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InUse {
+    Mounted { dev: String, mount_point: String },
+    Swap(String),
+    Holder { dev: String, holder: String },
+    LoopBacking(String),
+    QemuLock,
+    Signature(&'static str),
+}
+
+impl fmt::Display for InUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InUse::Mounted { dev, mount_point } => {
+                write!(f, "{} is mounted on {}", dev, mount_point)
+            }
+            InUse::Swap(dev) => write!(f, "{} is in use as swap", dev),
+            InUse::Holder { dev, holder } => write!(f, "{} is held by {}", dev, holder),
+            InUse::LoopBacking(dev) => write!(f, "file backs {}", dev),
+            InUse::QemuLock => write!(f, "image is locked by a running qemu"),
+            InUse::Signature(name) => write!(f, "contains a {} signature", name),
+        }
+    }
+}
+
+// Magic of sectors written by this tool (see sector::schema), the same
+// in every format version. A disk that starts with it is a leftover
+// test disk, not somebody's data.
+const OWN_MAGIC: [u8; 4] = [0x43, 0x46, 0x53, 0xfb];
+
+// (name, offset, magic) of the partition tables and filesystems we
+// refuse to overwrite without force.
+const SIGNATURES: &[(&str, u64, &[u8])] = &[
+    ("GPT", 512, b"EFI PART"),
+    ("GPT", 4096, b"EFI PART"),
+    ("LVM2", 512, b"LABELONE"),
+    ("LUKS", 0, b"LUKS\xba\xbe"),
+    ("XFS", 0, b"XFSB"),
+    ("ext2/3/4", 1080, &[0x53, 0xef]),
+    ("btrfs", 65600, b"_BHRfS_M"),
+    ("swap", 4086, b"SWAPSPACE2"),
+    ("swap", 4086, b"SWAP-SPACE"),
+    ("NTFS", 3, b"NTFS    "),
+    ("MBR", 510, &[0x55, 0xaa]),
+];
+
+const SCAN_SIZE: usize = 65600 + 8;
+
+// Everything that suggests `backend` should not be written to.
+pub fn inspect(backend: &dyn Backend) -> Vec<InUse> {
+    let mut vec = Vec::new();
+    let path = backend.path();
+
+    if let Ok(meta) = fs::metadata(path) {
+        if meta.file_type().is_block_device() {
+            let name = sysfs::device_name(path);
+            let mut devs = vec![(name.clone(), dev_id(meta.rdev()))];
+            devs.extend(partitions(&name));
+
+            vec.extend(check_mounts(&devs));
+            vec.extend(check_swaps(&devs));
+            vec.extend(check_holders(&devs));
+        } else if meta.is_file() {
+            vec.extend(check_loop_backing(path));
+            vec.extend(check_qemu_lock(path));
+        }
+    }
+
+    vec.extend(check_signatures(backend));
+
+    vec
+}
+
+// Refuse writes to `path` for the reasons in `in_use`, if there are
+// any.
+pub fn refuse(path: &str, in_use: &[InUse]) -> Result<(), BlockError> {
+    if in_use.is_empty() {
+        return Ok(());
+    }
+
+    let reasons: Vec<String> = in_use.iter().map(|r| r.to_string()).collect();
+    Err(BlockError::InUse {
+        path: path.to_string(),
+        reasons: reasons.join(", "),
+    })
+}
+
+fn dev_id(rdev: u64) -> String {
+    format!("{}:{}", libc::major(rdev), libc::minor(rdev))
+}
+
+// (name, "major:minor") of the partitions of disk `name`.
+fn partitions(name: &str) -> Vec<(String, String)> {
+    let mut vec = Vec::new();
+
+    let entries = match fs::read_dir(sysfs::class_dir(name)) {
+        Ok(entries) => entries,
+        Err(_) => return vec,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let part = entry.file_name().to_string_lossy().to_string();
+        if !entry.path().join("partition").exists() {
+            continue;
+        }
+        if let Some(dev) = sysfs::read_attr(name, &format!("{}/dev", part)) {
+            vec.push((part, dev));
+        }
+    }
+
+    vec
+}
+
+fn check_mounts(devs: &[(String, String)]) -> Vec<InUse> {
+    let mut vec = Vec::new();
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+
+    // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw
+    for line in mountinfo.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 5 {
+            continue;
+        }
+
+        for (name, dev) in devs {
+            if fields[2] == dev {
+                vec.push(InUse::Mounted {
+                    dev: name.clone(),
+                    mount_point: fields[4].to_string(),
+                });
+            }
+        }
+    }
+
+    vec
+}
+
+fn check_swaps(devs: &[(String, String)]) -> Vec<InUse> {
+    let mut vec = Vec::new();
+    let swaps = fs::read_to_string("/proc/swaps").unwrap_or_default();
+
+    for line in swaps.lines().skip(1) {
+        let file = match line.split_whitespace().next() {
+            Some(file) => file,
+            None => continue,
+        };
+
+        let name = sysfs::device_name(file);
+        if devs.iter().any(|(n, _)| *n == name) {
+            vec.push(InUse::Swap(name));
+        }
+    }
+
+    vec
+}
+
+fn check_holders(devs: &[(String, String)]) -> Vec<InUse> {
+    let mut vec = Vec::new();
+
+    for (name, _) in devs {
+        let holders = match fs::read_dir(sysfs::class_dir(name).join("holders")) {
+            Ok(holders) => holders,
+            Err(_) => continue,
+        };
+
+        for holder in holders.filter_map(|e| e.ok()) {
+            vec.push(InUse::Holder {
+                dev: name.clone(),
+                holder: holder.file_name().to_string_lossy().to_string(),
+            });
+        }
+    }
+
+    vec
+}
+
+fn check_loop_backing(path: &str) -> Vec<InUse> {
+    let mut vec = Vec::new();
+    let real = match fs::canonicalize(path) {
+        Ok(real) => real,
+        Err(_) => return vec,
+    };
+
+    let entries = match fs::read_dir("/sys/class/block") {
+        Ok(entries) => entries,
+        Err(_) => return vec,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(backing) = sysfs::read_attr(&name, "loop/backing_file") {
+            if Path::new(&backing) == real {
+                vec.push(InUse::LoopBacking(name));
+            }
+        }
+    }
+
+    vec
+}
+
+// qemu takes OFD locks on bytes of the images it has open: from 100
+// one for each permission it holds, such as write, from 200 one for
+// each it won't share.
+const QEMU_LOCK_START: i64 = 100;
+const QEMU_LOCK_LEN: i64 = 200;
+
+fn check_qemu_lock(path: &str) -> Vec<InUse> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+
+    // Any of them would conflict with a write lock over all of them.
+    let mut lock: libc::flock = unsafe { mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = QEMU_LOCK_START;
+    lock.l_len = QEMU_LOCK_LEN;
+
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_GETLK, &mut lock) };
+    if ret < 0 || lock.l_type == libc::F_UNLCK as libc::c_short {
+        return Vec::new();
+    }
+
+    vec![InUse::QemuLock]
+}
+
+pub fn check_signatures(backend: &dyn Backend) -> Vec<InUse> {
+    let len = std::cmp::min(SCAN_SIZE as u64, backend.size()) as usize;
+    let mut buf = vec![0; len];
+
+    let len = match backend.read_at(&mut buf, 0) {
+        Ok(len) => len,
+        Err(_) => return Vec::new(),
+    };
+    let buf = &buf[..len];
+
+    if buf.starts_with(&OWN_MAGIC) {
+        return Vec::new();
+    }
+
+    let mut vec = Vec::new();
+    for (name, offset, magic) in SIGNATURES {
+        let start = *offset as usize;
+        let end = start + magic.len();
+
+        if end <= buf.len() && &buf[start..end] == *magic {
+            let found = InUse::Signature(name);
+            if !vec.contains(&found) {
+                vec.push(found);
+            }
+        }
+    }
+
+    vec
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::BlockDevice;
+    use crate::memory::MemoryDisk;
+    use crate::qcow2::Qcow2Image;
+    use crate::temp::TempPath;
+
+    #[test]
+    fn find_signatures() {
+        let disk = MemoryDisk::new(128 * 1024);
+        assert!(inspect(&disk).is_empty());
+
+        disk.write_direct_at(&[0x55, 0xaa], 510).unwrap();
+        disk.write_direct_at(b"EFI PART", 512).unwrap();
+        assert_eq!(
+            inspect(&disk),
+            vec![InUse::Signature("GPT"), InUse::Signature("MBR")]
+        );
+
+        disk.write_direct_at(&OWN_MAGIC, 0).unwrap();
+        assert!(inspect(&disk).is_empty());
+    }
+
+    #[test]
+    fn qemu_image_lock() {
+        let path = TempPath::new("qemu-lock.qcow2");
+        Qcow2Image::create(&path, 1024 * 1024, 16, None).unwrap();
+        let blk = BlockDevice::new(&path).unwrap();
+        assert!(blk.get_in_use().is_empty());
+
+        // As qemu holds it for a writable disk: the write permission,
+        // and not sharing it.
+        let qemu = File::open(&*path).unwrap();
+        for byte in [QEMU_LOCK_START + 1, QEMU_LOCK_START + 101] {
+            let mut lock: libc::flock = unsafe { mem::zeroed() };
+            lock.l_type = libc::F_RDLCK as libc::c_short;
+            lock.l_whence = libc::SEEK_SET as libc::c_short;
+            lock.l_start = byte;
+            lock.l_len = 1;
+            let ret = unsafe { libc::fcntl(qemu.as_raw_fd(), libc::F_OFD_SETLK, &lock) };
+            assert_eq!(ret, 0, "{}", std::io::Error::last_os_error());
+        }
+
+        let blk = BlockDevice::new(&path).unwrap();
+        assert_eq!(blk.get_in_use(), &[InUse::QemuLock]);
+        match blk.write_direct_at(&[0; 512], 0) {
+            Err(BlockError::InUse { reasons, .. }) => assert!(reasons.contains("qemu")),
+            other => panic!("expected to be refused, got {:?}", other),
+        }
+        assert!(blk.with_force(true).write_direct_at(&[0; 512], 0).is_ok());
+
+        drop(qemu);
+        assert!(BlockDevice::new(&path).unwrap().get_in_use().is_empty());
+    }
+}
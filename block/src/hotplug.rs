@@ -0,0 +1,951 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::Backend;
+use crate::error::BlockError;
+use crate::extent::Extent;
+use crate::guard;
+use crate::info::DeviceInfo;
+use crate::lock::DeviceLock;
+use crate::raw::RawDevice;
+use crate::sysfs;
+use crate::uring::UringDevice;
+
+// A kernel uevent, e.g. "remove@/devices/.../block/sdb" followed by
+// its KEY=value properties.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uevent {
+    pub action: String,
+    pub devpath: String,
+    pub props: BTreeMap<String, String>,
+}
+
+impl Uevent {
+    // Parse one netlink message. Messages re-broadcast by udev start
+    // with "libudev" and a binary header, and are not understood.
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        let mut fields = msg
+            .split(|b| *b == 0)
+            .filter(|f| !f.is_empty())
+            .map(|f| String::from_utf8_lossy(f).to_string());
+
+        let header = fields.next()?;
+        let (action, devpath) = header.split_once('@')?;
+
+        let props = fields
+            .filter_map(|f| {
+                f.split_once('=')
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+            })
+            .collect();
+
+        Some(Uevent {
+            action: action.to_string(),
+            devpath: devpath.to_string(),
+            props,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.props.get(key).map(|v| v.as_str())
+    }
+
+    // Kernel name of the device, e.g. "sdb".
+    pub fn devname(&self) -> Option<&str> {
+        self.get("DEVNAME").map(|n| n.trim_start_matches("/dev/"))
+    }
+
+    // A whole block device, not a partition.
+    pub fn is_disk(&self) -> bool {
+        self.get("SUBSYSTEM") == Some("block") && self.get("DEVTYPE") == Some("disk")
+    }
+}
+
+impl fmt::Display for Uevent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.devname() {
+            Some(name) => write!(f, "{} {}", self.action, name),
+            None => write!(f, "{} {}", self.action, self.devpath),
+        }
+    }
+}
+
+// Multicast group the kernel sends uevents to, as opposed to the one
+// udev re-broadcasts them on after processing.
+const UEVENT_KERNEL_GROUP: u32 = 1;
+
+// Socket receiving the kernel's uevents.
+#[derive(Debug)]
+pub struct UeventSocket {
+    fd: OwnedFd,
+}
+
+impl UeventSocket {
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = UEVENT_KERNEL_GROUP;
+
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(UeventSocket { fd })
+    }
+
+    // The next uevent, or None if there was none within `timeout` or
+    // the message could not be parsed.
+    pub fn recv(&self, timeout: Duration) -> io::Result<Option<Uevent>> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = timeout.as_millis().min(i32::MAX as u128) as libc::c_int;
+
+        let ret = unsafe { libc::poll(&mut pfd, 1, ms) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+        if ret == 0 {
+            return Ok(None);
+        }
+
+        let mut buf = vec![0u8; 8192];
+        let size = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+
+        Ok(Uevent::parse(&buf[..size as usize]))
+    }
+}
+
+// What tells a disk apart from others when it comes back, possibly
+// under another name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+    pub serial: Option<String>,
+    pub wwn: Option<String>,
+}
+
+impl Identity {
+    pub fn of(info: &DeviceInfo) -> Self {
+        Identity {
+            serial: info.serial.clone(),
+            wwn: info.wwn.clone(),
+        }
+    }
+
+    pub fn is_known(&self) -> bool {
+        self.serial.is_some() || self.wwn.is_some()
+    }
+
+    // The WWN decides where both disks have one, the serial otherwise.
+    pub fn matches(&self, other: &Identity) -> bool {
+        match (&self.wwn, &other.wwn, &self.serial, &other.serial) {
+            (Some(a), Some(b), _, _) => a == b,
+            (_, _, Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.wwn, &self.serial) {
+            (Some(wwn), _) => write!(f, "wwn {}", wwn),
+            (None, Some(serial)) => write!(f, "serial {}", serial),
+            (None, None) => write!(f, "unknown identity"),
+        }
+    }
+}
+
+// The device node of the disk with identity `id`. Without an identity
+// to go by, `path` itself once it exists again if `same_path`, which
+// may well be another disk.
+pub fn find_device(id: &Identity, path: &str, same_path: bool) -> Option<String> {
+    if !id.is_known() {
+        if !same_path {
+            return None;
+        }
+        let file_type = fs::metadata(path).ok()?.file_type();
+        return file_type.is_block_device().then(|| path.to_string());
+    }
+
+    let entries = fs::read_dir("/sys/class/block").ok()?;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if sysfs::read_attr(&name, "partition").is_some() {
+            continue;
+        }
+
+        let dev = format!("/dev/{}", name);
+        let info = DeviceInfo::default().with_sysfs(&dev);
+        if id.matches(&Identity::of(&info)) && Path::new(&dev).exists() {
+            return Some(dev);
+        }
+    }
+
+    None
+}
+
+// Open the disk that came back as `found` with `open`, held to the
+// checks the device it replaces passed. If that was locked, `lock` is
+// replaced by a lock on `found` unless it covers it already, as locks
+// go by major:minor and the disk may be back under another. Unless
+// `force`, it is refused if the guard finds it in use.
+fn reopen_checked<F>(
+    found: &str,
+    lock: &Mutex<Option<Arc<DeviceLock>>>,
+    force: bool,
+    open: F,
+) -> Result<Arc<dyn Backend>, BlockError>
+where
+    F: FnOnce(&str) -> Result<Arc<dyn Backend>, BlockError>,
+{
+    let mut lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+    if lock.as_ref().is_some_and(|l| !l.covers(found)) {
+        *lock = Some(Arc::new(DeviceLock::acquire(found)?));
+    }
+
+    let backend = open(found)?;
+    if !force {
+        guard::refuse(found, &guard::inspect(backend.as_ref()))?;
+    }
+
+    Ok(backend)
+}
+
+// Events of a run with the time they happened, relative to the start.
+#[derive(Debug)]
+pub struct Timeline {
+    start: Instant,
+    events: Mutex<Vec<(Duration, String)>>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Timeline::new()
+    }
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Timeline {
+            start: Instant::now(),
+            events: Mutex::default(),
+        }
+    }
+
+    // Record and print `event` as it happens.
+    pub fn record(&self, event: String) {
+        let at = self.start.elapsed();
+        println!(">>> [{:>9.3}s] {}", at.as_secs_f64(), event);
+
+        if let Ok(mut events) = self.events.lock() {
+            events.push((at, event));
+        }
+    }
+
+    pub fn get_events(&self) -> Vec<(Duration, String)> {
+        self.events.lock().map(|e| e.clone()).unwrap_or_default()
+    }
+
+    pub fn show_info(&self) {
+        let events = self.get_events();
+        if events.is_empty() {
+            return;
+        }
+
+        println!("timeline:");
+        for (at, event) in events {
+            println!("  {:>9.3}s  {}", at.as_secs_f64(), event);
+        }
+    }
+}
+
+// Opens the device again once it is back, or fails while it isn't.
+pub type Reopen = dyn Fn() -> Result<Arc<dyn Backend>, BlockError> + Send + Sync;
+
+// How often the device is looked for while waiting, in case there is
+// no uevent to wake us up.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// State shared with the uevent watcher.
+#[derive(Debug, Default)]
+struct Watch {
+    // Kernel name of the device while it is present.
+    name: Mutex<Option<String>>,
+    waiting: AtomicBool,
+    stop: AtomicBool,
+    events: Mutex<u64>,
+    cond: Condvar,
+}
+
+impl Watch {
+    fn get_name(&self) -> Option<String> {
+        self.name.lock().ok().and_then(|n| n.clone())
+    }
+
+    fn notify(&self) {
+        if let Ok(mut events) = self.events.lock() {
+            *events += 1;
+        }
+        self.cond.notify_all();
+    }
+
+    // Sleep until the next uevent, or for at most `timeout`.
+    fn wait(&self, timeout: Duration) {
+        let events = match self.events.lock() {
+            Ok(events) => events,
+            Err(_) => return thread::sleep(timeout),
+        };
+        let seen = *events;
+        let _ = self
+            .cond
+            .wait_timeout_while(events, timeout, |e| *e == seen);
+    }
+}
+
+// Wraps a backend whose device may be unplugged during a run. When a
+// request fails because the device is gone, waits up to `timeout` for
+// it to come back, opens it again and retries the request, so that
+// the run resumes where it stopped. Everything that happens to the
+// device is recorded on a `Timeline`.
+pub struct HotplugDevice {
+    path: String,
+    size: u64,
+    timeout: Duration,
+    reopen: Box<Reopen>,
+    // The backend in use and how many times it was replaced.
+    inner: RwLock<(Arc<dyn Backend>, u64)>,
+    reattach: Mutex<()>,
+    timeline: Arc<Timeline>,
+    watch: Arc<Watch>,
+}
+
+impl fmt::Debug for HotplugDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HotplugDevice")
+            .field("inner", &self.current().0)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl HotplugDevice {
+    pub fn new(inner: Arc<dyn Backend>, timeout: Duration, reopen: Box<Reopen>) -> Self {
+        HotplugDevice {
+            path: inner.path().to_string(),
+            size: inner.size(),
+            timeout,
+            reopen,
+            inner: RwLock::new((inner, 0)),
+            reattach: Mutex::new(()),
+            timeline: Arc::new(Timeline::new()),
+            watch: Arc::default(),
+        }
+    }
+
+    // For a host block device: it is found again by serial or WWN,
+    // under whatever name it comes back as, or only if `same_path` by
+    // its path where it has neither. It is reopened like `inner`
+    // with `exclusive` and the queue depth of `inner`, see
+    // `reopen_checked` for `lock` and `force`. Removal and arrival of
+    // disks are followed through kernel uevents.
+    pub fn block_device(
+        inner: Arc<dyn Backend>,
+        exclusive: bool,
+        lock: Option<Arc<DeviceLock>>,
+        force: bool,
+        timeout: Duration,
+        same_path: bool,
+    ) -> Result<Self, BlockError> {
+        let path = inner.path().to_string();
+        let meta = fs::metadata(&path).map_err(|e| BlockError::open(&path, e))?;
+        if !meta.file_type().is_block_device() {
+            return Err(BlockError::Unsupported(format!(
+                "{} is not a block device",
+                path
+            )));
+        }
+
+        let identity = Identity::of(&inner.info());
+        if !identity.is_known() {
+            println!(
+                ">>> {}: no serial or wwn, whatever disk appears at that path is taken for it",
+                path
+            );
+            if !same_path {
+                return Err(BlockError::Unsupported(format!(
+                    "{}: cannot tell it from another disk when it returns",
+                    path
+                )));
+            }
+        }
+        let depth = inner.queue_depth();
+        let name = sysfs::device_name(&path);
+
+        let orig = path.clone();
+        let id = identity.clone();
+        let lock = Mutex::new(lock);
+        let reopen = move || -> Result<Arc<dyn Backend>, BlockError> {
+            let found = find_device(&id, &orig, same_path)
+                .ok_or_else(|| BlockError::Vanished(orig.clone()))?;
+            let raw = reopen_checked(&found, &lock, force, |found| {
+                Ok(Arc::new(RawDevice::open(found, exclusive)?))
+            })?;
+            if depth <= 1 {
+                return Ok(raw);
+            }
+
+            match UringDevice::new(raw, depth) {
+                Ok(uring) => Ok(Arc::new(uring)),
+                Err((raw, _)) => Ok(raw),
+            }
+        };
+
+        let dev = HotplugDevice::new(inner, timeout, Box::new(reopen));
+        if let Ok(mut n) = dev.watch.name.lock() {
+            *n = Some(name);
+        }
+
+        match UeventSocket::open() {
+            Ok(socket) => dev.spawn_watcher(socket),
+            Err(e) => println!(">>> no uevents ({}), polling for {}", e, path),
+        }
+        if identity.is_known() {
+            println!(">>> {}: will wait for {} to return", path, identity);
+        }
+
+        Ok(dev)
+    }
+
+    pub fn get_timeline(&self) -> Arc<Timeline> {
+        Arc::clone(&self.timeline)
+    }
+
+    // Record removal of the device and disks arriving while we wait,
+    // and wake up the waiter on each.
+    fn spawn_watcher(&self, socket: UeventSocket) {
+        let watch = Arc::clone(&self.watch);
+        let timeline = Arc::clone(&self.timeline);
+
+        thread::spawn(move || {
+            while !watch.stop.load(Ordering::Relaxed) {
+                let event = match socket.recv(Duration::from_millis(500)) {
+                    Ok(Some(event)) if event.is_disk() => event,
+                    Ok(_) => continue,
+                    Err(_) => break,
+                };
+
+                let ours =
+                    event.devname().is_some() && event.devname() == watch.get_name().as_deref();
+                let waiting = watch.waiting.load(Ordering::Relaxed);
+                if (event.action == "remove" && ours) || (event.action == "add" && waiting) {
+                    timeline.record(format!("uevent: {}", event));
+                }
+                watch.notify();
+            }
+        });
+    }
+
+    fn current(&self) -> (Arc<dyn Backend>, u64) {
+        let inner = self.inner.read().unwrap_or_else(|e| e.into_inner());
+
+        (Arc::clone(&inner.0), inner.1)
+    }
+
+    // Whether `e` means the device went away. Removed disks fail
+    // requests with ENODEV or ENXIO, or with EIO while the kernel
+    // tears them down.
+    fn vanished(&self, e: &io::Error) -> bool {
+        match e.raw_os_error() {
+            Some(libc::ENODEV) | Some(libc::ENXIO) => true,
+            Some(libc::EIO) => match self.watch.get_name() {
+                Some(name) => !sysfs::class_dir(&name).exists(),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    // Wait for the device to return after backend `generation` failed
+    // with `e`, and switch to it. Requests that failed on the same
+    // backend concurrently just wait for the first to get through.
+    fn reattach(&self, generation: u64, e: &io::Error) -> io::Result<()> {
+        let _reattach = self.reattach.lock().unwrap_or_else(|e| e.into_inner());
+        if self.current().1 != generation {
+            return Ok(());
+        }
+
+        self.timeline.record(format!(
+            "{}: {}, waiting up to {}s for it to return",
+            self.path,
+            e,
+            self.timeout.as_secs()
+        ));
+        if let Ok(mut name) = self.watch.name.lock() {
+            name.take();
+        }
+        self.watch.waiting.store(true, Ordering::Relaxed);
+        let ret = self.wait_and_reopen();
+        self.watch.waiting.store(false, Ordering::Relaxed);
+
+        let backend = match ret {
+            Ok(backend) => backend,
+            Err(e) => {
+                self.timeline.record(format!("{}: {}", self.path, e));
+                return Err(io::Error::from_raw_os_error(libc::ENODEV));
+            }
+        };
+
+        let path = backend.path().to_string();
+        if let Ok(mut name) = self.watch.name.lock() {
+            *name = Some(sysfs::device_name(&path));
+        }
+        self.timeline
+            .record(format!("{}: back as {}, resuming", self.path, path));
+
+        let mut inner = self.inner.write().unwrap_or_else(|e| e.into_inner());
+        *inner = (backend, generation + 1);
+
+        Ok(())
+    }
+
+    fn wait_and_reopen(&self) -> Result<Arc<dyn Backend>, String> {
+        let deadline = Instant::now() + self.timeout;
+
+        loop {
+            match (self.reopen)() {
+                Ok(backend) if backend.size() == self.size => return Ok(backend),
+                Ok(backend) => {
+                    return Err(format!(
+                        "came back as {} with {} bytes instead of {}",
+                        backend.path(),
+                        backend.size(),
+                        self.size
+                    ))
+                }
+                Err(BlockError::Vanished(_)) => {}
+                Err(e) => {
+                    return Err(format!("cannot reopen: {}", e));
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(format!(
+                    "did not return within {}s, giving up",
+                    self.timeout.as_secs()
+                ));
+            }
+            self.watch.wait(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    // Run `f` on the current backend, again on the new one for as long
+    // as the device vanishes and comes back.
+    fn retry<T, F>(&self, mut f: F) -> io::Result<T>
+    where
+        F: FnMut(&dyn Backend) -> io::Result<T>,
+    {
+        loop {
+            let (backend, generation) = self.current();
+            match f(backend.as_ref()) {
+                Err(e) if self.vanished(&e) => self.reattach(generation, &e)?,
+                ret => return ret,
+            }
+        }
+    }
+
+    // Batches are retried from the requests that failed because the
+    // device vanished.
+    fn retry_batch<F>(&self, n: usize, mut f: F) -> Vec<io::Result<usize>>
+    where
+        F: FnMut(&dyn Backend, &[usize]) -> Vec<io::Result<usize>>,
+    {
+        let mut results: Vec<io::Result<usize>> = (0..n)
+            .map(|_| Err(io::Error::from_raw_os_error(libc::ENODEV)))
+            .collect();
+        let mut todo: Vec<usize> = (0..n).collect();
+
+        while !todo.is_empty() {
+            let (backend, generation) = self.current();
+            let ret = f(backend.as_ref(), &todo);
+
+            let mut failed = Vec::new();
+            let mut error = None;
+            for (i, r) in todo.into_iter().zip(ret) {
+                match r {
+                    Err(e) if self.vanished(&e) => {
+                        failed.push(i);
+                        error.get_or_insert(e);
+                    }
+                    r => results[i] = r,
+                }
+            }
+
+            todo = failed;
+            if let Some(e) = error {
+                if self.reattach(generation, &e).is_err() {
+                    break;
+                }
+            }
+        }
+
+        results
+    }
+}
+
+impl Drop for HotplugDevice {
+    fn drop(&mut self) {
+        self.watch.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Backend for HotplugDevice {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn info(&self) -> DeviceInfo {
+        let mut info = self.current().0.info();
+        info.path = self.path.clone();
+        info.backing.push("hotplug".to_string());
+
+        info
+    }
+
+    fn logical_block_size(&self) -> u64 {
+        self.current().0.logical_block_size()
+    }
+
+    fn physical_block_size(&self) -> u64 {
+        self.current().0.physical_block_size()
+    }
+
+    fn alignment(&self) -> u64 {
+        self.current().0.alignment()
+    }
+
+    // No `raw_fd`: the descriptor changes when the device comes back.
+
+    fn queue_depth(&self) -> usize {
+        self.current().0.queue_depth()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.retry(|b| b.read_at(buf, offset))
+    }
+
+    fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.retry(|b| b.read_direct_at(buf, offset))
+    }
+
+    fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.retry(|b| b.write_direct_at(buf, offset))
+    }
+
+    fn read_vectored_at(&self, bufs: &mut [&mut [u8]], offset: u64) -> io::Result<usize> {
+        self.retry(|b| b.read_vectored_at(bufs, offset))
+    }
+
+    fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> io::Result<usize> {
+        self.retry(|b| b.write_vectored_at(bufs, offset))
+    }
+
+    fn read_batch(&self, reqs: &mut [(u64, &mut [u8])]) -> Vec<io::Result<usize>> {
+        self.retry_batch(reqs.len(), |b, todo| {
+            let mut sub: Vec<(u64, &mut [u8])> = reqs
+                .iter_mut()
+                .enumerate()
+                .filter(|(i, _)| todo.contains(i))
+                .map(|(_, (offset, buf))| (*offset, &mut **buf))
+                .collect();
+            b.read_batch(&mut sub)
+        })
+    }
+
+    fn write_batch(&self, reqs: &[(u64, &[u8])]) -> Vec<io::Result<usize>> {
+        self.retry_batch(reqs.len(), |b, todo| {
+            let sub: Vec<(u64, &[u8])> = todo.iter().map(|i| reqs[*i]).collect();
+            b.write_batch(&sub)
+        })
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.retry(|b| b.discard(offset, len))
+    }
+
+    fn secure_discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.retry(|b| b.secure_discard(offset, len))
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        self.current().0.discard_zeroes_data()
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.retry(|b| b.write_zeroes(offset, len))
+    }
+
+    fn allocation(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        self.retry(|b| b.allocation(offset, len))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.retry(|b| b.flush())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::ImageFile;
+    use crate::memory::MemoryDisk;
+    use crate::temp::TempPath;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn parse_uevent() {
+        let msg = b"remove@/devices/virtual/block/nbd3\0ACTION=remove\0\
+            DEVPATH=/devices/virtual/block/nbd3\0SUBSYSTEM=block\0\
+            DEVNAME=nbd3\0DEVTYPE=disk\0SEQNUM=4242\0";
+        let event = Uevent::parse(msg).unwrap();
+
+        assert_eq!(event.action, "remove");
+        assert_eq!(event.devpath, "/devices/virtual/block/nbd3");
+        assert_eq!(event.devname(), Some("nbd3"));
+        assert_eq!(event.get("SEQNUM"), Some("4242"));
+        assert!(event.is_disk());
+        assert_eq!(event.to_string(), "remove nbd3");
+
+        assert!(Uevent::parse(b"libudev\0\xfe\xed\xca\xfe").is_none());
+    }
+
+    #[test]
+    fn identity_match() {
+        let id = |serial: Option<&str>, wwn: Option<&str>| Identity {
+            serial: serial.map(|s| s.to_string()),
+            wwn: wwn.map(|s| s.to_string()),
+        };
+
+        assert!(id(Some("A"), None).matches(&id(Some("A"), Some("w"))));
+        assert!(!id(Some("A"), Some("x")).matches(&id(Some("A"), Some("w"))));
+        assert!(!id(None, None).matches(&id(None, None)));
+
+        // Without an identity only ever by path, and only if asked to.
+        let disk = fs::read_dir("/sys/block")
+            .ok()
+            .and_then(|mut d| d.next())
+            .and_then(|e| e.ok())
+            .map(|e| format!("/dev/{}", e.file_name().to_string_lossy()))
+            .filter(|dev| Path::new(dev).exists());
+        if let Some(dev) = disk {
+            assert_eq!(find_device(&id(None, None), &dev, false), None);
+            assert_eq!(find_device(&id(None, None), &dev, true), Some(dev));
+        }
+    }
+
+    // Fails every request with ENODEV once `gone` is set.
+    #[derive(Debug)]
+    struct Unplugged {
+        inner: MemoryDisk,
+        gone: Arc<AtomicBool>,
+    }
+
+    impl Unplugged {
+        fn check(&self) -> io::Result<()> {
+            if self.gone.load(Ordering::Relaxed) {
+                return Err(io::Error::from_raw_os_error(libc::ENODEV));
+            }
+            Ok(())
+        }
+    }
+
+    impl Backend for Unplugged {
+        fn path(&self) -> &str {
+            "/dev/test"
+        }
+
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+
+        fn info(&self) -> DeviceInfo {
+            self.inner.info()
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.check()?;
+            self.inner.read_at(buf, offset)
+        }
+
+        fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+            self.check()?;
+            self.inner.write_direct_at(buf, offset)
+        }
+    }
+
+    #[test]
+    fn reattach_and_resume() {
+        let gone = Arc::new(AtomicBool::new(false));
+        let inner: Arc<dyn Backend> = Arc::new(Unplugged {
+            inner: MemoryDisk::new(64 * 1024),
+            gone: Arc::clone(&gone),
+        });
+
+        // Back on the third look.
+        let tries = Arc::new(AtomicUsize::new(0));
+        let t = Arc::clone(&tries);
+        let reopen = move || -> Result<Arc<dyn Backend>, BlockError> {
+            if t.fetch_add(1, Ordering::Relaxed) < 2 {
+                return Err(BlockError::Vanished("/dev/test".to_string()));
+            }
+            let disk = MemoryDisk::new(64 * 1024);
+            disk.write_direct_at(&[0x5a; 4096], 0)
+                .map_err(|e| BlockError::io("/dev/test", 0, e))?;
+            Ok(Arc::new(disk))
+        };
+        let dev = HotplugDevice::new(inner, Duration::from_secs(10), Box::new(reopen));
+        dev.write_direct_at(&[0x11; 4096], 4096).unwrap();
+
+        gone.store(true, Ordering::Relaxed);
+        let mut buf = [0; 4096];
+        let mut reqs: Vec<(u64, &mut [u8])> = vec![(0, &mut buf[..])];
+        assert_eq!(dev.read_batch(&mut reqs)[0].as_ref().unwrap(), &4096);
+        assert!(buf.iter().all(|b| *b == 0x5a));
+        assert_eq!(tries.load(Ordering::Relaxed), 3);
+
+        let events = dev.get_timeline().get_events();
+        assert_eq!(events.len(), 2, "{:?}", events);
+        assert!(events[0].1.contains("waiting"));
+        assert!(events[1].1.contains("resuming"));
+    }
+
+    #[test]
+    fn give_up_after_timeout() {
+        let inner: Arc<dyn Backend> = Arc::new(Unplugged {
+            inner: MemoryDisk::new(64 * 1024),
+            gone: Arc::new(AtomicBool::new(true)),
+        });
+        let reopen = || -> Result<Arc<dyn Backend>, BlockError> {
+            Err(BlockError::Vanished("/dev/test".to_string()))
+        };
+        let dev = HotplugDevice::new(inner, Duration::from_millis(100), Box::new(reopen));
+
+        let e = dev.write_direct_at(&[0; 512], 0).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENODEV));
+        assert_eq!(dev.get_timeline().get_events().len(), 2);
+    }
+
+    #[test]
+    fn reopen_takes_lock_and_guards() {
+        let a = TempPath::new("reopen-a.img");
+        let b = TempPath::new("reopen-b.img");
+        ImageFile::create(&a, 64 * 1024).unwrap();
+        ImageFile::create(&b, 64 * 1024).unwrap();
+        let open = |found: &str| -> Result<Arc<dyn Backend>, BlockError> {
+            Ok(Arc::new(ImageFile::open(found)?))
+        };
+
+        // Back as the same device, under the lock already held.
+        let lock = Mutex::new(Some(Arc::new(DeviceLock::acquire(&a).unwrap())));
+        reopen_checked(&a, &lock, false, open).unwrap();
+
+        // Back as another one, which someone else has locked.
+        let other = DeviceLock::acquire(&b).unwrap();
+        match reopen_checked(&b, &lock, false, open) {
+            Err(BlockError::Locked { .. }) => {}
+            ret => panic!("expected a lock error, got {:?}", ret.map(|_| ())),
+        }
+        drop(other);
+        reopen_checked(&b, &lock, false, open).unwrap();
+        assert!(lock.lock().unwrap().as_ref().unwrap().covers(&b));
+
+        // Back with a partition table.
+        let mbr = |_: &str| -> Result<Arc<dyn Backend>, BlockError> {
+            let disk = MemoryDisk::new(64 * 1024);
+            disk.write_direct_at(&[0x55, 0xaa], 510)
+                .map_err(|e| BlockError::io("/dev/test", 510, e))?;
+            Ok(Arc::new(disk))
+        };
+        let lock = Mutex::new(None);
+        match reopen_checked("/dev/test", &lock, false, mbr) {
+            Err(BlockError::InUse { reasons, .. }) => assert!(reasons.contains("MBR")),
+            ret => panic!("expected to be refused, got {:?}", ret.map(|_| ())),
+        }
+        assert!(reopen_checked("/dev/test", &lock, true, mbr).is_ok());
+    }
+
+    #[test]
+    fn refused_on_return() {
+        let inner: Arc<dyn Backend> = Arc::new(Unplugged {
+            inner: MemoryDisk::new(64 * 1024),
+            gone: Arc::new(AtomicBool::new(true)),
+        });
+        let reopen = || -> Result<Arc<dyn Backend>, BlockError> {
+            Err(BlockError::InUse {
+                path: "/dev/test".to_string(),
+                reasons: "contains a MBR signature".to_string(),
+            })
+        };
+        let dev = HotplugDevice::new(inner, Duration::from_secs(10), Box::new(reopen));
+
+        let e = dev.write_direct_at(&[0; 512], 0).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENODEV));
+        let events = dev.get_timeline().get_events();
+        assert_eq!(events.len(), 2);
+        assert!(events[1].1.contains("refusing"), "{:?}", events);
+    }
+}
@@ -0,0 +1,266 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::aligned::AlignedBuf;
+use crate::backend::Backend;
+use crate::error::BlockError;
+use crate::extent::{self, Extent};
+use crate::info::DeviceInfo;
+
+// A raw disk image kept in a regular file, e.g. a .img on tmpfs or
+// ext4. Direct reads and writes use `O_DIRECT` when the filesystem
+// supports it and the request is aligned to its block size. Others
+// go through the page cache and drop the pages they touched, so a
+// later read still comes from storage where there is any.
+#[derive(Debug)]
+pub struct ImageFile {
+    path: String,
+    file: File,
+    direct: Option<File>,
+    direct_align: u64,
+    size: u64,
+}
+
+impl ImageFile {
+    pub fn open(path: &str) -> Result<Self, BlockError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .or_else(|_| File::open(path))
+            .map_err(|e| BlockError::open(path, e))?;
+
+        let meta = file.metadata().map_err(|e| BlockError::open(path, e))?;
+        if !meta.is_file() {
+            return Err(BlockError::Unsupported(format!(
+                "{} is not a regular file",
+                path
+            )));
+        }
+
+        Ok(ImageFile::from_file(path, file, meta.len()))
+    }
+
+    fn from_file(path: &str, file: File, size: u64) -> Self {
+        let writable = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) } & libc::O_ACCMODE
+            == libc::O_RDWR;
+
+        // tmpfs and some FUSE filesystems fail the open with EINVAL.
+        let direct = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+            .ok();
+        let direct_align = file
+            .metadata()
+            .map(|m| m.blksize())
+            .unwrap_or(4096)
+            .max(512);
+
+        ImageFile {
+            path: path.to_string(),
+            file,
+            direct,
+            direct_align,
+            size,
+        }
+    }
+
+    // Create (or truncate) an image file of `size` bytes. The file
+    // is sparse until it is written.
+    pub fn create(path: &str, size: u64) -> Result<Self, BlockError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| BlockError::open(path, e))?;
+        file.set_len(size).map_err(|e| BlockError::open(path, e))?;
+
+        Ok(ImageFile::from_file(path, file, size))
+    }
+
+    pub fn is_direct(&self) -> bool {
+        self.direct.is_some()
+    }
+
+    // The descriptor to use for direct I/O of `len` bytes at `offset`.
+    fn direct_file(&self, len: usize, offset: u64) -> Option<&File> {
+        let align = self.direct_align;
+        if !offset.is_multiple_of(align) || !(len as u64).is_multiple_of(align) {
+            return None;
+        }
+
+        self.direct.as_ref()
+    }
+}
+
+impl Backend for ImageFile {
+    fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo::new(self, "image file")
+    }
+
+    // Only the `O_DIRECT` one: io_uring reads through the other would
+    // come from the page cache, with nothing to drop it first.
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.direct.as_ref().map(|f| f.as_raw_fd())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = clamp_len(buf.len(), offset, self.size);
+        self.file.read_exact_at(&mut buf[..len], offset)?;
+        Ok(len)
+    }
+
+    fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = clamp_len(buf.len(), offset, self.size);
+
+        let direct = match self.direct_file(len, offset) {
+            Some(direct) => direct,
+            None => {
+                drop_cache(&self.file, offset, len);
+                self.file.read_exact_at(&mut buf[..len], offset)?;
+                drop_cache(&self.file, offset, len);
+                return Ok(len);
+            }
+        };
+
+        let align = self.direct_align as usize;
+        if AlignedBuf::is_aligned(buf, align) {
+            direct.read_exact_at(&mut buf[..len], offset)?;
+        } else {
+            let mut bounce = AlignedBuf::new(len, align);
+            direct.read_exact_at(&mut bounce, offset)?;
+            buf[..len].copy_from_slice(&bounce);
+        }
+
+        Ok(len)
+    }
+
+    fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let len = clamp_len(buf.len(), offset, self.size);
+
+        let direct = match self.direct_file(len, offset) {
+            Some(direct) => direct,
+            None => {
+                self.file.write_all_at(&buf[..len], offset)?;
+                write_back(&self.file, offset, len)?;
+                return Ok(len);
+            }
+        };
+
+        let align = self.direct_align as usize;
+        if AlignedBuf::is_aligned(buf, align) {
+            direct.write_all_at(&buf[..len], offset)?;
+        } else {
+            let mut bounce = AlignedBuf::new(len, align);
+            bounce.copy_from_slice(&buf[..len]);
+            direct.write_all_at(&bounce, offset)?;
+        }
+
+        Ok(len)
+    }
+
+    // Punched holes always read back as zeroes.
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.fallocate(libc::FALLOC_FL_PUNCH_HOLE, offset, len)
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        true
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.fallocate(libc::FALLOC_FL_ZERO_RANGE, offset, len)
+            .or_else(|_| self.fallocate(libc::FALLOC_FL_PUNCH_HOLE, offset, len))
+    }
+
+    fn allocation(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let len = clamp_len(len as usize, offset, self.size) as u64;
+        Ok(extent::file_map(&self.file, offset, len))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+impl ImageFile {
+    fn fallocate(&self, mode: libc::c_int, offset: u64, len: u64) -> io::Result<()> {
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                mode | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+// Drop the cached pages of a range, so that the next read of it
+// comes from storage. Dirty pages are left alone, see `write_back`.
+pub(crate) fn drop_cache(file: &File, offset: u64, len: usize) {
+    // Only advice; nothing to be done if it is not taken.
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        )
+    };
+}
+
+// Write a range out to storage and drop it from the cache.
+pub(crate) fn write_back(file: &File, offset: u64, len: usize) -> io::Result<()> {
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+        | libc::SYNC_FILE_RANGE_WRITE
+        | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+
+    let ret = unsafe {
+        libc::sync_file_range(
+            file.as_raw_fd(),
+            offset as libc::off64_t,
+            len as libc::off64_t,
+            flags,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    drop_cache(file, offset, len);
+
+    Ok(())
+}
+
+// Number of bytes of a `len` byte request at `offset` that fall
+// inside a disk of `size` bytes.
+pub(crate) fn clamp_len(len: usize, offset: u64, size: u64) -> usize {
+    if offset >= size {
+        return 0;
+    }
+
+    std::cmp::min(len as u64, size - offset) as usize
+}
@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::backend::Backend;
+use crate::sysfs;
+
+// What is known about the storage under test, so reports can say
+// which backend they were produced on. Fields the backend or kernel
+// doesn't provide are left empty.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceInfo {
+    pub path: String,
+    pub kind: String,
+    pub size: u64,
+    pub logical_block_size: u64,
+    pub physical_block_size: u64,
+    pub queue_depth: usize,
+
+    pub name: Option<String>,
+    pub dev: Option<String>,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub wwn: Option<String>,
+    pub rotational: Option<bool>,
+    pub hw_queue_depth: Option<u64>,
+    pub nr_requests: Option<u64>,
+    pub scheduler: Option<String>,
+    pub write_cache: Option<String>,
+    pub discard_granularity: Option<u64>,
+    pub discard_max_bytes: Option<u64>,
+    pub backing: Vec<String>,
+    pub virtio: Option<String>,
+    pub udev: BTreeMap<String, String>,
+}
+
+// udev properties worth showing in a report.
+const UDEV_KEYS: &[&str] = &[
+    "ID_BUS",
+    "ID_MODEL",
+    "ID_SERIAL",
+    "ID_WWN",
+    "ID_PATH",
+    "ID_PART_TABLE_TYPE",
+];
+
+impl DeviceInfo {
+    // The basics every backend can answer.
+    pub fn new(backend: &dyn Backend, kind: &str) -> Self {
+        DeviceInfo {
+            path: backend.path().to_string(),
+            kind: kind.to_string(),
+            size: backend.size(),
+            logical_block_size: backend.logical_block_size(),
+            physical_block_size: backend.physical_block_size(),
+            queue_depth: backend.queue_depth(),
+            ..Default::default()
+        }
+    }
+
+    // Fill in identity and topology of host block device `path`.
+    pub fn with_sysfs(mut self, path: &str) -> Self {
+        let name = sysfs::device_name(path);
+        let attr = |a: &str| sysfs::read_disk_attr(&name, a);
+        let number = |a: &str| attr(a).and_then(|v| v.parse::<u64>().ok());
+
+        self.vendor = attr("device/vendor");
+        self.model = attr("device/model");
+        self.serial = attr("serial").or_else(|| attr("device/serial"));
+        self.wwn = attr("wwid").or_else(|| attr("device/wwid"));
+        self.rotational = attr("queue/rotational").map(|v| v == "1");
+        self.hw_queue_depth = number("device/queue_depth");
+        self.nr_requests = number("queue/nr_requests");
+        self.scheduler = attr("queue/scheduler").map(|v| active_scheduler(&v));
+        self.write_cache = attr("queue/write_cache");
+        self.discard_granularity = number("queue/discard_granularity");
+        self.discard_max_bytes = number("queue/discard_max_bytes");
+        self.backing = backing(&name);
+        self.virtio = virtio(&name);
+
+        if let Ok(meta) = fs::metadata(path) {
+            let rdev = meta.rdev();
+            let dev = format!("{}:{}", libc::major(rdev), libc::minor(rdev));
+            self.udev = udev_properties(&dev);
+            self.dev = Some(dev);
+        }
+
+        if self.serial.is_none() {
+            self.serial = self.udev.get("ID_SERIAL").cloned();
+        }
+        if self.wwn.is_none() {
+            self.wwn = self.udev.get("ID_WWN").cloned();
+        }
+        self.name = Some(name);
+
+        self
+    }
+
+    pub fn show_info(&self) {
+        println!("device: {} ({})", self.path, self.kind);
+        println!(
+            "  size: {}, logical block size: {}, physical block size: {}, queue depth: {}",
+            self.size, self.logical_block_size, self.physical_block_size, self.queue_depth
+        );
+
+        let fields: Vec<(&str, Option<String>)> = vec![
+            ("name", self.name.clone()),
+            ("dev", self.dev.clone()),
+            ("vendor", self.vendor.clone()),
+            ("model", self.model.clone()),
+            ("serial", self.serial.clone()),
+            ("wwn", self.wwn.clone()),
+            ("rotational", self.rotational.map(|v| v.to_string())),
+            ("hw queue depth", self.hw_queue_depth.map(|v| v.to_string())),
+            ("nr_requests", self.nr_requests.map(|v| v.to_string())),
+            ("scheduler", self.scheduler.clone()),
+            ("write cache", self.write_cache.clone()),
+            (
+                "discard granularity",
+                self.discard_granularity.map(|v| v.to_string()),
+            ),
+            (
+                "discard max bytes",
+                self.discard_max_bytes.map(|v| v.to_string()),
+            ),
+            ("virtio", self.virtio.clone()),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                println!("  {}: {}", key, value);
+            }
+        }
+
+        for backing in self.backing.iter() {
+            println!("  backing: {}", backing);
+        }
+        for (key, value) in self.udev.iter() {
+            if UDEV_KEYS.contains(&key.as_str()) {
+                println!("  {}: {}", key, value);
+            }
+        }
+    }
+}
+
+// "mq-deadline kyber [bfq] none" -> "bfq"
+fn active_scheduler(s: &str) -> String {
+    s.split_whitespace()
+        .find(|w| w.starts_with('['))
+        .map(|w| w.trim_matches(|c| c == '[' || c == ']').to_string())
+        .unwrap_or_else(|| s.to_string())
+}
+
+// What the device is built on: device mapper and md targets with
+// their slaves, nbd servers and loop files.
+fn backing(name: &str) -> Vec<String> {
+    let mut vec = Vec::new();
+    let attr = |a: &str| sysfs::read_attr(name, a);
+
+    if let Some(dm_name) = attr("dm/name") {
+        let uuid = attr("dm/uuid").unwrap_or_default();
+        vec.push(format!("dm {} {}", dm_name, uuid));
+    }
+    if let Some(level) = attr("md/level") {
+        let disks = attr("md/raid_disks").unwrap_or_default();
+        vec.push(format!("md {} with {} disks", level, disks));
+    }
+    if let Some(pid) = attr("pid") {
+        let backend = attr("backend").unwrap_or_default();
+        vec.push(format!("nbd pid {} {}", pid, backend));
+    }
+    if let Some(file) = attr("loop/backing_file") {
+        vec.push(format!("loop {}", file));
+    }
+
+    if let Ok(slaves) = fs::read_dir(sysfs::class_dir(name).join("slaves")) {
+        for slave in slaves.filter_map(|e| e.ok()) {
+            vec.push(format!("slave {}", slave.file_name().to_string_lossy()));
+        }
+    }
+
+    vec
+}
+
+// The virtio device the disk hangs off, for virtio-blk and
+// virtio-scsi disks, e.g. "virtio2 vendor 0x1af4 device 0x0002".
+fn virtio(name: &str) -> Option<String> {
+    let dev = fs::canonicalize(sysfs::class_dir(name).join("device"))
+        .or_else(|_| fs::canonicalize(sysfs::class_dir(name).join("../device")))
+        .ok()?;
+
+    let virtio = dev.ancestors().find_map(|p| {
+        let n = p.file_name()?.to_string_lossy().to_string();
+        let id = n.strip_prefix("virtio")?;
+        if !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) {
+            Some(p.to_path_buf())
+        } else {
+            None
+        }
+    })?;
+
+    let read = |a: &str| {
+        fs::read_to_string(virtio.join(a))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    };
+    let n = virtio.file_name()?.to_string_lossy().to_string();
+    let (vendor, device) =
+        virtio_ids(&read("modalias")).unwrap_or_else(|| (read("vendor"), read("device")));
+
+    Some(format!("{} vendor {} device {}", n, vendor, device))
+}
+
+// "virtio:d00000002v00001AF4" -> ("0x1af4", "0x0002")
+fn virtio_ids(modalias: &str) -> Option<(String, String)> {
+    let (device, vendor) = modalias.strip_prefix("virtio:d")?.split_once('v')?;
+    let device = u32::from_str_radix(device, 16).ok()?;
+    let vendor = u32::from_str_radix(vendor, 16).ok()?;
+
+    Some((format!("{:#06x}", vendor), format!("{:#06x}", device)))
+}
+
+// "E:KEY=VALUE" lines of the udev database entry for block device
+// "major:minor".
+fn udev_properties(dev: &str) -> BTreeMap<String, String> {
+    let path = Path::new("/run/udev/data").join(format!("b{}", dev));
+    let data = fs::read_to_string(path).unwrap_or_default();
+
+    parse_udev(&data)
+}
+
+// Only the properties, not the symlinks ("S:") and other records.
+fn parse_udev(data: &str) -> BTreeMap<String, String> {
+    data.lines()
+        .filter_map(|l| l.strip_prefix("E:"))
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn active_scheduler_is_bracketed() {
+        assert_eq!(active_scheduler("noop [mq-deadline] kyber"), "mq-deadline");
+        assert_eq!(active_scheduler("[none] mq-deadline"), "none");
+        // Devices without a choice just name the one they have.
+        assert_eq!(active_scheduler("none"), "none");
+    }
+
+    #[test]
+    fn udev_properties_only() {
+        let data = "S:disk/by-id/virtio-data0\n\
+                    S:disk/by-path/pci-0000:00:05.0\n\
+                    I:4012345\n\
+                    E:ID_SERIAL=data0\n\
+                    E:ID_FS_TYPE=\n\
+                    E:ID_PATH=pci-0000:00:05.0\n\
+                    G:systemd\n";
+
+        let props = parse_udev(data);
+        assert_eq!(props.len(), 3);
+        assert_eq!(props["ID_SERIAL"], "data0");
+        assert_eq!(props["ID_FS_TYPE"], "");
+        assert_eq!(props["ID_PATH"], "pci-0000:00:05.0");
+    }
+
+    #[test]
+    fn virtio_modalias() {
+        let ids = virtio_ids("virtio:d00000002v00001AF4");
+        assert_eq!(ids, Some(("0x1af4".to_string(), "0x0002".to_string())));
+        let ids = virtio_ids("virtio:d00000008v00001af4");
+        assert_eq!(ids, Some(("0x1af4".to_string(), "0x0008".to_string())));
+
+        assert_eq!(virtio_ids("pci:v00001AF4d00001001"), None);
+        assert_eq!(virtio_ids("virtio:d0000000xv00001AF4"), None);
+        assert_eq!(virtio_ids(""), None);
+    }
+}
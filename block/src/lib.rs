@@ -1,4 +1,27 @@
+pub mod aligned;
+pub mod backend;
 pub mod device;
+pub mod error;
+pub mod extent;
+pub mod fault;
+pub mod group;
+pub mod guard;
+pub mod hotplug;
+pub mod image;
+pub mod info;
+pub mod lock;
+pub mod memory;
+pub mod nbd;
+pub mod partition;
+pub mod qcow2;
+pub mod raw;
+pub mod slice;
+pub mod stats;
+pub mod sysfs;
+#[cfg(any(test, feature = "test-util"))]
+pub mod temp;
+pub mod throttle;
+pub mod uring;
 
 pub fn add_one(x: i32) -> i32 {
     x + 1
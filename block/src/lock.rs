@@ -0,0 +1,135 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::error::BlockError;
+
+// Shared by every user on most distributions. There is deliberately
+// no fallback: the temp directory can differ between runs (TMPDIR,
+// PrivateTmp), and two runs locking in different places don't
+// exclude each other.
+pub const LOCK_DIR: &str = "/run/lock";
+
+// An advisory lock shared by every virt-tools process touching the
+// same device, released when dropped. The lock file holds the PID of
+// the owner so that a second run can say who is in the way.
+#[derive(Debug)]
+pub struct DeviceLock {
+    key: String,
+    lock_path: PathBuf,
+    // Keeps the flock until dropped.
+    _file: File,
+}
+
+impl DeviceLock {
+    pub fn acquire(path: &str) -> Result<Self, BlockError> {
+        let meta = fs::metadata(path).map_err(|e| BlockError::open(path, e))?;
+        let key = lock_key(&meta);
+        let name = format!("virt-tools-{}.lock", key);
+
+        let (lock_path, mut file) =
+            open_lock(Path::new(LOCK_DIR).join(&name)).map_err(|e| BlockError::LockDir {
+                path: path.to_string(),
+                dir: LOCK_DIR.to_string(),
+                source: e,
+            })?;
+        let lock_name = lock_path.to_string_lossy().to_string();
+
+        let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if ret < 0 {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+
+            return Err(BlockError::Locked {
+                path: path.to_string(),
+                lock: lock_name,
+                pid: pid.trim().parse().unwrap_or(0),
+            });
+        }
+
+        let _ = file.set_len(0);
+        let _ = file.seek(SeekFrom::Start(0));
+        let _ = write!(file, "{}", std::process::id());
+
+        Ok(DeviceLock {
+            key,
+            lock_path,
+            _file: file,
+        })
+    }
+
+    pub fn get_lock_path(&self) -> &Path {
+        &self.lock_path
+    }
+
+    // Whether this lock is the one `path` would take, e.g. for a disk
+    // that came back under the same major:minor.
+    pub fn covers(&self, path: &str) -> bool {
+        fs::metadata(path).is_ok_and(|meta| lock_key(&meta) == self.key)
+    }
+}
+
+fn open_lock(lock_path: PathBuf) -> std::io::Result<(PathBuf, File)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)?;
+
+    Ok((lock_path, file))
+}
+
+// major:minor for devices, device and inode for image files.
+fn lock_key(meta: &fs::Metadata) -> String {
+    if meta.file_type().is_block_device() {
+        let rdev = meta.rdev();
+        format!("{}:{}", libc::major(rdev), libc::minor(rdev))
+    } else {
+        let dev = meta.dev();
+        format!("{}:{}-{}", libc::major(dev), libc::minor(dev), meta.ino())
+    }
+}
+
+// PIDs of the processes with `path` open, found by walking
+// /proc/*/fd. Processes we may not inspect are skipped.
+pub fn holder_pids(path: &str) -> Vec<u32> {
+    let mut vec = Vec::new();
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(_) => return vec,
+    };
+
+    let procs = match fs::read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(_) => return vec,
+    };
+
+    for entry in procs.filter_map(|e| e.ok()) {
+        let pid = match entry.file_name().to_string_lossy().parse::<u32>() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        if pid == std::process::id() {
+            continue;
+        }
+
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        let found = fds.filter_map(|e| e.ok()).any(|fd| {
+            fs::read_link(fd.path())
+                .map(|l| l == target)
+                .unwrap_or(false)
+        });
+        if found {
+            vec.push(pid);
+        }
+    }
+
+    vec
+}
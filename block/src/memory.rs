@@ -0,0 +1,114 @@
+use std::fmt;
+use std::io;
+use std::sync::RwLock;
+
+use crate::backend::Backend;
+use crate::image::clamp_len;
+use crate::info::DeviceInfo;
+
+// A disk held entirely in memory, for tests and CI runs that have
+// neither root nor a spare block device.
+pub struct MemoryDisk {
+    name: String,
+    data: RwLock<Vec<u8>>,
+    alignment: u64,
+}
+
+impl MemoryDisk {
+    pub fn new(size: u64) -> Self {
+        MemoryDisk {
+            name: format!("memory:{}", size),
+            data: RwLock::new(vec![0; size as usize]),
+            alignment: 1,
+        }
+    }
+
+    // Refuse direct I/O that is not aligned to `alignment` with
+    // EINVAL, as `O_DIRECT` does.
+    pub fn with_alignment(mut self, alignment: u64) -> Self {
+        self.alignment = alignment;
+
+        self
+    }
+
+    fn check_aligned(&self, len: usize, offset: u64) -> io::Result<()> {
+        if !offset.is_multiple_of(self.alignment) || !(len as u64).is_multiple_of(self.alignment) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for MemoryDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryDisk")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl Backend for MemoryDisk {
+    fn path(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn size(&self) -> u64 {
+        self.data.read().unwrap().len() as u64
+    }
+
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo::new(self, "memory")
+    }
+
+    fn logical_block_size(&self) -> u64 {
+        self.alignment.max(512)
+    }
+
+    fn alignment(&self) -> u64 {
+        self.alignment
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = self.data.read().unwrap();
+        let len = clamp_len(buf.len(), offset, data.len() as u64);
+        let start = offset as usize;
+
+        buf[..len].copy_from_slice(&data[start..(start + len)]);
+        Ok(len)
+    }
+
+    fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.check_aligned(buf.len(), offset)?;
+
+        self.read_at(buf, offset)
+    }
+
+    fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.check_aligned(buf.len(), offset)?;
+
+        let mut data = self.data.write().unwrap();
+        let len = clamp_len(buf.len(), offset, data.len() as u64);
+        let start = offset as usize;
+
+        data[start..(start + len)].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.write_zeroes(offset, len)
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        true
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> io::Result<()> {
+        let mut data = self.data.write().unwrap();
+        let len = clamp_len(len as usize, offset, data.len() as u64);
+        let start = offset as usize;
+
+        data[start..(start + len)].fill(0);
+        Ok(())
+    }
+}
@@ -0,0 +1,1092 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::ops::Range;
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+
+use crate::backend::{self, Backend};
+use crate::error::BlockError;
+use crate::image::clamp_len;
+use crate::info::DeviceInfo;
+
+// Constants from the NBD protocol specification,
+// https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md
+const NBD_PORT: u16 = 10809;
+
+const NBDMAGIC: u64 = 0x4e42444d41474943;
+const IHAVEOPT: u64 = 0x49484156454f5054;
+const OPT_REPLY_MAGIC: u64 = 0x0003e889045565a9;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_GO: u32 = 7;
+const OPT_STRUCTURED_REPLY: u32 = 8;
+
+const REP_ACK: u32 = 1;
+const REP_INFO: u32 = 3;
+const REP_FLAG_ERROR: u32 = 1 << 31;
+const REP_ERR_UNSUP: u32 = REP_FLAG_ERROR | 1;
+const REP_ERR_UNKNOWN: u32 = REP_FLAG_ERROR | 6;
+
+const INFO_EXPORT: u16 = 0;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+const FLAG_READ_ONLY: u16 = 1 << 1;
+const FLAG_SEND_FLUSH: u16 = 1 << 2;
+const FLAG_SEND_TRIM: u16 = 1 << 5;
+const FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const REQUEST_MAGIC: u32 = 0x25609513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+const STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+const CMD_WRITE_ZEROES: u16 = 6;
+
+const REPLY_FLAG_DONE: u16 = 1 << 0;
+const REPLY_TYPE_NONE: u16 = 0;
+const REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const REPLY_TYPE_ERROR: u16 = 1 << 15;
+
+// What servers must accept when they don't say otherwise.
+const DEFAULT_MAX_REQUEST: u32 = 32 * 1024 * 1024;
+
+// qemu-nbd serves at most 16 requests of a client at a time.
+const QUEUE_DEPTH: usize = 16;
+
+// Option replies are small; anything bigger is a broken server.
+const MAX_OPTION_REPLY: u32 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NbdAddr {
+    Tcp(String),
+    Unix(String),
+}
+
+// Whether `path` names an NBD export rather than a file.
+pub fn is_uri(path: &str) -> bool {
+    path.starts_with("nbd://") || path.starts_with("nbd+unix://")
+}
+
+// Parse the URIs qemu uses for exports, `nbd://host[:port][/export]`
+// and `nbd+unix:///[export]?socket=path`.
+pub fn parse_uri(uri: &str) -> Option<(NbdAddr, String)> {
+    if let Some(rest) = uri.strip_prefix("nbd+unix://") {
+        let (export, query) = rest.split_once('?')?;
+        let socket = query.split('&').find_map(|kv| kv.strip_prefix("socket="))?;
+
+        return Some((
+            NbdAddr::Unix(socket.to_string()),
+            export.trim_start_matches('/').to_string(),
+        ));
+    }
+
+    let rest = uri.strip_prefix("nbd://")?;
+    let (host, export) = rest.split_once('/').unwrap_or((rest, ""));
+    if host.is_empty() {
+        return None;
+    }
+
+    // A bare IPv6 address is in brackets, so a port follows "]:".
+    let has_port = match host.rfind(']') {
+        Some(i) => host[i..].contains(':'),
+        None => host.contains(':'),
+    };
+    let addr = if has_port {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, NBD_PORT)
+    };
+
+    Some((NbdAddr::Tcp(addr), export.to_string()))
+}
+
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+impl Stream {
+    fn shutdown(&self) {
+        let _ = match self {
+            Stream::Tcp(s) => s.shutdown(Shutdown::Both),
+            Stream::Unix(s) => s.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("NBD: {}", msg))
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut b = [0; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_be_bytes(b))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_be_bytes(b))
+}
+
+fn skip<R: Read>(r: &mut R, len: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut r.take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+
+    Ok(())
+}
+
+// NBD error numbers are the Linux ones for the few that are defined.
+fn nbd_error(error: u32) -> io::Error {
+    let errno = match error as i32 {
+        libc::EPERM | libc::EIO | libc::ENOMEM | libc::EINVAL | libc::ENOSPC => error as i32,
+        libc::EOVERFLOW | libc::ENOTSUP | libc::ESHUTDOWN => error as i32,
+        _ => libc::EIO,
+    };
+
+    io::Error::from_raw_os_error(errno)
+}
+
+// One request of a batch. Reads land in `buf`, writes send `data`.
+struct Request<'a> {
+    cmd: u16,
+    offset: u64,
+    len: u32,
+    data: &'a [u8],
+    buf: Option<&'a mut [u8]>,
+}
+
+struct Pending<'a> {
+    cookie: u64,
+    offset: u64,
+    buf: Option<&'a mut [u8]>,
+    // Parts of `buf` the chunks of a structured reply filled in.
+    covered: Vec<Range<usize>>,
+    error: Option<io::Error>,
+    done: bool,
+}
+
+// What the server told us about the export.
+#[derive(Debug)]
+struct Negotiated {
+    size: u64,
+    flags: u16,
+    structured: bool,
+    min_block: u32,
+    preferred_block: u32,
+    max_request: u32,
+}
+
+#[derive(Debug)]
+struct Conn {
+    stream: Stream,
+    cookie: u64,
+    // Set when the stream is out of step with the server, after which
+    // nothing more can be sent.
+    broken: bool,
+}
+
+impl Conn {
+    // Fixed newstyle negotiation, asking for structured replies and
+    // block size constraints. Servers that don't know `NBD_OPT_GO` get
+    // `NBD_OPT_EXPORT_NAME` instead.
+    fn handshake(&mut self, export: &str) -> io::Result<Negotiated> {
+        let s = &mut self.stream;
+        if read_u64(s)? != NBDMAGIC || read_u64(s)? != IHAVEOPT {
+            return Err(protocol_error("not a newstyle server"));
+        }
+
+        let server_flags = read_u16(s)?;
+        if server_flags & FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(protocol_error("server lacks fixed newstyle negotiation"));
+        }
+        let no_zeroes = server_flags & FLAG_NO_ZEROES != 0;
+        let client_flags = (FLAG_FIXED_NEWSTYLE | (server_flags & FLAG_NO_ZEROES)) as u32;
+        s.write_all(&client_flags.to_be_bytes())?;
+
+        self.send_option(OPT_STRUCTURED_REPLY, &[])?;
+        let (reply, _) = self.read_option_reply(OPT_STRUCTURED_REPLY)?;
+        let structured = reply == REP_ACK;
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&(export.len() as u32).to_be_bytes());
+        data.extend_from_slice(export.as_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&INFO_BLOCK_SIZE.to_be_bytes());
+        self.send_option(OPT_GO, &data)?;
+
+        let mut export_info = None;
+        let mut block_size = (1, 4096, DEFAULT_MAX_REQUEST);
+        loop {
+            let (reply, data) = self.read_option_reply(OPT_GO)?;
+            match reply {
+                REP_ACK => break,
+                REP_INFO if data.len() >= 2 => {
+                    let field = |i: usize, n: usize| data.get(i..(i + n));
+                    let be32 = |i| field(i, 4).map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+
+                    match u16::from_be_bytes([data[0], data[1]]) {
+                        INFO_EXPORT if data.len() >= 12 => {
+                            let size = u64::from_be_bytes(data[2..10].try_into().unwrap());
+                            let flags = u16::from_be_bytes([data[10], data[11]]);
+                            export_info = Some((size, flags));
+                        }
+                        INFO_BLOCK_SIZE if data.len() >= 14 => {
+                            block_size = (be32(2).unwrap(), be32(6).unwrap(), be32(10).unwrap());
+                        }
+                        _ => {}
+                    }
+                }
+                REP_INFO => {}
+                REP_ERR_UNSUP => {
+                    self.send_option(OPT_EXPORT_NAME, export.as_bytes())?;
+                    let s = &mut self.stream;
+                    let size = read_u64(s)?;
+                    let flags = read_u16(s)?;
+                    if !no_zeroes {
+                        skip(s, 124)?;
+                    }
+                    export_info = Some((size, flags));
+                    break;
+                }
+                REP_ERR_UNKNOWN => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no NBD export named {:?}", export),
+                    ));
+                }
+                _ => {
+                    return Err(io::Error::other(format!(
+                        "NBD export {:?} refused ({:#x}): {}",
+                        export,
+                        reply,
+                        String::from_utf8_lossy(&data)
+                    )));
+                }
+            }
+        }
+
+        let (size, flags) = export_info.ok_or_else(|| protocol_error("no export size"))?;
+        let (min_block, preferred_block, max_request) = block_size;
+        let min_block = min_block.max(1);
+
+        Ok(Negotiated {
+            size,
+            flags,
+            structured,
+            min_block,
+            preferred_block: preferred_block.max(min_block),
+            max_request: max_request.clamp(min_block, DEFAULT_MAX_REQUEST),
+        })
+    }
+
+    fn send_option(&mut self, option: u32, data: &[u8]) -> io::Result<()> {
+        let mut msg = Vec::with_capacity(16 + data.len());
+        msg.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        msg.extend_from_slice(&option.to_be_bytes());
+        msg.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        msg.extend_from_slice(data);
+
+        self.stream.write_all(&msg)
+    }
+
+    fn read_option_reply(&mut self, option: u32) -> io::Result<(u32, Vec<u8>)> {
+        let s = &mut self.stream;
+
+        if read_u64(s)? != OPT_REPLY_MAGIC || read_u32(s)? != option {
+            return Err(protocol_error("bad option reply"));
+        }
+        let reply = read_u32(s)?;
+        let len = read_u32(s)?;
+        if len > MAX_OPTION_REPLY {
+            return Err(protocol_error("option reply too long"));
+        }
+
+        let mut data = vec![0; len as usize];
+        s.read_exact(&mut data)?;
+
+        Ok((reply, data))
+    }
+
+    fn send(&mut self, req: &Request) -> io::Result<u64> {
+        self.cookie += 1;
+
+        let mut msg = Vec::with_capacity(28);
+        msg.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&0u16.to_be_bytes());
+        msg.extend_from_slice(&req.cmd.to_be_bytes());
+        msg.extend_from_slice(&self.cookie.to_be_bytes());
+        msg.extend_from_slice(&req.offset.to_be_bytes());
+        msg.extend_from_slice(&req.len.to_be_bytes());
+
+        self.stream.write_all(&msg)?;
+        if !req.data.is_empty() {
+            self.stream.write_all(req.data)?;
+        }
+
+        Ok(self.cookie)
+    }
+
+    // Where a chunk of `len` bytes at `offset` goes in the buffer of a
+    // read of `p`. Each byte may only be sent once.
+    fn chunk(p: &mut Pending, offset: u64, len: usize) -> io::Result<(usize, usize)> {
+        let buf_len = p.buf.as_ref().map(|b| b.len()).unwrap_or(0) as u64;
+        if p.buf.is_none() || offset < p.offset || offset + len as u64 > p.offset + buf_len {
+            return Err(protocol_error("reply chunk outside of the request"));
+        }
+
+        let start = (offset - p.offset) as usize;
+        let end = start + len;
+        if p.covered.iter().any(|r| r.start < end && start < r.end) {
+            return Err(protocol_error("overlapping reply chunks"));
+        }
+        p.covered.push(start..end);
+
+        Ok((start, end))
+    }
+
+    fn recv(&mut self, pending: &mut [Pending]) -> io::Result<()> {
+        let s = &mut self.stream;
+
+        match read_u32(s)? {
+            SIMPLE_REPLY_MAGIC => {}
+            STRUCTURED_REPLY_MAGIC => return Conn::recv_chunk(s, pending),
+            _ => return Err(protocol_error("bad reply magic")),
+        }
+
+        // Simple replies carry the data of a successful read.
+        let error = read_u32(s)?;
+        let cookie = read_u64(s)?;
+        let p = find(pending, cookie)?;
+
+        if error != 0 {
+            p.error = Some(nbd_error(error));
+        } else if let Some(buf) = p.buf.as_deref_mut() {
+            s.read_exact(buf)?;
+        }
+        p.done = true;
+
+        Ok(())
+    }
+
+    // One chunk of a structured reply, after the magic.
+    fn recv_chunk(s: &mut Stream, pending: &mut [Pending]) -> io::Result<()> {
+        let flags = read_u16(s)?;
+        let reply_type = read_u16(s)?;
+        let cookie = read_u64(s)?;
+        let len = read_u32(s)?;
+
+        let p = find(pending, cookie)?;
+        match reply_type {
+            REPLY_TYPE_OFFSET_DATA if len >= 8 => {
+                let offset = read_u64(s)?;
+                let (start, end) = Conn::chunk(p, offset, len as usize - 8)?;
+                s.read_exact(&mut p.buf.as_deref_mut().unwrap()[start..end])?;
+            }
+            REPLY_TYPE_OFFSET_HOLE if len == 12 => {
+                let offset = read_u64(s)?;
+                let size = read_u32(s)?;
+                let (start, end) = Conn::chunk(p, offset, size as usize)?;
+                p.buf.as_deref_mut().unwrap()[start..end].fill(0);
+            }
+            t if t & REPLY_TYPE_ERROR != 0 && len >= 6 => {
+                let error = read_u32(s)?;
+                skip(s, len as u64 - 4)?;
+                if p.error.is_none() {
+                    p.error = Some(nbd_error(error));
+                }
+            }
+            REPLY_TYPE_OFFSET_DATA | REPLY_TYPE_OFFSET_HOLE => {
+                return Err(protocol_error("bad reply chunk"));
+            }
+            REPLY_TYPE_NONE if len == 0 => {}
+            // Types we didn't ask for may be ignored.
+            _ => skip(s, len as u64)?,
+        }
+
+        if flags & REPLY_FLAG_DONE != 0 {
+            // Whatever the chunks didn't cover would be left as the
+            // buffer was, e.g. holding the data of an earlier read.
+            let len = p.buf.as_ref().map(|b| b.len()).unwrap_or(0);
+            let covered: usize = p.covered.iter().map(|r| r.len()).sum();
+            if p.error.is_none() && covered < len {
+                p.error = Some(protocol_error("read reply short of the request"));
+            }
+            p.done = true;
+        }
+
+        Ok(())
+    }
+
+    // Send all of `reqs` before collecting the replies, which may come
+    // back in any order.
+    fn transact(&mut self, reqs: Vec<Request>) -> io::Result<Vec<io::Result<()>>> {
+        if self.broken {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        }
+
+        let mut pending = Vec::with_capacity(reqs.len());
+        for req in reqs {
+            let cookie = self.send(&req)?;
+            pending.push(Pending {
+                cookie,
+                offset: req.offset,
+                buf: req.buf,
+                covered: Vec::new(),
+                error: None,
+                done: false,
+            });
+        }
+
+        while pending.iter().any(|p| !p.done) {
+            self.recv(&mut pending)?;
+        }
+
+        Ok(pending
+            .into_iter()
+            .map(|p| match p.error {
+                Some(e) => Err(e),
+                None => Ok(()),
+            })
+            .collect())
+    }
+}
+
+fn find<'a, 'b>(pending: &'a mut [Pending<'b>], cookie: u64) -> io::Result<&'a mut Pending<'b>> {
+    pending
+        .iter_mut()
+        .find(|p| p.cookie == cookie && !p.done)
+        .ok_or_else(|| protocol_error("reply to a request we didn't send"))
+}
+
+// An export of an NBD server such as qemu-nbd or
+// qemu-storage-daemon, spoken to over a socket without the nbd kernel
+// module. Requests of a batch are pipelined.
+#[derive(Debug)]
+pub struct NbdDevice {
+    uri: String,
+    export: String,
+    neg: Negotiated,
+    conn: Mutex<Conn>,
+}
+
+impl NbdDevice {
+    pub fn connect(uri: &str) -> Result<Self, BlockError> {
+        let (addr, export) = parse_uri(uri)
+            .ok_or_else(|| BlockError::Unsupported(format!("{}: not an NBD URI", uri)))?;
+
+        let stream = match addr {
+            NbdAddr::Tcp(addr) => TcpStream::connect(addr).map(|s| {
+                let _ = s.set_nodelay(true);
+                Stream::Tcp(s)
+            }),
+            NbdAddr::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+        .map_err(|e| BlockError::open(uri, e))?;
+
+        let mut conn = Conn {
+            stream,
+            cookie: 0,
+            broken: false,
+        };
+        let neg = conn
+            .handshake(&export)
+            .map_err(|e| BlockError::open(uri, e))?;
+
+        Ok(NbdDevice {
+            uri: uri.to_string(),
+            export,
+            neg,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.neg.flags & FLAG_READ_ONLY != 0
+    }
+
+    pub fn has_structured_replies(&self) -> bool {
+        self.neg.structured
+    }
+
+    // Run `reqs` at most `QUEUE_DEPTH` at a time. After an error on
+    // the connection itself every request fails.
+    fn submit(&self, reqs: Vec<Request>) -> Vec<io::Result<()>> {
+        let mut conn = self.conn.lock().unwrap();
+        let nr = reqs.len();
+        let mut results = Vec::with_capacity(nr);
+
+        let mut reqs = reqs.into_iter().peekable();
+        while reqs.peek().is_some() {
+            let window: Vec<Request> = reqs.by_ref().take(QUEUE_DEPTH).collect();
+            match conn.transact(window) {
+                Ok(ret) => results.extend(ret),
+                Err(e) => {
+                    conn.broken = true;
+                    while results.len() < nr {
+                        results.push(Err(io::Error::new(e.kind(), e.to_string())));
+                    }
+                }
+            }
+        }
+
+        results
+    }
+
+    // Split `len` bytes at `offset` into requests no larger than the
+    // server accepts.
+    fn split(&self, offset: u64, len: u64) -> Vec<(u64, u32)> {
+        let max = self.neg.max_request as u64;
+
+        (0..len.div_ceil(max))
+            .map(|i| {
+                let start = i * max;
+                (offset + start, std::cmp::min(max, len - start) as u32)
+            })
+            .collect()
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.is_read_only() {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+
+        Ok(())
+    }
+
+    // Requests without payload covering `len` bytes at `offset`.
+    fn command(&self, cmd: u16, offset: u64, len: u64) -> io::Result<()> {
+        let reqs = self
+            .split(offset, len)
+            .into_iter()
+            .map(|(offset, len)| Request {
+                cmd,
+                offset,
+                len,
+                data: &[],
+                buf: None,
+            })
+            .collect();
+
+        self.submit(reqs).into_iter().collect()
+    }
+}
+
+// Collect the results of requests split by `owners`, the index of
+// the caller's request each came from, into one per caller's request.
+fn gather(
+    nr: usize,
+    lens: &[usize],
+    owners: &[usize],
+    results: Vec<io::Result<()>>,
+) -> Vec<io::Result<usize>> {
+    let mut ret: Vec<io::Result<usize>> = lens.iter().map(|len| Ok(*len)).collect();
+
+    for (owner, r) in owners.iter().zip(results) {
+        if let Err(e) = r {
+            if ret[*owner].is_ok() {
+                ret[*owner] = Err(e);
+            }
+        }
+    }
+
+    debug_assert_eq!(ret.len(), nr);
+    ret
+}
+
+impl Backend for NbdDevice {
+    fn path(&self) -> &str {
+        self.uri.as_str()
+    }
+
+    fn size(&self) -> u64 {
+        self.neg.size
+    }
+
+    fn info(&self) -> DeviceInfo {
+        let mut info = DeviceInfo::new(self, "nbd export");
+        info.name = Some(self.export.clone());
+
+        info
+    }
+
+    fn logical_block_size(&self) -> u64 {
+        (self.neg.min_block as u64).max(512)
+    }
+
+    fn physical_block_size(&self) -> u64 {
+        (self.neg.preferred_block as u64).max(self.logical_block_size())
+    }
+
+    fn alignment(&self) -> u64 {
+        self.neg.min_block as u64
+    }
+
+    fn queue_depth(&self) -> usize {
+        QUEUE_DEPTH
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.read_batch(&mut [(offset, buf)]).pop().unwrap()
+    }
+
+    fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.write_batch(&[(offset, buf)]).pop().unwrap()
+    }
+
+    fn read_batch(&self, reqs: &mut [(u64, &mut [u8])]) -> Vec<io::Result<usize>> {
+        let mut lens = Vec::with_capacity(reqs.len());
+        let mut owners = Vec::new();
+        let mut split = Vec::new();
+
+        for (i, (offset, buf)) in reqs.iter_mut().enumerate() {
+            let len = clamp_len(buf.len(), *offset, self.neg.size);
+            lens.push(len);
+
+            let max = self.neg.max_request as usize;
+            for (j, chunk) in buf[..len].chunks_mut(max).enumerate() {
+                owners.push(i);
+                split.push(Request {
+                    cmd: CMD_READ,
+                    offset: *offset + (j * max) as u64,
+                    len: chunk.len() as u32,
+                    data: &[],
+                    buf: Some(chunk),
+                });
+            }
+        }
+
+        let results = self.submit(split);
+        gather(reqs.len(), &lens, &owners, results)
+    }
+
+    fn write_batch(&self, reqs: &[(u64, &[u8])]) -> Vec<io::Result<usize>> {
+        if let Err(e) = self.check_writable() {
+            return reqs
+                .iter()
+                .map(|_| Err(io::Error::from(e.kind())))
+                .collect();
+        }
+
+        let mut lens = Vec::with_capacity(reqs.len());
+        let mut owners = Vec::new();
+        let mut split = Vec::new();
+
+        for (i, (offset, buf)) in reqs.iter().enumerate() {
+            let len = clamp_len(buf.len(), *offset, self.neg.size);
+            lens.push(len);
+
+            let max = self.neg.max_request as usize;
+            for (j, chunk) in buf[..len].chunks(max).enumerate() {
+                owners.push(i);
+                split.push(Request {
+                    cmd: CMD_WRITE,
+                    offset: *offset + (j * max) as u64,
+                    len: chunk.len() as u32,
+                    data: chunk,
+                    buf: None,
+                });
+            }
+        }
+
+        let results = self.submit(split);
+        gather(reqs.len(), &lens, &owners, results)
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.check_writable()?;
+        if self.neg.flags & FLAG_SEND_TRIM == 0 {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+
+        self.command(CMD_TRIM, offset, len)
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.check_writable()?;
+        if self.neg.flags & FLAG_SEND_WRITE_ZEROES == 0 {
+            return backend::fill_zeroes(self, offset, len);
+        }
+
+        self.command(CMD_WRITE_ZEROES, offset, len)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if self.neg.flags & FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+
+        self.submit(vec![Request {
+            cmd: CMD_FLUSH,
+            offset: 0,
+            len: 0,
+            data: &[],
+            buf: None,
+        }])
+        .pop()
+        .unwrap()
+    }
+}
+
+// Say goodbye so that the server doesn't log a dropped connection.
+impl Drop for NbdDevice {
+    fn drop(&mut self) {
+        let conn = self.conn.get_mut().unwrap();
+        if conn.broken {
+            return;
+        }
+
+        let _ = conn.send(&Request {
+            cmd: CMD_DISC,
+            offset: 0,
+            len: 0,
+            data: &[],
+            buf: None,
+        });
+        conn.stream.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::BlockDevice;
+    use crate::temp::TempPath;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    const EXPORT_SIZE: usize = 1024 * 1024;
+    // Small enough that the client has to split requests.
+    const SERVER_MAX_REQUEST: u32 = 64 * 1024;
+
+    fn write_reply<S: Write>(s: &mut S, flags: u16, reply_type: u16, cookie: u64, data: &[u8]) {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&STRUCTURED_REPLY_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&reply_type.to_be_bytes());
+        msg.extend_from_slice(&cookie.to_be_bytes());
+        msg.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        msg.extend_from_slice(data);
+        s.write_all(&msg).unwrap();
+    }
+
+    fn write_option_reply<S: Write>(s: &mut S, option: u32, reply: u32, data: &[u8]) {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&OPT_REPLY_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&option.to_be_bytes());
+        msg.extend_from_slice(&reply.to_be_bytes());
+        msg.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        msg.extend_from_slice(data);
+        s.write_all(&msg).unwrap();
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Mode {
+        Simple,
+        Structured,
+        // Structured, leaving out the second half of reads with data.
+        ShortReads,
+    }
+
+    // Just enough of an NBD server for the tests: one export named
+    // "disk" held in memory. Structured reads come back as a hole
+    // where the data is all zeroes, and otherwise as two data chunks,
+    // the second half first.
+    fn serve<S: Read + Write>(mut s: S, data: Arc<Mutex<Vec<u8>>>, mode: Mode) {
+        s.write_all(&NBDMAGIC.to_be_bytes()).unwrap();
+        s.write_all(&IHAVEOPT.to_be_bytes()).unwrap();
+        s.write_all(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes())
+            .unwrap();
+        read_u32(&mut s).unwrap();
+
+        let mut structured = false;
+        loop {
+            assert_eq!(read_u64(&mut s).unwrap(), IHAVEOPT);
+            let option = read_u32(&mut s).unwrap();
+            let mut payload = vec![0; read_u32(&mut s).unwrap() as usize];
+            s.read_exact(&mut payload).unwrap();
+
+            match option {
+                OPT_STRUCTURED_REPLY if mode != Mode::Simple => {
+                    structured = true;
+                    write_option_reply(&mut s, option, REP_ACK, &[]);
+                }
+                OPT_GO => {
+                    let len = u32::from_be_bytes(payload[..4].try_into().unwrap()) as usize;
+                    if &payload[4..(4 + len)] != b"disk" {
+                        write_option_reply(&mut s, option, REP_ERR_UNKNOWN, &[]);
+                        return;
+                    }
+
+                    let flags = 1 | FLAG_SEND_FLUSH | FLAG_SEND_TRIM | FLAG_SEND_WRITE_ZEROES;
+                    let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&(EXPORT_SIZE as u64).to_be_bytes());
+                    info.extend_from_slice(&flags.to_be_bytes());
+                    write_option_reply(&mut s, option, REP_INFO, &info);
+
+                    let mut info = INFO_BLOCK_SIZE.to_be_bytes().to_vec();
+                    for v in [1, 4096, SERVER_MAX_REQUEST] {
+                        info.extend_from_slice(&v.to_be_bytes());
+                    }
+                    write_option_reply(&mut s, option, REP_INFO, &info);
+
+                    write_option_reply(&mut s, option, REP_ACK, &[]);
+                    break;
+                }
+                _ => write_option_reply(&mut s, option, REP_ERR_UNSUP, &[]),
+            }
+        }
+
+        loop {
+            assert_eq!(read_u32(&mut s).unwrap(), REQUEST_MAGIC);
+            read_u16(&mut s).unwrap();
+            let cmd = read_u16(&mut s).unwrap();
+            let cookie = read_u64(&mut s).unwrap();
+            let offset = read_u64(&mut s).unwrap() as usize;
+            let len = read_u32(&mut s).unwrap() as usize;
+
+            let mut payload = vec![0; if cmd == CMD_WRITE { len } else { 0 }];
+            s.read_exact(&mut payload).unwrap();
+
+            if cmd == CMD_DISC {
+                return;
+            }
+
+            let mut data = data.lock().unwrap();
+            if offset + len > data.len() || len > SERVER_MAX_REQUEST as usize {
+                if structured {
+                    let mut err = (libc::EINVAL as u32).to_be_bytes().to_vec();
+                    err.extend_from_slice(&0u16.to_be_bytes());
+                    write_reply(&mut s, REPLY_FLAG_DONE, REPLY_TYPE_ERROR | 1, cookie, &err);
+                } else {
+                    let mut msg = SIMPLE_REPLY_MAGIC.to_be_bytes().to_vec();
+                    msg.extend_from_slice(&(libc::EINVAL as u32).to_be_bytes());
+                    msg.extend_from_slice(&cookie.to_be_bytes());
+                    s.write_all(&msg).unwrap();
+                }
+                continue;
+            }
+
+            let range = offset..(offset + len);
+            match cmd {
+                CMD_WRITE => data[range.clone()].copy_from_slice(&payload),
+                CMD_TRIM | CMD_WRITE_ZEROES => data[range.clone()].fill(0),
+                _ => {}
+            }
+
+            if !structured {
+                let mut msg = SIMPLE_REPLY_MAGIC.to_be_bytes().to_vec();
+                msg.extend_from_slice(&0u32.to_be_bytes());
+                msg.extend_from_slice(&cookie.to_be_bytes());
+                if cmd == CMD_READ {
+                    msg.extend_from_slice(&data[range]);
+                }
+                s.write_all(&msg).unwrap();
+                continue;
+            }
+
+            if cmd != CMD_READ {
+                write_reply(&mut s, REPLY_FLAG_DONE, REPLY_TYPE_NONE, cookie, &[]);
+            } else if data[range.clone()].iter().all(|b| *b == 0) {
+                let mut hole = (offset as u64).to_be_bytes().to_vec();
+                hole.extend_from_slice(&(len as u32).to_be_bytes());
+                write_reply(
+                    &mut s,
+                    REPLY_FLAG_DONE,
+                    REPLY_TYPE_OFFSET_HOLE,
+                    cookie,
+                    &hole,
+                );
+            } else {
+                let mid = offset + len / 2;
+                for (start, end, flags) in [(mid, offset + len, 0), (offset, mid, REPLY_FLAG_DONE)]
+                {
+                    if mode == Mode::ShortReads && flags == 0 {
+                        continue;
+                    }
+                    let mut chunk = (start as u64).to_be_bytes().to_vec();
+                    chunk.extend_from_slice(&data[start..end]);
+                    write_reply(&mut s, flags, REPLY_TYPE_OFFSET_DATA, cookie, &chunk);
+                }
+            }
+        }
+    }
+
+    fn round_trip(blk: &BlockDevice) {
+        let pattern: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+        blk.write_direct_at(&pattern, 4096).unwrap();
+        blk.flush().unwrap();
+
+        let mut buf = vec![0xff; pattern.len()];
+        blk.read_direct_at(&mut buf, 4096).unwrap();
+        assert_eq!(buf, pattern);
+
+        let mut a = vec![0xff; 4096];
+        let mut b = vec![0xff; 4096];
+        let mut reqs: Vec<(u64, &mut [u8])> = vec![(0, &mut a), (8192, &mut b)];
+        for r in blk.read_batch(&mut reqs) {
+            r.unwrap();
+        }
+        assert!(a.iter().all(|v| *v == 0));
+        assert_eq!(&b[..], &pattern[4096..8192]);
+
+        blk.discard(4096, 4096).unwrap();
+        blk.write_zeroes(8192, 4096).unwrap();
+        blk.read_direct_at(&mut buf[..12288], 4096).unwrap();
+        assert!(buf[..8192].iter().all(|v| *v == 0));
+        assert_eq!(&buf[8192..12288], &pattern[8192..12288]);
+
+        assert!(blk
+            .read_direct_at(&mut buf[..4096], EXPORT_SIZE as u64)
+            .is_err());
+    }
+
+    #[test]
+    fn parse_uris() {
+        assert_eq!(
+            parse_uri("nbd://localhost/disk"),
+            Some((
+                NbdAddr::Tcp("localhost:10809".to_string()),
+                "disk".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_uri("nbd://[::1]:10810"),
+            Some((NbdAddr::Tcp("[::1]:10810".to_string()), "".to_string()))
+        );
+        assert_eq!(
+            parse_uri("nbd+unix:///disk?socket=/run/nbd.sock"),
+            Some((
+                NbdAddr::Unix("/run/nbd.sock".to_string()),
+                "disk".to_string()
+            ))
+        );
+        assert_eq!(parse_uri("nbd+unix:///disk"), None);
+        assert!(!is_uri("/dev/nbd0"));
+    }
+
+    #[test]
+    fn unix_structured() {
+        let sock = TempPath::new("nbd.sock");
+        let listener = UnixListener::bind(&sock).unwrap();
+
+        let data = Arc::new(Mutex::new(vec![0; EXPORT_SIZE]));
+        let server = thread::spawn(move || {
+            let (s, _) = listener.accept().unwrap();
+            serve(s, data, Mode::Structured);
+        });
+
+        let uri = format!("nbd+unix:///disk?socket={}", &*sock);
+        let blk = BlockDevice::new(&uri).unwrap();
+        assert_eq!(blk.get_disk_size(), EXPORT_SIZE as u64);
+        round_trip(&blk);
+
+        drop(blk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn tcp_simple() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let data = Arc::new(Mutex::new(vec![0; EXPORT_SIZE]));
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                let (s, _) = listener.accept().unwrap();
+                serve(s, Arc::clone(&data), Mode::Simple);
+            }
+        });
+
+        match NbdDevice::connect(&format!("nbd://127.0.0.1:{}/other", port)) {
+            Err(BlockError::Open { source, .. }) => {
+                assert_eq!(source.kind(), io::ErrorKind::NotFound)
+            }
+            other => panic!("expected an unknown export, got {:?}", other),
+        }
+
+        let dev = NbdDevice::connect(&format!("nbd://127.0.0.1:{}/disk", port)).unwrap();
+        assert!(!dev.has_structured_replies());
+        round_trip(&BlockDevice::from_backend(Arc::new(dev)));
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn short_structured_reads() {
+        let sock = TempPath::new("nbd-short.sock");
+        let listener = UnixListener::bind(&sock).unwrap();
+
+        let mut data = vec![0; EXPORT_SIZE];
+        data[8192..16384].fill(0x5a);
+        let data = Arc::new(Mutex::new(data));
+        let server = thread::spawn(move || {
+            let (s, _) = listener.accept().unwrap();
+            serve(s, data, Mode::ShortReads);
+        });
+
+        let uri = format!("nbd+unix:///disk?socket={}", &*sock);
+        let blk = BlockDevice::new(&uri).unwrap();
+
+        // The buffer still holds what an earlier read left there.
+        let mut buf = vec![0x5a; 4096];
+        match blk.read_direct_at(&mut buf, 12288) {
+            Err(BlockError::Io { source, .. }) => {
+                assert_eq!(source.kind(), io::ErrorKind::InvalidData)
+            }
+            other => panic!("expected a protocol error, got {:?}", other),
+        }
+
+        // Holes come in one chunk, and the connection is still good.
+        blk.read_direct_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        drop(blk);
+        server.join().unwrap();
+    }
+}
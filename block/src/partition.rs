@@ -0,0 +1,278 @@
+use crate::device::BlockDevice;
+use crate::error::BlockError;
+
+// A partition table entry. `index` follows the kernel's numbering:
+// the GPT slot or MBR primary slot counted from 1, with MBR logical
+// partitions starting at 5. `kind` is the GPT type GUID or the MBR
+// type byte in hex. Like the kernel, an extended partition is listed
+// with only its first 1 KiB, so that it doesn't give a window over the
+// logical partitions and the records chaining them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+    pub index: u32,
+    pub start: u64,
+    pub len: u64,
+    pub kind: String,
+    pub name: String,
+}
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_PROTECTIVE: u8 = 0xee;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const MBR_EXTENDED_LEN: u64 = 1024;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+// Guard against corrupt tables sending us round in circles.
+const MAX_LOGICAL: u32 = 128;
+
+// Partitions listed in the GPT or, failing that, the MBR of `blk`.
+// A disk without either has no partitions.
+pub fn read_partitions(blk: &BlockDevice) -> Result<Vec<Partition>, BlockError> {
+    let lbs = blk.get_logical_block_size();
+
+    let mbr = read_block(blk, 0, 512)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let entries = mbr_entries(&mbr);
+    if entries.iter().any(|e| e.0 == MBR_PROTECTIVE) {
+        return read_gpt(blk, lbs);
+    }
+
+    let mut vec = Vec::new();
+    for (i, (kind, lba, sectors)) in entries.iter().enumerate() {
+        if *kind == 0 || *sectors == 0 {
+            continue;
+        }
+
+        let mut len = sectors * lbs;
+        if MBR_EXTENDED.contains(kind) {
+            vec.extend(read_logical(blk, lbs, *lba)?);
+            len = len.min(MBR_EXTENDED_LEN.max(lbs));
+        }
+
+        vec.push(Partition {
+            index: i as u32 + 1,
+            start: lba * lbs,
+            len,
+            kind: format!("{:02x}", kind),
+            name: String::new(),
+        });
+    }
+    vec.sort_by_key(|p| p.index);
+
+    Ok(vec)
+}
+
+fn read_block(blk: &BlockDevice, offset: u64, len: usize) -> Result<Vec<u8>, BlockError> {
+    let mut buf = vec![0; len];
+    blk.read_at(&mut buf, offset)?;
+
+    Ok(buf)
+}
+
+fn le_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..(pos + 4)].try_into().unwrap())
+}
+
+fn le_u64(buf: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(buf[pos..(pos + 8)].try_into().unwrap())
+}
+
+// (type, first LBA, number of sectors) of the four slots of an MBR or
+// extended boot record.
+fn mbr_entries(buf: &[u8]) -> Vec<(u8, u64, u64)> {
+    (0..4)
+        .map(|i| {
+            let pos = MBR_ENTRIES + 16 * i;
+            (
+                buf[pos + 4],
+                le_u32(buf, pos + 8) as u64,
+                le_u32(buf, pos + 12) as u64,
+            )
+        })
+        .collect()
+}
+
+// Walk the chain of extended boot records starting at `ext_lba`. Data
+// partitions are relative to their EBR, links to the next EBR are
+// relative to the extended partition.
+fn read_logical(blk: &BlockDevice, lbs: u64, ext_lba: u64) -> Result<Vec<Partition>, BlockError> {
+    let mut vec = Vec::new();
+    let mut ebr_lba = ext_lba;
+
+    for index in 5..(5 + MAX_LOGICAL) {
+        let ebr = read_block(blk, ebr_lba * lbs, 512)?;
+        if ebr[510..512] != MBR_SIGNATURE {
+            break;
+        }
+
+        let entries = mbr_entries(&ebr);
+        let (kind, lba, sectors) = entries[0];
+        if kind != 0 && sectors != 0 {
+            vec.push(Partition {
+                index,
+                start: (ebr_lba + lba) * lbs,
+                len: sectors * lbs,
+                kind: format!("{:02x}", kind),
+                name: String::new(),
+            });
+        }
+
+        let (kind, lba, _) = entries[1];
+        if kind == 0 || lba == 0 {
+            break;
+        }
+        ebr_lba = ext_lba + lba;
+    }
+
+    Ok(vec)
+}
+
+fn read_gpt(blk: &BlockDevice, lbs: u64) -> Result<Vec<Partition>, BlockError> {
+    let header = read_block(blk, lbs, 512)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Err(BlockError::Unsupported(format!(
+            "{}: protective MBR without a GPT header",
+            blk.get_path()
+        )));
+    }
+
+    let entries_lba = le_u64(&header, 72);
+    let nr_entries = le_u32(&header, 80) as usize;
+    let entry_size = le_u32(&header, 84) as usize;
+    if entry_size < 128 || nr_entries > 1024 {
+        return Err(BlockError::Unsupported(format!(
+            "{}: bad GPT header, {} entries of {} bytes",
+            blk.get_path(),
+            nr_entries,
+            entry_size
+        )));
+    }
+
+    let table = read_block(blk, entries_lba * lbs, nr_entries * entry_size)?;
+
+    let mut vec = Vec::new();
+    for i in 0..nr_entries {
+        let entry = &table[(i * entry_size)..((i + 1) * entry_size)];
+        if entry[0..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+
+        let first = le_u64(entry, 32);
+        let last = le_u64(entry, 40);
+        if last < first {
+            continue;
+        }
+
+        let name: Vec<u16> = entry[56..128]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+
+        vec.push(Partition {
+            index: i as u32 + 1,
+            start: first * lbs,
+            len: (last - first + 1) * lbs,
+            kind: guid_to_string(&entry[0..16]),
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+
+    Ok(vec)
+}
+
+// GUIDs are stored with the first three groups little endian.
+fn guid_to_string(b: &[u8]) -> String {
+    format!(
+        "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mbr_entry(buf: &mut [u8], slot: usize, kind: u8, lba: u32, sectors: u32) {
+        let pos = MBR_ENTRIES + 16 * slot;
+        buf[pos + 4] = kind;
+        buf[(pos + 8)..(pos + 12)].copy_from_slice(&lba.to_le_bytes());
+        buf[(pos + 12)..(pos + 16)].copy_from_slice(&sectors.to_le_bytes());
+        buf[510..512].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    #[test]
+    fn mbr_with_logical_partitions() {
+        let blk = BlockDevice::memory(1024 * 1024).with_force(true);
+
+        let mut mbr = vec![0; 512];
+        mbr_entry(&mut mbr, 0, 0x83, 8, 100);
+        mbr_entry(&mut mbr, 1, 0x05, 200, 1000);
+        blk.write_direct_at(&mbr, 0).unwrap();
+
+        let mut ebr = vec![0; 512];
+        mbr_entry(&mut ebr, 0, 0x83, 8, 50);
+        mbr_entry(&mut ebr, 1, 0x05, 100, 200);
+        blk.write_direct_at(&ebr, 200 * 512).unwrap();
+
+        let mut ebr = vec![0; 512];
+        mbr_entry(&mut ebr, 0, 0x82, 4, 20);
+        blk.write_direct_at(&ebr, 300 * 512).unwrap();
+
+        let parts = read_partitions(&blk).unwrap();
+        let layout: Vec<(u32, u64, u64)> =
+            parts.iter().map(|p| (p.index, p.start, p.len)).collect();
+        assert_eq!(
+            layout,
+            vec![
+                (1, 8 * 512, 100 * 512),
+                (2, 200 * 512, 1024),
+                (5, 208 * 512, 50 * 512),
+                (6, 304 * 512, 20 * 512),
+            ]
+        );
+
+        assert_eq!(blk.partition(6).unwrap().get_disk_size(), 20 * 512);
+        assert_eq!(blk.partition(2).unwrap().get_disk_size(), 1024);
+        assert!(blk.partition(3).is_err());
+    }
+
+    #[test]
+    fn gpt() {
+        let blk = BlockDevice::memory(1024 * 1024).with_force(true);
+
+        let mut mbr = vec![0; 512];
+        mbr_entry(&mut mbr, 0, MBR_PROTECTIVE, 1, 2047);
+        blk.write_direct_at(&mbr, 0).unwrap();
+
+        let mut header = vec![0; 512];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        blk.write_direct_at(&header, 512).unwrap();
+
+        let mut table = vec![0; 512];
+        let entry = &mut table[256..384];
+        entry[0] = 0xaf;
+        entry[32..40].copy_from_slice(&64u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&127u64.to_le_bytes());
+        for (i, c) in "data".encode_utf16().enumerate() {
+            entry[(56 + 2 * i)..(58 + 2 * i)].copy_from_slice(&c.to_le_bytes());
+        }
+        blk.write_direct_at(&table, 1024).unwrap();
+
+        let parts = read_partitions(&blk).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].index, 3);
+        assert_eq!(parts[0].start, 64 * 512);
+        assert_eq!(parts[0].len, 64 * 512);
+        assert_eq!(parts[0].name, "data");
+        assert_eq!(parts[0].kind, "000000af-0000-0000-0000-000000000000");
+    }
+}
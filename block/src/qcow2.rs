@@ -0,0 +1,1374 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use flate2::{Decompress, FlushDecompress};
+
+use crate::backend::{self, Backend};
+use crate::error::BlockError;
+use crate::extent::{self, Allocation, Extent};
+use crate::image::{self, clamp_len, ImageFile};
+use crate::info::DeviceInfo;
+
+// Layout from the qcow2 specification in qemu,
+// https://gitlab.com/qemu-project/qemu/-/blob/master/docs/interop/qcow2.txt
+const MAGIC: u32 = 0x514649fb; // QFI\xfb
+
+const HEADER_V2_LEN: usize = 72;
+const HEADER_V3_LEN: usize = 104;
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+
+const REFTABLE_OFFSET: u64 = 48;
+const AUTOCLEAR_OFFSET: u64 = 88;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+pub const DEFAULT_CLUSTER_BITS: u32 = 16;
+
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFT_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+const OFLAG_COPIED: u64 = 1 << 63;
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+const OFLAG_ZERO: u64 = 1 << 0;
+
+// What qemu itself refuses to open.
+const MAX_L1_SIZE: u64 = 32 * 1024 * 1024;
+const MAX_REFTABLE_SIZE: u64 = 8 * 1024 * 1024;
+
+// Backing chains longer than this are most likely a loop.
+const MAX_BACKING_DEPTH: usize = 16;
+
+#[derive(Debug, Clone)]
+struct Header {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    incompatible: u64,
+    autoclear: u64,
+    refcount_order: u32,
+}
+
+fn be32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn be64(buf: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+impl Header {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_V2_LEN || be32(buf, 0) != MAGIC {
+            return None;
+        }
+
+        let mut header = Header {
+            version: be32(buf, 4),
+            backing_file_offset: be64(buf, 8),
+            backing_file_size: be32(buf, 16),
+            cluster_bits: be32(buf, 20),
+            size: be64(buf, 24),
+            crypt_method: be32(buf, 32),
+            l1_size: be32(buf, 36),
+            l1_table_offset: be64(buf, 40),
+            refcount_table_offset: be64(buf, 48),
+            refcount_table_clusters: be32(buf, 56),
+            nb_snapshots: be32(buf, 60),
+            incompatible: 0,
+            autoclear: 0,
+            refcount_order: 4,
+        };
+
+        if header.version >= 3 {
+            if buf.len() < HEADER_V3_LEN {
+                return None;
+            }
+            header.incompatible = be64(buf, 72);
+            header.autoclear = be64(buf, AUTOCLEAR_OFFSET as usize);
+            header.refcount_order = be32(buf, 96);
+        }
+
+        Some(header)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buf = vec![0; HEADER_V3_LEN];
+
+        buf[0..4].copy_from_slice(&MAGIC.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.version.to_be_bytes());
+        buf[8..16].copy_from_slice(&self.backing_file_offset.to_be_bytes());
+        buf[16..20].copy_from_slice(&self.backing_file_size.to_be_bytes());
+        buf[20..24].copy_from_slice(&self.cluster_bits.to_be_bytes());
+        buf[24..32].copy_from_slice(&self.size.to_be_bytes());
+        buf[32..36].copy_from_slice(&self.crypt_method.to_be_bytes());
+        buf[36..40].copy_from_slice(&self.l1_size.to_be_bytes());
+        buf[40..48].copy_from_slice(&self.l1_table_offset.to_be_bytes());
+        buf[48..56].copy_from_slice(&self.refcount_table_offset.to_be_bytes());
+        buf[56..60].copy_from_slice(&self.refcount_table_clusters.to_be_bytes());
+        buf[60..64].copy_from_slice(&self.nb_snapshots.to_be_bytes());
+        buf[72..80].copy_from_slice(&self.incompatible.to_be_bytes());
+        buf[88..96].copy_from_slice(&self.autoclear.to_be_bytes());
+        buf[96..100].copy_from_slice(&self.refcount_order.to_be_bytes());
+        buf[100..104].copy_from_slice(&(HEADER_V3_LEN as u32).to_be_bytes());
+
+        buf
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    // Bytes of guest data one L2 table maps.
+    fn l2_span(&self) -> u64 {
+        self.cluster_size() * (self.cluster_size() / 8)
+    }
+
+    // Refcounts in one refcount block.
+    fn refcounts_per_block(&self) -> u64 {
+        (self.cluster_size() * 8) >> self.refcount_order
+    }
+}
+
+// Where a guest cluster's data is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mapping {
+    // Not in this image; read from the backing file, if any.
+    Unallocated,
+    // Reads as zeroes, possibly with a host cluster kept for it.
+    Zero(u64),
+    Data(u64),
+    // `len` bytes of deflate stream at `offset`.
+    Compressed { offset: u64, len: u64 },
+}
+
+#[derive(Debug)]
+struct Meta {
+    l1: Vec<u64>,
+    // Moves when it grows, see `grow_refcount_table`.
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    // The L2 table looked at last, by offset.
+    l2_cache: Option<(u64, Vec<u64>)>,
+    // The compressed cluster read last, by host offset.
+    compressed_cache: Option<(u64, Vec<u8>)>,
+    // New clusters are allocated here, at the end of the file.
+    end: u64,
+    // Autoclear features still set on disk.
+    autoclear: u64,
+}
+
+// A qcow2 image, version 2 or 3, read and written without qemu.
+// Compressed clusters are read but never written: a write to one
+// moves the cluster to a new uncompressed one. Images that qemu
+// marked dirty or corrupt, or that have internal snapshots, open
+// read-only, as their refcounts cannot be trusted or shared clusters
+// would have to be copied on write.
+#[derive(Debug)]
+pub struct Qcow2Image {
+    path: String,
+    file: File,
+    header: Header,
+    backing: Option<Arc<dyn Backend>>,
+    read_only: Option<String>,
+    meta: Mutex<Meta>,
+}
+
+// Whether `path` is a qcow2 image.
+pub fn is_qcow2(path: &str) -> bool {
+    let mut magic = [0; 4];
+
+    match File::open(path).and_then(|mut f| f.read_exact(&mut magic)) {
+        Ok(()) => u32::from_be_bytes(magic) == MAGIC,
+        Err(_) => false,
+    }
+}
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("qcow2: {}", msg))
+}
+
+impl Qcow2Image {
+    pub fn open(path: &str) -> Result<Self, BlockError> {
+        Qcow2Image::open_chain(path, 0)
+    }
+
+    fn open_chain(path: &str, depth: usize) -> Result<Self, BlockError> {
+        let unsupported = |msg: &str| BlockError::Unsupported(format!("{}: {}", path, msg));
+
+        let (file, writable) = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => (file, true),
+            Err(_) => (
+                File::open(path).map_err(|e| BlockError::open(path, e))?,
+                false,
+            ),
+        };
+
+        let mut buf = vec![0; HEADER_V3_LEN];
+        let len = file
+            .read_at(&mut buf, 0)
+            .map_err(|e| BlockError::open(path, e))?;
+        let header = Header::parse(&buf[..len]).ok_or_else(|| unsupported("not a qcow2 image"))?;
+
+        if header.version != 2 && header.version != 3 {
+            return Err(unsupported(&format!(
+                "qcow2 version {} is not supported",
+                header.version
+            )));
+        }
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&header.cluster_bits) {
+            return Err(unsupported(&format!(
+                "bad cluster size 2^{}",
+                header.cluster_bits
+            )));
+        }
+        if header.crypt_method != 0 {
+            return Err(unsupported("encrypted images are not supported"));
+        }
+        // External data files, zstd and extended L2 entries.
+        let unknown = header.incompatible & !(INCOMPAT_DIRTY | INCOMPAT_CORRUPT);
+        if unknown != 0 {
+            return Err(unsupported(&format!(
+                "incompatible features {:#x} are not supported",
+                unknown
+            )));
+        }
+        if header.refcount_order > 6 {
+            return Err(unsupported("bad refcount width"));
+        }
+
+        let cluster_size = header.cluster_size();
+        let l1_size = header.l1_size as u64;
+        if l1_size * 8 > MAX_L1_SIZE || l1_size * header.l2_span() < header.size {
+            return Err(unsupported("bad L1 table size"));
+        }
+        let reftable_size = header.refcount_table_clusters as u64 * cluster_size;
+        if reftable_size > MAX_REFTABLE_SIZE {
+            return Err(unsupported("bad refcount table size"));
+        }
+
+        let l1 = read_table(&file, header.l1_table_offset, l1_size)
+            .map_err(|e| BlockError::open(path, e))?;
+        let refcount_table = read_table(&file, header.refcount_table_offset, reftable_size / 8)
+            .map_err(|e| BlockError::open(path, e))?;
+
+        let read_only = if !writable {
+            Some("opened read-only".to_string())
+        } else if header.incompatible & INCOMPAT_CORRUPT != 0 {
+            Some("marked corrupt".to_string())
+        } else if header.incompatible & INCOMPAT_DIRTY != 0 {
+            Some("marked dirty, run qemu-img check -r all first".to_string())
+        } else if header.nb_snapshots > 0 {
+            Some("has internal snapshots".to_string())
+        } else if header.refcount_order < 3 {
+            Some(format!("{}-bit refcounts", 1 << header.refcount_order))
+        } else {
+            None
+        };
+
+        let backing = match header.backing_file_offset {
+            0 => None,
+            offset => {
+                let mut name = vec![0; header.backing_file_size as usize];
+                file.read_exact_at(&mut name, offset)
+                    .map_err(|e| BlockError::open(path, e))?;
+                let name = String::from_utf8_lossy(&name).to_string();
+
+                Some(open_backing(path, &name, depth + 1)?)
+            }
+        };
+
+        let end = file
+            .metadata()
+            .map_err(|e| BlockError::open(path, e))?
+            .len()
+            .next_multiple_of(cluster_size);
+
+        let refcount_table_offset = header.refcount_table_offset;
+        let autoclear = header.autoclear;
+        Ok(Qcow2Image {
+            path: path.to_string(),
+            file,
+            header,
+            backing,
+            read_only,
+            meta: Mutex::new(Meta {
+                l1,
+                refcount_table_offset,
+                refcount_table,
+                l2_cache: None,
+                compressed_cache: None,
+                end,
+                autoclear,
+            }),
+        })
+    }
+
+    // Create (or truncate) a qcow2 v3 image of `size` bytes with
+    // clusters of 2^`cluster_bits` bytes, on top of `backing` if given.
+    // Nothing but the metadata is allocated.
+    pub fn create(
+        path: &str,
+        size: u64,
+        cluster_bits: u32,
+        backing: Option<&str>,
+    ) -> Result<Self, BlockError> {
+        let unsupported = |msg: &str| BlockError::Unsupported(format!("{}: {}", path, msg));
+
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(unsupported(&format!("bad cluster size 2^{}", cluster_bits)));
+        }
+
+        let mut header = Header {
+            version: 3,
+            backing_file_offset: 0,
+            backing_file_size: 0,
+            cluster_bits,
+            size,
+            crypt_method: 0,
+            l1_size: 0,
+            l1_table_offset: 0,
+            refcount_table_offset: 0,
+            refcount_table_clusters: 1,
+            nb_snapshots: 0,
+            incompatible: 0,
+            autoclear: 0,
+            refcount_order: 4,
+        };
+        let cluster_size = header.cluster_size();
+
+        // The header with an empty extension list and the backing file
+        // name in cluster 0, the refcount table in 1, its first block
+        // in 2 and the L1 table from 3 on.
+        let l1_size = size.div_ceil(header.l2_span());
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+        let nr_clusters = 3 + l1_clusters;
+        if l1_size * 8 > MAX_L1_SIZE || nr_clusters > header.refcounts_per_block() {
+            return Err(unsupported("image too large for the cluster size"));
+        }
+
+        header.l1_size = l1_size as u32;
+        header.refcount_table_offset = cluster_size;
+        header.l1_table_offset = 3 * cluster_size;
+
+        let name_offset = HEADER_V3_LEN as u64 + 8;
+        if let Some(name) = backing {
+            if name_offset + name.len() as u64 > cluster_size {
+                return Err(unsupported("backing file name too long"));
+            }
+            header.backing_file_offset = name_offset;
+            header.backing_file_size = name.len() as u32;
+        }
+
+        let mut buf = vec![0; (nr_clusters * cluster_size) as usize];
+        buf[..HEADER_V3_LEN].copy_from_slice(&header.serialize());
+        if let Some(name) = backing {
+            let start = name_offset as usize;
+            buf[start..start + name.len()].copy_from_slice(name.as_bytes());
+        }
+
+        let cs = cluster_size as usize;
+        buf[cs..cs + 8].copy_from_slice(&(2 * cluster_size).to_be_bytes());
+        for i in 0..nr_clusters as usize {
+            let at = 2 * cs + i * 2;
+            buf[at..at + 2].copy_from_slice(&1u16.to_be_bytes());
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| BlockError::open(path, e))?;
+        file.write_all_at(&buf, 0)
+            .and_then(|_| file.sync_data())
+            .map_err(|e| BlockError::open(path, e))?;
+        drop(file);
+
+        Qcow2Image::open(path)
+    }
+
+    pub fn get_version(&self) -> u32 {
+        self.header.version
+    }
+
+    pub fn get_cluster_size(&self) -> u64 {
+        self.header.cluster_size()
+    }
+
+    pub fn get_backing(&self) -> Option<&Arc<dyn Backend>> {
+        self.backing.as_ref()
+    }
+
+    // Why the image can't be written, if it can't.
+    pub fn get_read_only(&self) -> Option<&str> {
+        self.read_only.as_deref()
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only.is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+
+        Ok(())
+    }
+
+    // Autoclear features mark metadata that any write by a program
+    // not keeping it up to date makes stale, such as persistent dirty
+    // bitmaps. None is kept up to date here, so they are all cleared
+    // on disk before the first write, as the specification asks.
+    fn clear_autoclear(&self, meta: &mut Meta) -> io::Result<()> {
+        if meta.autoclear != 0 {
+            self.file
+                .write_all_at(&0u64.to_be_bytes(), AUTOCLEAR_OFFSET)?;
+            self.file.sync_data()?;
+            meta.autoclear = 0;
+        }
+
+        Ok(())
+    }
+
+    fn decode(&self, entry: u64) -> Mapping {
+        if entry & OFLAG_COMPRESSED != 0 {
+            // The host offset takes the low bits, the number of extra
+            // 512 byte sectors the stream spans the rest up to bit 61.
+            let shift = 62 - (self.header.cluster_bits - 8);
+            let offset = entry & ((1 << shift) - 1);
+            let sectors = ((entry & !(OFLAG_COPIED | OFLAG_COMPRESSED)) >> shift) + 1;
+
+            return Mapping::Compressed {
+                offset,
+                len: sectors * 512 - (offset & 511),
+            };
+        }
+
+        let host = entry & OFFSET_MASK;
+        if self.header.version >= 3 && entry & OFLAG_ZERO != 0 {
+            Mapping::Zero(host)
+        } else if host == 0 {
+            Mapping::Unallocated
+        } else {
+            Mapping::Data(host)
+        }
+    }
+
+    // The L2 table and index in it that map `pos`, if there is one.
+    fn l2_slot(&self, meta: &Meta, pos: u64) -> Option<(u64, usize)> {
+        let l1_index = (pos / self.header.l2_span()) as usize;
+        let l2_offset = meta.l1.get(l1_index)? & OFFSET_MASK;
+        if l2_offset == 0 {
+            return None;
+        }
+
+        let index = (pos % self.header.l2_span()) >> self.header.cluster_bits;
+        Some((l2_offset, index as usize))
+    }
+
+    fn l2_entry(&self, meta: &mut Meta, l2_offset: u64, index: usize) -> io::Result<u64> {
+        match &meta.l2_cache {
+            Some((offset, table)) if *offset == l2_offset => Ok(table[index]),
+            _ => {
+                let table = read_table(&self.file, l2_offset, self.header.cluster_size() / 8)?;
+                let entry = table[index];
+                meta.l2_cache = Some((l2_offset, table));
+                Ok(entry)
+            }
+        }
+    }
+
+    fn set_l2_entry(
+        &self,
+        meta: &mut Meta,
+        l2_offset: u64,
+        index: usize,
+        entry: u64,
+    ) -> io::Result<()> {
+        self.file
+            .write_all_at(&entry.to_be_bytes(), l2_offset + index as u64 * 8)?;
+
+        if let Some((offset, table)) = &mut meta.l2_cache {
+            if *offset == l2_offset {
+                table[index] = entry;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The raw L2 entry for `pos`, zero where no L2 table exists.
+    fn entry(&self, meta: &mut Meta, pos: u64) -> io::Result<u64> {
+        match self.l2_slot(meta, pos) {
+            Some((l2_offset, index)) => self.l2_entry(meta, l2_offset, index),
+            None => Ok(0),
+        }
+    }
+
+    // Like `l2_slot`, but allocates the L2 table if there is none.
+    fn l2_slot_alloc(&self, meta: &mut Meta, pos: u64) -> io::Result<(u64, usize)> {
+        if let Some(slot) = self.l2_slot(meta, pos) {
+            return Ok(slot);
+        }
+
+        let l1_index = (pos / self.header.l2_span()) as usize;
+        if l1_index >= meta.l1.len() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let l2_offset = self.alloc_cluster(meta)?;
+        self.file
+            .write_all_at(&vec![0; self.header.cluster_size() as usize], l2_offset)?;
+
+        let entry = l2_offset | OFLAG_COPIED;
+        self.file.write_all_at(
+            &entry.to_be_bytes(),
+            self.header.l1_table_offset + l1_index as u64 * 8,
+        )?;
+        meta.l1[l1_index] = entry;
+
+        Ok(self.l2_slot(meta, pos).unwrap())
+    }
+
+    fn refcount_width(&self) -> usize {
+        (1 << self.header.refcount_order) / 8
+    }
+
+    // Where the refcount of host cluster `cluster` is kept, if its
+    // refcount block exists.
+    fn refcount_slot(&self, meta: &Meta, cluster: u64) -> Option<u64> {
+        let per_block = self.header.refcounts_per_block();
+        let block = meta.refcount_table.get((cluster / per_block) as usize)? & REFT_OFFSET_MASK;
+        if block == 0 {
+            return None;
+        }
+
+        Some(block + (cluster % per_block) * self.refcount_width() as u64)
+    }
+
+    fn get_refcount(&self, meta: &Meta, cluster: u64) -> io::Result<u64> {
+        let slot = match self.refcount_slot(meta, cluster) {
+            Some(slot) => slot,
+            None => return Ok(0),
+        };
+
+        let width = self.refcount_width();
+        let mut buf = [0; 8];
+        self.file.read_exact_at(&mut buf[8 - width..], slot)?;
+
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn set_refcount(&self, meta: &Meta, cluster: u64, value: u64) -> io::Result<()> {
+        let slot = self
+            .refcount_slot(meta, cluster)
+            .ok_or_else(|| corrupt("refcount block missing"))?;
+
+        let width = self.refcount_width();
+        self.file
+            .write_all_at(&value.to_be_bytes()[8 - width..], slot)
+    }
+
+    // Allocate a host cluster at the end of the file, with a new
+    // refcount block first if its refcount has nowhere to go yet, and
+    // a larger refcount table before that if the block has no entry.
+    fn alloc_cluster(&self, meta: &mut Meta) -> io::Result<u64> {
+        let cluster_size = self.header.cluster_size();
+        let per_block = self.header.refcounts_per_block();
+
+        loop {
+            let host = meta.end;
+            let cluster = host / cluster_size;
+            let index = (cluster / per_block) as usize;
+            if index >= meta.refcount_table.len() {
+                self.grow_refcount_table(meta, index)?;
+                continue;
+            }
+
+            meta.end += cluster_size;
+
+            if meta.refcount_table[index] & REFT_OFFSET_MASK == 0 {
+                // The new block goes where the cluster would have, and
+                // counts itself.
+                let mut block = vec![0; cluster_size as usize];
+                let width = self.refcount_width();
+                let at = (cluster % per_block) as usize * width;
+                block[at..at + width].copy_from_slice(&1u64.to_be_bytes()[8 - width..]);
+                self.file.write_all_at(&block, host)?;
+
+                self.file.write_all_at(
+                    &host.to_be_bytes(),
+                    meta.refcount_table_offset + index as u64 * 8,
+                )?;
+                meta.refcount_table[index] = host;
+                continue;
+            }
+
+            // Metadata qemu preallocated past the end of the file.
+            if self.get_refcount(meta, cluster)? != 0 {
+                continue;
+            }
+
+            self.set_refcount(meta, cluster, 1)?;
+            return Ok(host);
+        }
+    }
+
+    // Replace the refcount table with one at least twice as large at
+    // the end of the file, with an entry for refcount block `index`.
+    // The refcount blocks the new clusters need are put right after
+    // it. Only once all of that is on disk does the header point to
+    // it, and then the old table is freed.
+    fn grow_refcount_table(&self, meta: &mut Meta, index: usize) -> io::Result<()> {
+        let cluster_size = self.header.cluster_size();
+        let per_block = self.header.refcounts_per_block();
+        let per_cluster = cluster_size / 8;
+
+        let old_clusters = meta.refcount_table.len() as u64 / per_cluster;
+        let new_clusters = (index as u64 + 1)
+            .div_ceil(per_cluster)
+            .max(old_clusters * 2);
+        if new_clusters * cluster_size > MAX_REFTABLE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "qcow2: refcount table is full",
+            ));
+        }
+
+        let mut table = meta.refcount_table.clone();
+        table.resize((new_clusters * per_cluster) as usize, 0);
+
+        // New blocks can make for more clusters that need one.
+        let start = meta.end / cluster_size;
+        let mut blocks: Vec<u64> = Vec::new();
+        let end = loop {
+            let end = start + new_clusters + blocks.len() as u64;
+            let missing: Vec<u64> = (start / per_block..=(end - 1) / per_block)
+                .filter(|b| {
+                    table
+                        .get(*b as usize)
+                        .is_none_or(|e| e & REFT_OFFSET_MASK == 0)
+                })
+                .collect();
+            if missing.len() == blocks.len() {
+                break end;
+            }
+            blocks = missing;
+        };
+        if blocks.last().is_some_and(|b| *b as usize >= table.len()) {
+            return Err(corrupt("refcount table too small for itself"));
+        }
+
+        let width = self.refcount_width();
+        let one = &1u64.to_be_bytes()[8 - width..];
+        for (i, b) in blocks.iter().enumerate() {
+            let host = (start + new_clusters + i as u64) * cluster_size;
+            let mut block = vec![0; cluster_size as usize];
+            let first = b * per_block;
+            for c in start.max(first)..end.min(first + per_block) {
+                let at = (c - first) as usize * width;
+                block[at..at + width].copy_from_slice(one);
+            }
+            self.file.write_all_at(&block, host)?;
+            table[*b as usize] = host;
+        }
+        // The rest are counted in blocks that already exist.
+        for c in start..end {
+            if !blocks.contains(&(c / per_block)) {
+                self.set_refcount(meta, c, 1)?;
+            }
+        }
+
+        let offset = start * cluster_size;
+        let buf: Vec<u8> = table.iter().flat_map(|e| e.to_be_bytes()).collect();
+        self.file.write_all_at(&buf, offset)?;
+        self.file.sync_data()?;
+
+        let mut header = [0; 12];
+        header[..8].copy_from_slice(&offset.to_be_bytes());
+        header[8..].copy_from_slice(&(new_clusters as u32).to_be_bytes());
+        self.file.write_all_at(&header, REFTABLE_OFFSET)?;
+        self.file.sync_data()?;
+
+        let old_offset = meta.refcount_table_offset;
+        meta.refcount_table_offset = offset;
+        meta.refcount_table = table;
+        meta.end = end * cluster_size;
+
+        self.free(meta, old_offset, old_clusters * cluster_size)
+    }
+
+    // Drop a reference to each host cluster of `len` bytes at `offset`
+    // and punch out the clusters that are no longer used.
+    fn free(&self, meta: &Meta, offset: u64, len: u64) -> io::Result<()> {
+        let cluster_size = self.header.cluster_size();
+
+        for cluster in (offset / cluster_size)..(offset + len).div_ceil(cluster_size) {
+            let refcount = self.get_refcount(meta, cluster)?;
+            if refcount == 0 {
+                continue;
+            }
+
+            self.set_refcount(meta, cluster, refcount - 1)?;
+            if refcount == 1 {
+                self.punch(cluster * cluster_size, cluster_size);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn free_mapping(&self, meta: &Meta, mapping: Mapping) -> io::Result<()> {
+        match mapping {
+            Mapping::Data(host) | Mapping::Zero(host) if host != 0 => {
+                self.free(meta, host, self.header.cluster_size())
+            }
+            // As qemu frees them, from the start of the first sector.
+            Mapping::Compressed { offset, len } => {
+                let start = offset & !511;
+                self.free(meta, start, (offset + len) - start)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn punch(&self, offset: u64, len: u64) {
+        // Space is only given back where the filesystem can.
+        unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+    }
+
+    fn decompress(&self, meta: &mut Meta, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        if let Some((cached, data)) = &meta.compressed_cache {
+            if *cached == offset {
+                return Ok(data.clone());
+            }
+        }
+
+        // The last stream in the file may end before its last sector.
+        let mut input = vec![0; len as usize];
+        let mut read = 0;
+        while read < input.len() {
+            match self
+                .file
+                .read_at(&mut input[read..], offset + read as u64)?
+            {
+                0 => break,
+                n => read += n,
+            }
+        }
+
+        let mut data = vec![0; self.header.cluster_size() as usize];
+        let mut inflate = Decompress::new(false);
+        inflate
+            .decompress(&input[..read], &mut data, FlushDecompress::Finish)
+            .map_err(|e| corrupt(&format!("compressed cluster at {}: {}", offset, e)))?;
+        if inflate.total_out() != data.len() as u64 {
+            return Err(corrupt(&format!(
+                "compressed cluster at {} is short",
+                offset
+            )));
+        }
+
+        meta.compressed_cache = Some((offset, data.clone()));
+        Ok(data)
+    }
+
+    fn read_backing(&self, buf: &mut [u8], pos: u64, direct: bool) -> io::Result<()> {
+        let backing = match &self.backing {
+            Some(backing) => backing,
+            None => {
+                buf.fill(0);
+                return Ok(());
+            }
+        };
+
+        // Backing files may be smaller than the image on top.
+        let len = clamp_len(buf.len(), pos, backing.size());
+        let (head, tail) = buf.split_at_mut(len);
+        let ret = if direct {
+            backing.read_direct_at(head, pos)?
+        } else {
+            backing.read_at(head, pos)?
+        };
+        if ret != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        tail.fill(0);
+
+        Ok(())
+    }
+
+    fn read(&self, buf: &mut [u8], offset: u64, direct: bool) -> io::Result<usize> {
+        let len = clamp_len(buf.len(), offset, self.header.size);
+        let cluster_size = self.header.cluster_size();
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let in_cluster = pos % cluster_size;
+            let n = std::cmp::min(cluster_size - in_cluster, (len - done) as u64) as usize;
+            let chunk = &mut buf[done..done + n];
+
+            // The lock is only held for the lookup; reads racing with
+            // a write to the same cluster see either its old or new
+            // contents, as they would on a disk.
+            let mut meta = self.meta.lock().unwrap();
+            let mapping = self.decode(self.entry(&mut meta, pos)?);
+            match mapping {
+                Mapping::Compressed { offset, len } => {
+                    let data = self.decompress(&mut meta, offset, len)?;
+                    let start = in_cluster as usize;
+                    chunk.copy_from_slice(&data[start..start + n]);
+                }
+                _ => {
+                    drop(meta);
+                    match mapping {
+                        Mapping::Unallocated => self.read_backing(chunk, pos, direct)?,
+                        Mapping::Data(host) => {
+                            if direct {
+                                image::drop_cache(&self.file, host + in_cluster, n);
+                            }
+                            self.file.read_exact_at(chunk, host + in_cluster)?;
+                        }
+                        _ => chunk.fill(0),
+                    }
+                }
+            }
+
+            done += n;
+        }
+
+        Ok(len)
+    }
+
+    // Write `chunk` at `pos`, within one cluster. Clusters that are
+    // not allocated, or only shared or compressed, get a new host
+    // cluster filled with what they read as before.
+    fn write_cluster(&self, meta: &mut Meta, chunk: &[u8], pos: u64) -> io::Result<()> {
+        let cluster_size = self.header.cluster_size();
+        let in_cluster = pos % cluster_size;
+        let start = pos - in_cluster;
+
+        let (l2_offset, index) = self.l2_slot_alloc(meta, pos)?;
+        let entry = self.l2_entry(meta, l2_offset, index)?;
+        let mapping = self.decode(entry);
+
+        if let Mapping::Data(host) = mapping {
+            if entry & OFLAG_COPIED != 0 {
+                self.file.write_all_at(chunk, host + in_cluster)?;
+                return image::write_back(&self.file, host + in_cluster, chunk.len());
+            }
+        }
+
+        let mut data = vec![0; cluster_size as usize];
+        if chunk.len() as u64 != cluster_size {
+            match mapping {
+                Mapping::Unallocated => self.read_backing(&mut data, start, false)?,
+                Mapping::Data(host) => self.file.read_exact_at(&mut data, host)?,
+                Mapping::Compressed { offset, len } => {
+                    data = self.decompress(meta, offset, len)?;
+                }
+                Mapping::Zero(_) => {}
+            }
+        }
+        let at = in_cluster as usize;
+        data[at..at + chunk.len()].copy_from_slice(chunk);
+
+        // A zero cluster that kept its host cluster can have it back.
+        let (host, old) = match mapping {
+            Mapping::Zero(host) if host != 0 && entry & OFLAG_COPIED != 0 => {
+                (host, Mapping::Unallocated)
+            }
+            _ => (self.alloc_cluster(meta)?, mapping),
+        };
+
+        self.file.write_all_at(&data, host)?;
+        image::write_back(&self.file, host, data.len())?;
+        self.set_l2_entry(meta, l2_offset, index, host | OFLAG_COPIED)?;
+
+        if let Mapping::Compressed { offset, .. } = old {
+            if matches!(&meta.compressed_cache, Some((cached, _)) if *cached == offset) {
+                meta.compressed_cache = None;
+            }
+        }
+        self.free_mapping(meta, old)
+    }
+
+    // Turn the whole clusters in `len` bytes at `offset` into zero
+    // clusters and write zeroes to the partial ones at either end.
+    // With `keep` the host clusters stay allocated for later writes,
+    // otherwise they are freed.
+    fn zero_range(&self, offset: u64, len: u64, keep: bool) -> io::Result<()> {
+        self.check_writable()?;
+        if self.header.version < 3 {
+            return backend::fill_zeroes(self, offset, len);
+        }
+
+        let cluster_size = self.header.cluster_size();
+        let len = clamp_len(len as usize, offset, self.header.size) as u64;
+        let zeroes = vec![0; cluster_size as usize];
+        let mut meta = self.meta.lock().unwrap();
+        self.clear_autoclear(&mut meta)?;
+
+        let mut pos = offset;
+        while pos < offset + len {
+            let in_cluster = pos % cluster_size;
+            let n = std::cmp::min(cluster_size - in_cluster, offset + len - pos);
+            let last = pos + n == self.header.size;
+
+            if n != cluster_size && !(in_cluster == 0 && last) {
+                self.write_cluster(&mut meta, &zeroes[..n as usize], pos)?;
+                pos += n;
+                continue;
+            }
+
+            // Nothing to hide and nothing to free.
+            if self.backing.is_none() && self.l2_slot(&meta, pos).is_none() {
+                pos += n;
+                continue;
+            }
+
+            let (l2_offset, index) = self.l2_slot_alloc(&mut meta, pos)?;
+            let entry = self.l2_entry(&mut meta, l2_offset, index)?;
+            let mapping = self.decode(entry);
+
+            let (new, old) = match mapping {
+                Mapping::Data(host) | Mapping::Zero(host)
+                    if keep && host != 0 && entry & OFLAG_COPIED != 0 =>
+                {
+                    (host | OFLAG_COPIED | OFLAG_ZERO, Mapping::Unallocated)
+                }
+                _ => (OFLAG_ZERO, mapping),
+            };
+            self.set_l2_entry(&mut meta, l2_offset, index, new)?;
+            self.free_mapping(&meta, old)?;
+
+            pos += n;
+        }
+
+        Ok(())
+    }
+}
+
+fn read_table(file: &File, offset: u64, entries: u64) -> io::Result<Vec<u64>> {
+    let mut buf = vec![0; entries as usize * 8];
+    file.read_exact_at(&mut buf, offset)?;
+
+    Ok(buf.chunks_exact(8).map(|b| be64(b, 0)).collect())
+}
+
+// Backing file names are relative to the image that names them.
+fn open_backing(path: &str, name: &str, depth: usize) -> Result<Arc<dyn Backend>, BlockError> {
+    if depth > MAX_BACKING_DEPTH {
+        return Err(BlockError::Unsupported(format!(
+            "{}: backing chain is longer than {}",
+            path, MAX_BACKING_DEPTH
+        )));
+    }
+
+    let backing = match Path::new(path).parent() {
+        Some(dir) if !Path::new(name).is_absolute() => dir.join(name),
+        _ => Path::new(name).to_path_buf(),
+    };
+    let backing = backing.to_string_lossy();
+
+    if is_qcow2(&backing) {
+        Ok(Arc::new(Qcow2Image::open_chain(&backing, depth)?))
+    } else {
+        Ok(Arc::new(ImageFile::open(&backing)?))
+    }
+}
+
+impl Backend for Qcow2Image {
+    fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    fn info(&self) -> DeviceInfo {
+        let mut info = DeviceInfo::new(self, "qcow2 image");
+        if let Some(backing) = &self.backing {
+            let backing_info = backing.info();
+            info.backing
+                .push(format!("{} ({})", backing_info.path, backing_info.kind));
+            info.backing.extend(backing_info.backing);
+        }
+
+        info
+    }
+
+    // No `raw_fd`: guest offsets are not offsets in the file.
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.read(buf, offset, false)
+    }
+
+    fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.read(buf, offset, true)
+    }
+
+    fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.check_writable()?;
+
+        let len = clamp_len(buf.len(), offset, self.header.size);
+        let cluster_size = self.header.cluster_size();
+        let mut meta = self.meta.lock().unwrap();
+        self.clear_autoclear(&mut meta)?;
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let n = std::cmp::min(cluster_size - pos % cluster_size, (len - done) as u64) as usize;
+            self.write_cluster(&mut meta, &buf[done..done + n], pos)?;
+            done += n;
+        }
+
+        Ok(len)
+    }
+
+    // Discarded clusters become zero clusters rather than unallocated
+    // ones, so they don't show the backing file again.
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        if self.header.version < 3 {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+
+        self.zero_range(offset, len, false)
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        self.header.version >= 3
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.zero_range(offset, len, true)
+    }
+
+    // From the L2 tables, and the backing file where they have nothing.
+    fn allocation(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let len = clamp_len(len as usize, offset, self.header.size) as u64;
+        let end = offset + len;
+        let cluster_size = self.header.cluster_size();
+
+        let mut extents = Vec::new();
+        let mut unallocated: Vec<Extent> = Vec::new();
+        let mut meta = self.meta.lock().unwrap();
+
+        let mut pos = offset;
+        while pos < end {
+            let (n, state) = match self.l2_slot(&meta, pos) {
+                Some((l2_offset, index)) => {
+                    let entry = self.l2_entry(&mut meta, l2_offset, index)?;
+                    let state = match self.decode(entry) {
+                        Mapping::Unallocated => None,
+                        Mapping::Zero(_) => Some(Allocation::Zero),
+                        _ => Some(Allocation::Data),
+                    };
+                    (cluster_size - pos % cluster_size, state)
+                }
+                // No L2 table, nothing in all it would map.
+                None => (self.header.l2_span() - pos % self.header.l2_span(), None),
+            };
+            let n = std::cmp::min(n, end - pos);
+
+            match state {
+                Some(state) => extents.push(Extent::new(pos, n, state)),
+                None => match unallocated.last_mut() {
+                    Some(last) if last.end() == pos => last.len += n,
+                    _ => unallocated.push(Extent::new(pos, n, Allocation::Hole)),
+                },
+            }
+            pos += n;
+        }
+        drop(meta);
+
+        // What the backing file doesn't cover is left to be a hole.
+        if let Some(backing) = &self.backing {
+            for run in unallocated {
+                let len = clamp_len(run.len as usize, run.offset, backing.size()) as u64;
+                if len > 0 {
+                    extents.extend(backing.allocation(run.offset, len)?);
+                }
+            }
+        }
+
+        Ok(extent::normalize(extents, offset, len, Allocation::Hole))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::BlockDevice;
+    use crate::temp::TempPath;
+    use flate2::{Compress, Compression, FlushCompress};
+
+    fn temp_path(name: &str) -> TempPath {
+        TempPath::new(&format!("{}.qcow2", name))
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+    }
+
+    // Every host cluster in use is referenced exactly as often as its
+    // refcount says.
+    fn check_refcounts(img: &Qcow2Image) {
+        let meta = img.meta.lock().unwrap();
+        let cluster_size = img.header.cluster_size();
+        let mut expected = vec![0u64; (meta.end / cluster_size) as usize];
+        let mut add = |offset: u64, len: u64| {
+            for c in (offset / cluster_size)..(offset + len).div_ceil(cluster_size) {
+                expected[c as usize] += 1;
+            }
+        };
+
+        add(0, cluster_size);
+        add(
+            meta.refcount_table_offset,
+            meta.refcount_table.len() as u64 * 8,
+        );
+        add(img.header.l1_table_offset, img.header.l1_size as u64 * 8);
+        for block in meta.refcount_table.iter().filter(|b| **b != 0) {
+            add(*block, cluster_size);
+        }
+        for l1 in meta.l1.iter().map(|e| e & OFFSET_MASK).filter(|o| *o != 0) {
+            add(l1, cluster_size);
+            for entry in read_table(&img.file, l1, cluster_size / 8).unwrap() {
+                match img.decode(entry) {
+                    Mapping::Data(host) | Mapping::Zero(host) if host != 0 => {
+                        add(host, cluster_size)
+                    }
+                    Mapping::Compressed { offset, len } => {
+                        add(offset & !511, offset + len - (offset & !511))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for (c, count) in expected.iter().enumerate() {
+            assert_eq!(
+                img.get_refcount(&meta, c as u64).unwrap(),
+                *count,
+                "cluster {}",
+                c
+            );
+        }
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let path = temp_path("rw");
+        // Small clusters so that writes need new L2 tables and
+        // refcount blocks.
+        let img = Qcow2Image::create(&path, 1024 * 1024, 9, None).unwrap();
+        assert_eq!(img.get_cluster_size(), 512);
+
+        let data = pattern(300 * 1024, 1);
+        assert_eq!(img.write_direct_at(&data, 1000).unwrap(), data.len());
+        img.flush().unwrap();
+        check_refcounts(&img);
+        drop(img);
+
+        let img = Qcow2Image::open(&path).unwrap();
+        let mut buf = vec![0xff; 400 * 1024];
+        img.read_direct_at(&mut buf, 0).unwrap();
+        assert!(buf[..1000].iter().all(|b| *b == 0));
+        assert_eq!(&buf[1000..1000 + data.len()], &data[..]);
+        assert!(buf[1000 + data.len()..].iter().all(|b| *b == 0));
+
+        // Rewrites stay in place.
+        let end = img.meta.lock().unwrap().end;
+        img.write_direct_at(&pattern(4096, 2), 4096).unwrap();
+        assert_eq!(img.meta.lock().unwrap().end, end);
+
+        // Whole clusters become zero clusters, partial ones are
+        // written with zeroes.
+        img.write_zeroes(4000, 8192).unwrap();
+        img.discard(65536, 4096).unwrap();
+        img.read_at(&mut buf[..8192], 4000).unwrap();
+        assert!(buf[..8192].iter().all(|b| *b == 0));
+        assert!(matches!(
+            img.decode(img.entry(&mut img.meta.lock().unwrap(), 4096).unwrap()),
+            Mapping::Zero(host) if host != 0
+        ));
+        assert_eq!(
+            img.decode(img.entry(&mut img.meta.lock().unwrap(), 65536).unwrap()),
+            Mapping::Zero(0)
+        );
+        img.write_direct_at(&[7; 100], 4200).unwrap();
+        img.read_at(&mut buf[..512], 4096).unwrap();
+        assert!(buf[..104].iter().all(|b| *b == 0));
+        assert!(buf[104..204].iter().all(|b| *b == 7));
+        assert!(buf[204..512].iter().all(|b| *b == 0));
+        check_refcounts(&img);
+    }
+
+    #[test]
+    fn refcount_table_grows() {
+        let path = temp_path("reftable");
+        // One cluster of refcount table covers 64 refcount blocks of
+        // 256 clusters, 8 MiB of 512 byte clusters.
+        let img = Qcow2Image::create(&path, 16 * 1024 * 1024, 9, None).unwrap();
+        let data = pattern(10 * 1024 * 1024, 5);
+        img.write_direct_at(&data, 0).unwrap();
+        assert!(img.meta.lock().unwrap().refcount_table.len() > 64);
+        check_refcounts(&img);
+        drop(img);
+
+        let img = Qcow2Image::open(&path).unwrap();
+        assert!(img.header.refcount_table_clusters > 1);
+        let mut buf = vec![0; data.len()];
+        img.read_at(&mut buf, 0).unwrap();
+        assert!(buf == data);
+        check_refcounts(&img);
+    }
+
+    #[test]
+    fn autoclear_cleared_on_write() {
+        let path = temp_path("autoclear");
+        let img = Qcow2Image::create(&path, 256 * 1024, 12, None).unwrap();
+        // As if qemu had added a persistent bitmap.
+        img.file
+            .write_all_at(&1u64.to_be_bytes(), AUTOCLEAR_OFFSET)
+            .unwrap();
+        drop(img);
+
+        // Reads leave it alone, the first write clears it.
+        let img = Qcow2Image::open(&path).unwrap();
+        assert_eq!(img.header.autoclear, 1);
+        img.read_at(&mut [0; 512], 0).unwrap();
+        drop(img);
+        let img = Qcow2Image::open(&path).unwrap();
+        assert_eq!(img.header.autoclear, 1);
+        img.write_direct_at(&[1; 512], 0).unwrap();
+        drop(img);
+
+        let img = Qcow2Image::open(&path).unwrap();
+        assert_eq!(img.header.autoclear, 0);
+    }
+
+    #[test]
+    fn backing_chain() {
+        let base_path = temp_path("base");
+        let top_path = temp_path("top");
+
+        let base = Qcow2Image::create(&base_path, 256 * 1024, 12, None).unwrap();
+        let data = pattern(256 * 1024, 3);
+        base.write_direct_at(&data, 0).unwrap();
+        drop(base);
+
+        // A larger image on top reads zeroes past the end of the base.
+        let name = Path::new(&*base_path)
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let top = Qcow2Image::create(&top_path, 512 * 1024, 12, Some(name)).unwrap();
+        assert_eq!(
+            top.info().backing,
+            vec![format!("{} (qcow2 image)", &*base_path)]
+        );
+
+        top.write_direct_at(&[9; 100], 5000).unwrap();
+        top.write_zeroes(8192, 4096).unwrap();
+
+        let mut buf = vec![0xff; 512 * 1024];
+        top.read_at(&mut buf, 0).unwrap();
+        let mut expected = data.clone();
+        expected[5000..5100].fill(9);
+        expected[8192..12288].fill(0);
+        expected.resize(512 * 1024, 0);
+        assert!(buf == expected);
+        check_refcounts(&top);
+
+        assert_eq!(
+            top.allocation(0, 512 * 1024).unwrap(),
+            vec![
+                Extent::new(0, 8192, Allocation::Data),
+                Extent::new(8192, 4096, Allocation::Zero),
+                Extent::new(12288, 256 * 1024 - 12288, Allocation::Data),
+                Extent::new(256 * 1024, 256 * 1024, Allocation::Hole),
+            ]
+        );
+
+        let mut buf = vec![0; 4096];
+        top.get_backing().unwrap().read_at(&mut buf, 4096).unwrap();
+        assert_eq!(&buf[..], &data[4096..8192]);
+
+        drop(top);
+        std::fs::remove_file(&base_path).unwrap();
+    }
+
+    #[test]
+    fn compressed_clusters() {
+        let path = temp_path("compressed");
+        let img = Qcow2Image::create(&path, 256 * 1024, 16, None).unwrap();
+        let data = pattern(65536, 4);
+
+        // Store cluster 1 compressed, as qemu-img convert -c would.
+        let mut stream = vec![0; 2 * 65536];
+        let mut deflate = Compress::new(Compression::default(), false);
+        deflate
+            .compress(&data, &mut stream, FlushCompress::Finish)
+            .unwrap();
+        stream.truncate(deflate.total_out() as usize);
+        {
+            let mut meta = img.meta.lock().unwrap();
+            let host = img.alloc_cluster(&mut meta).unwrap() + 100;
+            img.file.write_all_at(&stream, host).unwrap();
+
+            let sectors = (host % 512 + stream.len() as u64).div_ceil(512);
+            let entry = OFLAG_COMPRESSED | ((sectors - 1) << (62 - 8)) | host;
+            let (l2_offset, index) = img.l2_slot_alloc(&mut meta, 65536).unwrap();
+            img.set_l2_entry(&mut meta, l2_offset, index, entry)
+                .unwrap();
+        }
+        check_refcounts(&img);
+
+        let blk = BlockDevice::new(&path).unwrap();
+        assert_eq!(blk.get_info().kind, "qcow2 image");
+        let mut buf = vec![0; 65536];
+        blk.read_direct_at(&mut buf, 65536).unwrap();
+        assert!(buf == data);
+        drop(blk);
+
+        // Writing into it moves it to a plain cluster.
+        img.write_direct_at(&[1; 10], 65536 + 10).unwrap();
+        img.read_at(&mut buf, 65536).unwrap();
+        assert_eq!(&buf[..10], &data[..10]);
+        assert_eq!(&buf[10..20], &[1; 10]);
+        assert_eq!(&buf[20..], &data[20..]);
+        assert!(matches!(
+            img.decode(img.entry(&mut img.meta.lock().unwrap(), 65536).unwrap()),
+            Mapping::Data(_)
+        ));
+        check_refcounts(&img);
+    }
+}
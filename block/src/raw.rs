@@ -0,0 +1,385 @@
+use libc;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::aligned::AlignedBuf;
+use crate::backend::Backend;
+use crate::error::BlockError;
+use crate::image::{drop_cache, write_back};
+use crate::info::DeviceInfo;
+use crate::lock;
+use crate::sysfs;
+
+// A host block device. Both descriptors stay open for the lifetime
+// of the device: `file` goes through the page cache, `direct` is
+// opened with `O_DIRECT`. A few drivers reject that, and then
+// `direct` is `buffered` too and direct I/O drops the pages it
+// touched from the cache instead.
+#[derive(Debug)]
+pub struct RawDevice {
+    dev_path: String,
+    size: u64,
+    logical_block_size: u64,
+    physical_block_size: u64,
+    discard_zeroes_data: bool,
+    file: File,
+    direct: File,
+    buffered: bool,
+}
+
+// Used when neither the ioctls nor sysfs can tell us better.
+const DEFAULT_BLOCK_SIZE: u64 = 512;
+
+// _IO(0x12, nr) from <linux/fs.h>. The direction bits differ between
+// architectures, so borrow them from BLKSSZGET, which is _IO(0x12, 104).
+const fn blk_io(nr: libc::Ioctl) -> libc::Ioctl {
+    (libc::BLKSSZGET & !0xff) | nr
+}
+
+const BLKDISCARD: libc::Ioctl = blk_io(119);
+const BLKSECDISCARD: libc::Ioctl = blk_io(125);
+const BLKZEROOUT: libc::Ioctl = blk_io(127);
+
+impl RawDevice {
+    pub fn new(path: &str) -> Result<Self, BlockError> {
+        RawDevice::open(path, false)
+    }
+
+    // With `exclusive` the device is opened with `O_EXCL`, which the
+    // kernel refuses while it is mounted or opened exclusively by
+    // anybody else, and which keeps others out until we close it.
+    pub fn open(path: &str, exclusive: bool) -> Result<Self, BlockError> {
+        let meta = fs::metadata(path).map_err(|e| BlockError::open(path, e))?;
+        let file_type = meta.file_type();
+
+        if !file_type.is_block_device() {
+            return Err(BlockError::Unsupported(format!(
+                "{} is not a block device",
+                path
+            )));
+        }
+
+        let name = sysfs::device_name(path);
+        let size = sysfs::read_attr(&name, "size")
+            .ok_or_else(|| BlockError::Unsupported(format!("{}: no size in sysfs", name)))?;
+        let sectors = size
+            .parse::<u64>()
+            .map_err(|_| BlockError::Unsupported(format!("{}: bad size {:?}", name, size)))?;
+
+        let file = open_device(path, 0)?;
+
+        let excl = if exclusive { libc::O_EXCL } else { 0 };
+        let (direct, buffered) = match open_device(path, libc::O_DIRECT | excl) {
+            Err(BlockError::Open { source, .. }) if source.raw_os_error() == Some(libc::EINVAL) => {
+                (open_device(path, excl), true)
+            }
+            ret => (ret, false),
+        };
+        let direct = direct.map_err(|e| match e {
+            BlockError::Open { source, .. } if source.raw_os_error() == Some(libc::EBUSY) => {
+                BlockError::Busy {
+                    path: path.to_string(),
+                    pids: lock::holder_pids(path),
+                }
+            }
+            e => e,
+        })?;
+
+        let logical_block_size = ioctl_block_size(&file, libc::BLKSSZGET)
+            .or_else(|| queue_block_size(&name, "logical_block_size"))
+            .unwrap_or(DEFAULT_BLOCK_SIZE);
+        let physical_block_size = ioctl_block_size(&file, libc::BLKPBSZGET)
+            .or_else(|| queue_block_size(&name, "physical_block_size"))
+            .unwrap_or(logical_block_size);
+
+        let discard_zeroes_data = sysfs::read_queue_attr(&name, "discard_zeroes_data")
+            .map(|v| v == "1")
+            .unwrap_or(false);
+
+        Ok(RawDevice {
+            dev_path: path.to_string(),
+            // sysfs always counts in 512 byte units
+            size: 512 * sectors,
+            logical_block_size,
+            physical_block_size,
+            discard_zeroes_data,
+            file,
+            direct,
+            buffered,
+        })
+    }
+}
+
+// Both ioctls return the size as a 32-bit int.
+fn ioctl_block_size(file: &File, request: libc::Ioctl) -> Option<u64> {
+    let mut val: libc::c_int = 0;
+
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), request, &mut val as *mut libc::c_int) };
+    if ret < 0 || val <= 0 {
+        return None;
+    }
+
+    Some(val as u64)
+}
+
+fn queue_block_size(name: &str, attr: &str) -> Option<u64> {
+    let val = sysfs::read_queue_attr(name, attr)?.parse::<u64>().ok()?;
+    if val == 0 {
+        return None;
+    }
+
+    Some(val)
+}
+
+// Open read-write if we may, read-only otherwise, so checking a disk
+// doesn't need write permission. Writes to a read-only device then
+// fail with `EBADF`.
+fn open_device(path: &str, flags: i32) -> Result<File, BlockError> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).custom_flags(flags);
+
+    match options.open(path) {
+        Ok(f) => Ok(f),
+        Err(_) => OpenOptions::new()
+            .read(true)
+            .custom_flags(flags)
+            .open(path)
+            .map_err(|e| BlockError::open(path, e)),
+    }
+}
+
+// Like `read_exact_at`, but returns the byte count at end of device
+// instead of failing.
+fn read_full_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut done = 0;
+
+    while done < buf.len() {
+        match file.read_at(&mut buf[done..], offset + done as u64) {
+            Ok(0) => break,
+            Ok(size) => done += size,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(done)
+}
+
+fn retry<F: FnMut() -> isize>(mut f: F) -> io::Result<usize> {
+    loop {
+        let ret = f();
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+impl RawDevice {
+    // Issue one of the ioctls that take a `[start, len]` byte range.
+    fn range_ioctl(&self, request: libc::Ioctl, offset: u64, len: u64) -> io::Result<()> {
+        let range: [u64; 2] = [offset, len];
+
+        let ret = unsafe { libc::ioctl(self.direct.as_raw_fd(), request, range.as_ptr()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    // User buffers need a power of two alignment of at least the
+    // logical block size, which a few virtual devices report as an
+    // odd value.
+    fn memory_alignment(&self) -> usize {
+        (self.logical_block_size as usize).next_power_of_two()
+    }
+
+    fn read_direct(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if !self.buffered {
+            return read_full_at(&self.direct, buf, offset);
+        }
+
+        drop_cache(&self.direct, offset, buf.len());
+        let size = read_full_at(&self.direct, buf, offset)?;
+        drop_cache(&self.direct, offset, buf.len());
+
+        Ok(size)
+    }
+
+    fn write_direct(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.direct.write_all_at(buf, offset)?;
+        if self.buffered {
+            write_back(&self.direct, offset, buf.len())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Backend for RawDevice {
+    fn path(&self) -> &str {
+        self.dev_path.as_str()
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo::new(self, "block device").with_sysfs(&self.dev_path)
+    }
+
+    fn logical_block_size(&self) -> u64 {
+        self.logical_block_size
+    }
+
+    fn physical_block_size(&self) -> u64 {
+        self.physical_block_size
+    }
+
+    // `O_DIRECT` needs offsets, lengths and memory aligned to the
+    // logical block size.
+    fn alignment(&self) -> u64 {
+        self.logical_block_size
+    }
+
+    // Not when buffered, as the cache would go unflushed.
+    fn raw_fd(&self) -> Option<RawFd> {
+        if self.buffered {
+            return None;
+        }
+
+        Some(self.direct.as_raw_fd())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        read_full_at(&self.file, buf, offset)
+    }
+
+    fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let align = self.memory_alignment();
+        if AlignedBuf::is_aligned(buf, align) {
+            return self.read_direct(buf, offset);
+        }
+
+        let mut bounce = AlignedBuf::new(buf.len(), align);
+        let size = self.read_direct(&mut bounce, offset)?;
+        buf[..size].copy_from_slice(&bounce[..size]);
+
+        Ok(size)
+    }
+
+    fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let align = self.memory_alignment();
+        if AlignedBuf::is_aligned(buf, align) {
+            self.write_direct(buf, offset)?;
+            return Ok(buf.len());
+        }
+
+        let mut bounce = AlignedBuf::new(buf.len(), align);
+        bounce.copy_from_slice(buf);
+        self.write_direct(&bounce, offset)?;
+
+        Ok(buf.len())
+    }
+
+    fn read_vectored_at(&self, bufs: &mut [&mut [u8]], offset: u64) -> io::Result<usize> {
+        let align = self.memory_alignment();
+        if self.buffered || !bufs.iter().all(|b| AlignedBuf::is_aligned(b, align)) {
+            let len = bufs.iter().map(|b| b.len()).sum();
+            let mut bounce = AlignedBuf::new(len, align);
+            let size = self.read_direct(&mut bounce, offset)?;
+
+            let mut pos = 0;
+            for buf in bufs.iter_mut() {
+                let n = buf.len().min(size - pos);
+                buf[..n].copy_from_slice(&bounce[pos..(pos + n)]);
+                pos += n;
+            }
+
+            return Ok(size);
+        }
+
+        let iov: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|b| libc::iovec {
+                iov_base: b.as_mut_ptr() as *mut libc::c_void,
+                iov_len: b.len(),
+            })
+            .collect();
+
+        retry(|| unsafe {
+            libc::preadv(
+                self.direct.as_raw_fd(),
+                iov.as_ptr(),
+                iov.len() as i32,
+                offset as libc::off_t,
+            )
+        })
+    }
+
+    fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> io::Result<usize> {
+        let align = self.memory_alignment();
+        if self.buffered || !bufs.iter().all(|b| AlignedBuf::is_aligned(b, align)) {
+            let len = bufs.iter().map(|b| b.len()).sum();
+            let mut bounce = AlignedBuf::new(len, align);
+
+            let mut pos = 0;
+            for buf in bufs.iter() {
+                bounce[pos..(pos + buf.len())].copy_from_slice(buf);
+                pos += buf.len();
+            }
+
+            self.write_direct(&bounce, offset)?;
+            return Ok(len);
+        }
+
+        let iov: Vec<libc::iovec> = bufs
+            .iter()
+            .map(|b| libc::iovec {
+                iov_base: b.as_ptr() as *mut libc::c_void,
+                iov_len: b.len(),
+            })
+            .collect();
+
+        retry(|| unsafe {
+            libc::pwritev(
+                self.direct.as_raw_fd(),
+                iov.as_ptr(),
+                iov.len() as i32,
+                offset as libc::off_t,
+            )
+        })
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.range_ioctl(BLKDISCARD, offset, len)
+    }
+
+    fn secure_discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.range_ioctl(BLKSECDISCARD, offset, len)
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        self.discard_zeroes_data
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.range_ioctl(BLKZEROOUT, offset, len)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.direct.sync_data()
+    }
+}
@@ -0,0 +1,153 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::backend::Backend;
+use crate::extent::Extent;
+use crate::image::clamp_len;
+use crate::info::DeviceInfo;
+
+// A window of `len` bytes at `start` of another backend. Offsets are
+// relative to the window and nothing outside of it is ever touched.
+#[derive(Debug)]
+pub struct SliceDevice {
+    inner: Arc<dyn Backend>,
+    name: String,
+    start: u64,
+    len: u64,
+}
+
+impl SliceDevice {
+    // The caller checks that the window lies inside `inner`.
+    pub fn new(inner: Arc<dyn Backend>, start: u64, len: u64) -> Self {
+        let name = format!("{}[{}+{}]", inner.path(), start, len);
+
+        SliceDevice {
+            inner,
+            name,
+            start,
+            len,
+        }
+    }
+
+    pub fn get_start(&self) -> u64 {
+        self.start
+    }
+
+    fn clamp(&self, len: usize, offset: u64) -> usize {
+        clamp_len(len, offset, self.len)
+    }
+}
+
+impl Backend for SliceDevice {
+    fn path(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn info(&self) -> DeviceInfo {
+        let mut info = self.inner.info();
+        info.backing.push(format!(
+            "range {}+{} of {}",
+            self.start, self.len, info.path
+        ));
+        info.path = self.name.clone();
+        info.size = self.len;
+
+        info
+    }
+
+    fn logical_block_size(&self) -> u64 {
+        self.inner.logical_block_size()
+    }
+
+    fn physical_block_size(&self) -> u64 {
+        self.inner.physical_block_size()
+    }
+
+    fn alignment(&self) -> u64 {
+        self.inner.alignment()
+    }
+
+    // No `raw_fd`: an engine submitting to the descriptor directly
+    // would bypass the offset translation.
+
+    fn queue_depth(&self) -> usize {
+        self.inner.queue_depth()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = self.clamp(buf.len(), offset);
+        self.inner.read_at(&mut buf[..len], self.start + offset)
+    }
+
+    fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = self.clamp(buf.len(), offset);
+        self.inner
+            .read_direct_at(&mut buf[..len], self.start + offset)
+    }
+
+    fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let len = self.clamp(buf.len(), offset);
+        self.inner.write_direct_at(&buf[..len], self.start + offset)
+    }
+
+    fn read_batch(&self, reqs: &mut [(u64, &mut [u8])]) -> Vec<io::Result<usize>> {
+        let mut reqs: Vec<(u64, &mut [u8])> = reqs
+            .iter_mut()
+            .map(|(offset, buf)| {
+                let len = self.clamp(buf.len(), *offset);
+                (self.start + *offset, &mut buf[..len])
+            })
+            .collect();
+
+        self.inner.read_batch(&mut reqs)
+    }
+
+    fn write_batch(&self, reqs: &[(u64, &[u8])]) -> Vec<io::Result<usize>> {
+        let reqs: Vec<(u64, &[u8])> = reqs
+            .iter()
+            .map(|(offset, buf)| {
+                let len = self.clamp(buf.len(), *offset);
+                (self.start + *offset, &buf[..len])
+            })
+            .collect();
+
+        self.inner.write_batch(&reqs)
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        let len = self.clamp(len as usize, offset) as u64;
+        self.inner.discard(self.start + offset, len)
+    }
+
+    fn secure_discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        let len = self.clamp(len as usize, offset) as u64;
+        self.inner.secure_discard(self.start + offset, len)
+    }
+
+    fn allocation(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let len = self.clamp(len as usize, offset) as u64;
+        let mut extents = self.inner.allocation(self.start + offset, len)?;
+        for e in extents.iter_mut() {
+            e.offset -= self.start;
+        }
+
+        Ok(extents)
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        self.inner.discard_zeroes_data()
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> io::Result<()> {
+        let len = self.clamp(len as usize, offset) as u64;
+        self.inner.write_zeroes(self.start + offset, len)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
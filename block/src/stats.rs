@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+// Each power of two is split into 2^SUB_BITS linear buckets, so a
+// recorded value is off by at most 1/32 of itself.
+const SUB_BITS: u32 = 5;
+const SUB_COUNT: usize = 1 << SUB_BITS;
+const NR_BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_COUNT;
+
+// Latencies in nanoseconds, HDR style: constant relative precision
+// from nanoseconds to hours in a fixed number of buckets.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: vec![0; NR_BUCKETS],
+            total: 0,
+            max: 0,
+        }
+    }
+}
+
+fn bucket(value: u64) -> usize {
+    if value < SUB_COUNT as u64 {
+        return value as usize;
+    }
+
+    let shift = 63 - value.leading_zeros() - SUB_BITS;
+    let sub = (value >> shift) as usize - SUB_COUNT;
+
+    (shift as usize + 1) * SUB_COUNT + sub
+}
+
+// Highest value that falls into `index`.
+fn bucket_max(index: usize) -> u64 {
+    if index < SUB_COUNT {
+        return index as u64;
+    }
+
+    let shift = index / SUB_COUNT - 1;
+    let low = ((SUB_COUNT + index % SUB_COUNT) as u64) << shift;
+
+    low + ((1u64 << shift) - 1)
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let ns = latency.as_nanos().min(u64::MAX as u128) as u64;
+
+        self.counts[bucket(ns)] += 1;
+        self.total += 1;
+        self.max = self.max.max(ns);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += b;
+        }
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    // Latency that `p` percent of the samples do not exceed.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.total == 0 {
+            return Duration::ZERO;
+        }
+
+        let rank = ((p / 100.0 * self.total as f64).ceil() as u64).clamp(1, self.total);
+
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_max(i).min(self.max));
+            }
+        }
+
+        self.max()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+    Discard,
+    Flush,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OpStats {
+    pub ops: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    pub latency: Histogram,
+}
+
+impl OpStats {
+    fn show_info(&self, name: &str) {
+        if self.ops == 0 {
+            return;
+        }
+
+        let h = &self.latency;
+        println!(
+            ">>> {}: {} ops, {} bytes, {} errors, latency p50 {:?} p99 {:?} p99.9 {:?} max {:?}",
+            name,
+            self.ops,
+            self.bytes,
+            self.errors,
+            h.percentile(50.0),
+            h.percentile(99.0),
+            h.percentile(99.9),
+            h.max()
+        );
+    }
+}
+
+// What a device has done since it was opened. Failed requests count
+// as ops and errors but not bytes; their latency is recorded all the
+// same, as a stall that ends in an error is still a stall.
+#[derive(Debug, Clone, Default)]
+pub struct IoStats {
+    pub read: OpStats,
+    pub write: OpStats,
+    pub discard: OpStats,
+    pub flush: OpStats,
+}
+
+impl IoStats {
+    pub fn get(&self, op: Op) -> &OpStats {
+        match op {
+            Op::Read => &self.read,
+            Op::Write => &self.write,
+            Op::Discard => &self.discard,
+            Op::Flush => &self.flush,
+        }
+    }
+
+    pub fn record(&mut self, op: Op, bytes: u64, latency: Duration, ok: bool) {
+        let stats = match op {
+            Op::Read => &mut self.read,
+            Op::Write => &mut self.write,
+            Op::Discard => &mut self.discard,
+            Op::Flush => &mut self.flush,
+        };
+
+        stats.ops += 1;
+        if ok {
+            stats.bytes += bytes;
+        } else {
+            stats.errors += 1;
+        }
+        stats.latency.record(latency);
+    }
+
+    pub fn show_info(&self) {
+        self.read.show_info("read");
+        self.write.show_info("write");
+        self.discard.show_info("discard");
+        self.flush.show_info("flush");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        for v in [0, 31, 32, 33, 63, 64, 1000, 123456789, u64::MAX] {
+            let i = bucket(v);
+            assert!(bucket_max(i) >= v);
+            assert!(i == 0 || bucket_max(i - 1) < v);
+        }
+        assert_eq!(bucket(u64::MAX), NR_BUCKETS - 1);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut h = Histogram::default();
+        assert_eq!(h.percentile(99.0), Duration::ZERO);
+
+        for us in 1..=1000 {
+            h.record(Duration::from_micros(us));
+        }
+        h.record(Duration::from_secs(2));
+
+        let p50 = h.percentile(50.0).as_micros();
+        assert!((485..=516).contains(&p50), "{}", p50);
+        let p99 = h.percentile(99.0).as_micros();
+        assert!((970..=1023).contains(&p99), "{}", p99);
+        assert_eq!(h.percentile(100.0), Duration::from_secs(2));
+        assert_eq!(h.max(), Duration::from_secs(2));
+        assert_eq!(h.count(), 1001);
+    }
+}
@@ -0,0 +1,33 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Kernel name of the block device at `path`, e.g. "nbd0" for
+// "/dev/nbd0". Symlinks such as /dev/disk/by-id/* are resolved first.
+pub fn device_name(path: &str) -> String {
+    let real = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+
+    real.file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+pub fn class_dir(name: &str) -> PathBuf {
+    Path::new("/sys/class/block").join(name)
+}
+
+// Read and trim a sysfs attribute of block device `name`.
+pub fn read_attr(name: &str, attr: &str) -> Option<String> {
+    let s = fs::read_to_string(class_dir(name).join(attr)).ok()?;
+
+    Some(s.trim().to_string())
+}
+
+// Attribute of the whole disk: partitions have no queue or device
+// directory of their own and share the parent disk's.
+pub fn read_disk_attr(name: &str, attr: &str) -> Option<String> {
+    read_attr(name, attr).or_else(|| read_attr(name, &format!("../{}", attr)))
+}
+
+pub fn read_queue_attr(name: &str, attr: &str) -> Option<String> {
+    read_disk_attr(name, &format!("queue/{}", attr))
+}
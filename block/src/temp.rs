@@ -0,0 +1,44 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::Path;
+
+// A path in the temp directory for test fixtures, removed when
+// dropped so that a failing assert doesn't leave the file behind.
+#[derive(Debug)]
+pub struct TempPath {
+    path: String,
+}
+
+impl TempPath {
+    // "virt-tools-<pid>-<name>". Whatever a killed run left there is
+    // removed first.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir()
+            .join(format!("virt-tools-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string();
+        let _ = fs::remove_file(&path);
+
+        TempPath { path }
+    }
+}
+
+impl Deref for TempPath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.path)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Rate limits for a device. Zero means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub read_iops: u64,
+    pub read_bps: u64,
+    pub write_iops: u64,
+    pub write_bps: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ThrottleStats {
+    pub read_ops: u64,
+    pub read_bytes: u64,
+    pub write_ops: u64,
+    pub write_bytes: u64,
+    // Requests that had to wait for tokens, and for how long in total.
+    pub throttled: u64,
+    pub waited: Duration,
+}
+
+impl ThrottleStats {
+    pub fn show_info(&self) {
+        println!(
+            ">>> throttle: read {} ops {} bytes, write {} ops {} bytes, {} throttled for {:?}",
+            self.read_ops,
+            self.read_bytes,
+            self.write_ops,
+            self.write_bytes,
+            self.throttled,
+            self.waited
+        );
+    }
+}
+
+// Tokens accrue at `rate` per second up to one second's worth. A
+// request takes what it needs even when that leaves the bucket in
+// debt, so requests larger than the burst still pass, and whoever
+// comes next waits for the debt to be paid off.
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    // How long to wait before `amount` may be used.
+    fn take(&mut self, amount: u64, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.tokens -= amount as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    limits: Limits,
+    read_iops: Bucket,
+    read_bps: Bucket,
+    write_iops: Bucket,
+    write_bps: Bucket,
+    stats: ThrottleStats,
+}
+
+impl State {
+    fn new(limits: Limits, stats: ThrottleStats) -> Self {
+        State {
+            limits,
+            read_iops: Bucket::new(limits.read_iops),
+            read_bps: Bucket::new(limits.read_bps),
+            write_iops: Bucket::new(limits.write_iops),
+            write_bps: Bucket::new(limits.write_bps),
+            stats,
+        }
+    }
+}
+
+// Token-bucket throttling of the requests to a device, shared by all
+// views of it. Limits can be changed while I/O is running.
+#[derive(Debug)]
+pub struct Throttle {
+    state: Mutex<State>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle::new(Limits::default())
+    }
+}
+
+impl Throttle {
+    pub fn new(limits: Limits) -> Self {
+        Throttle {
+            state: Mutex::new(State::new(limits, ThrottleStats::default())),
+        }
+    }
+
+    // Takes effect from the next request, with full buckets.
+    pub fn set_limits(&self, limits: Limits) {
+        let mut state = self.state.lock().unwrap();
+        let stats = std::mem::take(&mut state.stats);
+
+        *state = State::new(limits, stats);
+    }
+
+    pub fn get_limits(&self) -> Limits {
+        self.state.lock().unwrap().limits
+    }
+
+    pub fn get_stats(&self) -> ThrottleStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    // Block until `ops` reads of `bytes` in total are allowed.
+    pub fn read(&self, ops: u64, bytes: u64) {
+        self.wait(false, ops, bytes);
+    }
+
+    // Block until `ops` writes of `bytes` in total are allowed.
+    pub fn write(&self, ops: u64, bytes: u64) {
+        self.wait(true, ops, bytes);
+    }
+
+    fn wait(&self, write: bool, ops: u64, bytes: u64) {
+        let delay = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+
+            let delay = if write {
+                state.stats.write_ops += ops;
+                state.stats.write_bytes += bytes;
+                let iops = state.write_iops.take(ops, now);
+                iops.max(state.write_bps.take(bytes, now))
+            } else {
+                state.stats.read_ops += ops;
+                state.stats.read_bytes += bytes;
+                let iops = state.read_iops.take(ops, now);
+                iops.max(state.read_bps.take(bytes, now))
+            };
+
+            if !delay.is_zero() {
+                state.stats.throttled += 1;
+                state.stats.waited += delay;
+            }
+
+            delay
+        };
+
+        // Sleep without the lock so that limits and stats can be
+        // looked at meanwhile.
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket_debt() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(100);
+
+        assert!(bucket.take(100, now).is_zero());
+        assert_eq!(bucket.take(50, now), Duration::from_millis(500));
+
+        // Half a second pays off the debt but not more.
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.take(0, later).is_zero());
+        assert_eq!(bucket.take(10, later), Duration::from_millis(100));
+
+        assert!(Bucket::new(0).take(u64::MAX, now).is_zero());
+    }
+}
@@ -0,0 +1,353 @@
+use io_uring::{opcode, squeue, types, EnterFlags, IoUring};
+use std::fmt;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::aligned::AlignedBuf;
+use crate::backend::Backend;
+use crate::extent::Extent;
+use crate::info::DeviceInfo;
+
+// Submits batches of direct I/O through io_uring against the
+// descriptor of another backend, keeping up to `depth` requests in
+// flight. Everything else is passed through to the inner backend.
+pub struct UringDevice {
+    inner: Arc<dyn Backend>,
+    fd: RawFd,
+    depth: usize,
+    ring: Mutex<Ring>,
+}
+
+struct Ring {
+    // None once a failed submission left entries behind that can't be
+    // taken back.
+    ring: Option<IoUring>,
+    // In the upper half of the user data of each entry, so completions
+    // are only ever taken for the batch they belong to.
+    batch: u32,
+}
+
+impl fmt::Debug for UringDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UringDevice")
+            .field("inner", &self.inner)
+            .field("depth", &self.depth)
+            .finish()
+    }
+}
+
+impl UringDevice {
+    // Hands `inner` back if it has no descriptor or the kernel refuses
+    // to set up a ring, so the caller can keep using the pread path.
+    pub fn new(
+        inner: Arc<dyn Backend>,
+        depth: usize,
+    ) -> Result<Self, (Arc<dyn Backend>, io::Error)> {
+        let fd = match inner.raw_fd() {
+            Some(fd) => fd,
+            None => {
+                let e = io::Error::new(io::ErrorKind::Unsupported, "backend has no descriptor");
+                return Err((inner, e));
+            }
+        };
+
+        let depth = depth.max(1);
+        match IoUring::new(depth as u32) {
+            Ok(ring) => Ok(UringDevice {
+                inner,
+                fd,
+                depth,
+                ring: Mutex::new(Ring {
+                    ring: Some(ring),
+                    batch: 0,
+                }),
+            }),
+            Err(e) => Err((inner, e)),
+        }
+    }
+
+    // The descriptor is opened with `O_DIRECT`, which wants memory
+    // aligned to at least the logical block size even where the inner
+    // backend doesn't need callers to align.
+    fn memory_alignment(&self) -> usize {
+        let align = self.inner.alignment().max(self.inner.logical_block_size());
+
+        (align as usize).next_power_of_two()
+    }
+
+    // `O_DIRECT` refuses requests not aligned to what the filesystem
+    // or device wants, which the inner backend has its own way with.
+    fn misaligned(r: &io::Result<usize>) -> bool {
+        matches!(r, Err(e) if e.raw_os_error() == Some(libc::EINVAL))
+    }
+
+    // Submit `entries` and wait for all of them to complete. Their
+    // buffers must stay valid until this returns, so it never returns
+    // with any of them in flight or still queued.
+    fn submit(&self, entries: &[squeue::Entry]) -> Vec<io::Result<usize>> {
+        let mut guard = self.ring.lock().unwrap();
+        let state = &mut *guard;
+
+        let ring = match state.ring.as_mut() {
+            Some(ring) => ring,
+            None => {
+                let e = || io::Error::other("io_uring is unusable after a failed submission");
+                return entries.iter().map(|_| Err(e())).collect();
+            }
+        };
+
+        state.batch = state.batch.wrapping_add(1);
+        let tag = (state.batch as u64) << 32;
+        unsafe {
+            let mut sq = ring.submission();
+            for (i, e) in entries.iter().enumerate() {
+                let e = e.clone().user_data(tag | i as u64);
+                sq.push(&e).expect("batches never exceed the queue depth");
+            }
+        }
+
+        let mut results: Vec<Option<io::Result<usize>>> = entries.iter().map(|_| None).collect();
+        let mut submitted = 0;
+        let mut done = 0;
+        let mut failed = None;
+        while done < entries.len() {
+            match ring.submit_and_wait(entries.len() - done) {
+                Ok(n) => submitted += n,
+                Err(e) if e.raw_os_error() == Some(libc::EINTR) => continue,
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {}
+                Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => {}
+                Err(e) => {
+                    // Anything else means io_uring_enter rejected the
+                    // ring itself, the entries it didn't consume stay
+                    // queued.
+                    failed = Some(e);
+                    break;
+                }
+            }
+
+            done += Self::reap(ring, tag, &mut results);
+        }
+
+        if let Some(e) = failed {
+            // Wait for what is in flight without submitting anything,
+            // falling back to polling the completion queue if even
+            // that fails.
+            while done < submitted {
+                let want = (submitted - done) as u32;
+                let flags = EnterFlags::GETEVENTS.bits();
+                let ret = unsafe {
+                    ring.submitter()
+                        .enter::<libc::sigset_t>(0, want, flags, None)
+                };
+                if ret.is_err() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                done += Self::reap(ring, tag, &mut results);
+            }
+
+            // The unsubmitted entries point into buffers that are
+            // about to go away, drop them with the ring.
+            state.ring = IoUring::new(self.depth as u32).ok();
+
+            return results
+                .into_iter()
+                .map(|r| r.unwrap_or_else(|| Err(io::Error::new(e.kind(), e.to_string()))))
+                .collect();
+        }
+
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    // Take the completions of batch `tag` into `results`, returning how
+    // many there were. Anything else on the ring is not ours to take.
+    fn reap(ring: &mut IoUring, tag: u64, results: &mut [Option<io::Result<usize>>]) -> usize {
+        let mut n = 0;
+
+        for cqe in ring.completion() {
+            let i = cqe.user_data() ^ tag;
+            if i >= results.len() as u64 || results[i as usize].is_some() {
+                continue;
+            }
+
+            let ret = cqe.result();
+            results[i as usize] = Some(if ret < 0 {
+                Err(io::Error::from_raw_os_error(-ret))
+            } else {
+                Ok(ret as usize)
+            });
+            n += 1;
+        }
+
+        n
+    }
+}
+
+impl Backend for UringDevice {
+    fn path(&self) -> &str {
+        self.inner.path()
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            queue_depth: self.depth,
+            ..self.inner.info()
+        }
+    }
+
+    fn logical_block_size(&self) -> u64 {
+        self.inner.logical_block_size()
+    }
+
+    fn physical_block_size(&self) -> u64 {
+        self.inner.physical_block_size()
+    }
+
+    fn alignment(&self) -> u64 {
+        self.inner.alignment()
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.fd)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.depth
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.inner.read_at(buf, offset)
+    }
+
+    fn read_direct_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.read_batch(&mut [(offset, buf)]).remove(0)
+    }
+
+    fn write_direct_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.write_batch(&[(offset, buf)]).remove(0)
+    }
+
+    fn read_batch(&self, reqs: &mut [(u64, &mut [u8])]) -> Vec<io::Result<usize>> {
+        let align = self.memory_alignment();
+        let mut results = Vec::with_capacity(reqs.len());
+
+        for chunk in reqs.chunks_mut(self.depth) {
+            let mut bounces: Vec<Option<AlignedBuf>> = chunk
+                .iter()
+                .map(|(_, buf)| {
+                    if AlignedBuf::is_aligned(buf, align) {
+                        None
+                    } else {
+                        Some(AlignedBuf::new(buf.len(), align))
+                    }
+                })
+                .collect();
+
+            let entries: Vec<squeue::Entry> = chunk
+                .iter_mut()
+                .zip(bounces.iter_mut())
+                .map(|((offset, buf), bounce)| {
+                    let len = buf.len() as u32;
+                    let ptr = match bounce {
+                        Some(b) => b.as_mut_ptr(),
+                        None => buf.as_mut_ptr(),
+                    };
+
+                    opcode::Read::new(types::Fd(self.fd), ptr, len)
+                        .offset(*offset)
+                        .build()
+                })
+                .collect();
+
+            let mut ret = self.submit(&entries);
+            for (((offset, buf), bounce), r) in
+                chunk.iter_mut().zip(bounces.iter()).zip(ret.iter_mut())
+            {
+                if Self::misaligned(r) {
+                    *r = self.inner.read_direct_at(buf, *offset);
+                } else if let (Some(b), Ok(size)) = (bounce, &r) {
+                    buf[..*size].copy_from_slice(&b[..*size]);
+                }
+            }
+            results.extend(ret);
+        }
+
+        results
+    }
+
+    fn write_batch(&self, reqs: &[(u64, &[u8])]) -> Vec<io::Result<usize>> {
+        let align = self.memory_alignment();
+        let mut results = Vec::with_capacity(reqs.len());
+
+        for chunk in reqs.chunks(self.depth) {
+            let bounces: Vec<Option<AlignedBuf>> = chunk
+                .iter()
+                .map(|(_, buf)| {
+                    if AlignedBuf::is_aligned(buf, align) {
+                        None
+                    } else {
+                        let mut b = AlignedBuf::new(buf.len(), align);
+                        b.copy_from_slice(buf);
+                        Some(b)
+                    }
+                })
+                .collect();
+
+            let entries: Vec<squeue::Entry> = chunk
+                .iter()
+                .zip(bounces.iter())
+                .map(|((offset, buf), bounce)| {
+                    let len = buf.len() as u32;
+                    let ptr = match bounce {
+                        Some(b) => b.as_ptr(),
+                        None => buf.as_ptr(),
+                    };
+
+                    opcode::Write::new(types::Fd(self.fd), ptr, len)
+                        .offset(*offset)
+                        .build()
+                })
+                .collect();
+
+            let mut ret = self.submit(&entries);
+            for ((offset, buf), r) in chunk.iter().zip(ret.iter_mut()) {
+                if Self::misaligned(r) {
+                    *r = self.inner.write_direct_at(buf, *offset);
+                }
+            }
+            results.extend(ret);
+        }
+
+        results
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.inner.discard(offset, len)
+    }
+
+    fn secure_discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.inner.secure_discard(offset, len)
+    }
+
+    fn allocation(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        self.inner.allocation(offset, len)
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        self.inner.discard_zeroes_data()
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.inner.write_zeroes(offset, len)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
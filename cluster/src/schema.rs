@@ -1,29 +1,74 @@
 use rand::Rng;
 
-use sector::schema::SectorSchema;
+use block::device::BlockDevice;
+use block::error::BlockError;
+use sector::checksum::Checksum;
+use sector::schema::{check_sector_size, SectorSchema, Uuid, DEFAULT_SECTOR_SIZE};
+use sector::verdict::Verdict;
 
 #[derive(Debug, Default)]
 pub struct ClusterSchema {
     pub buf: Vec<u8>,
     disk_size: u64,
+    cluster_size: u64,
+    sector_size: u64,
     id: u64,
+    run_id: Uuid,
+    generation: u64,
+    checksum: Checksum,
 }
 
-pub const CLUSTER_SIZE: u64 = 512 * 2 * 1024; // 1M
+pub const DEFAULT_CLUSTER_SIZE: u64 = 1024 * 1024;
+pub const MIN_CLUSTER_SIZE: u64 = 4 * 1024;
+pub const MAX_CLUSTER_SIZE: u64 = 64 * 1024 * 1024;
+
+// Whether clusters of `cluster_size` bytes can be laid out in sectors
+// of `sector_size` bytes.
+pub fn check_cluster_size(cluster_size: u64, sector_size: u64) -> Result<(), BlockError> {
+    check_sector_size(sector_size).map_err(BlockError::Unsupported)?;
+    if !(MIN_CLUSTER_SIZE..=MAX_CLUSTER_SIZE).contains(&cluster_size) {
+        return Err(BlockError::Unsupported(format!(
+            "cluster size {} is not between {} and {} bytes",
+            cluster_size, MIN_CLUSTER_SIZE, MAX_CLUSTER_SIZE
+        )));
+    }
+    if !cluster_size.is_multiple_of(sector_size) {
+        return Err(BlockError::Unsupported(format!(
+            "cluster size {} is not a multiple of the {} byte sector size",
+            cluster_size, sector_size
+        )));
+    }
+
+    Ok(())
+}
 
 impl ClusterSchema {
     pub fn new() -> Self {
-        let mut clu = ClusterSchema {
-            buf: Vec::with_capacity(CLUSTER_SIZE as usize),
+        ClusterSchema {
+            buf: vec![0; DEFAULT_CLUSTER_SIZE as usize],
             disk_size: 0,
+            cluster_size: DEFAULT_CLUSTER_SIZE,
+            sector_size: DEFAULT_SECTOR_SIZE,
             id: 0,
-        };
-
-        unsafe {
-            clu.buf.set_len(CLUSTER_SIZE as usize);
+            run_id: Uuid::nil(),
+            generation: 0,
+            checksum: Checksum::default(),
         }
+    }
 
-        clu
+    // See `check_cluster_size` for the sizes that work.
+    pub fn with_cluster_size(mut self, cluster_size: u64) -> Self {
+        self.cluster_size = cluster_size;
+        self.buf = vec![0; cluster_size as usize];
+
+        self
+    }
+
+    // Also see `check_cluster_size`.
+    pub fn with_sector_size(mut self, sector_size: u64) -> Self {
+        self.sector_size = sector_size;
+
+        self
     }
 
     pub fn with_disk_size(mut self, disk_size: u64) -> Self {
@@ -38,6 +83,22 @@ impl ClusterSchema {
         self
     }
 
+    // See `SectorSchema::with_run`.
+    pub fn with_run(mut self, run_id: Uuid, generation: u64) -> Self {
+        self.run_id = run_id;
+        self.generation = generation;
+
+        self
+    }
+
+    // Algorithm `fill` checksums sectors with. Checks go by the one
+    // recorded in each sector.
+    pub fn with_checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+
+        self
+    }
+
     pub fn set_id(&mut self, id: u64) {
         self.id = id;
     }
@@ -46,18 +107,43 @@ impl ClusterSchema {
         self.id
     }
 
+    pub fn get_sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
     pub fn get_cluster_size(&self) -> u64 {
-        CLUSTER_SIZE
+        self.cluster_size
+    }
+
+    // Byte offset of this cluster on the disk.
+    pub fn get_offset(&self) -> u64 {
+        self.id * self.cluster_size
+    }
+
+    pub fn load(&mut self, blk: &BlockDevice) -> Result<(), BlockError> {
+        let offset = self.get_offset();
+        blk.read_direct_at(&mut self.buf, offset)?;
+
+        Ok(())
+    }
+
+    pub fn store(&self, blk: &BlockDevice) -> Result<(), BlockError> {
+        blk.write_direct_at(&self.buf, self.get_offset())?;
+
+        Ok(())
     }
 
     pub fn fill(&mut self) {
         let mut sec = SectorSchema::new()
+            .with_sector_size(self.sector_size)
             .with_disk_size(self.disk_size)
-            .with_cluster_size(CLUSTER_SIZE)
-            .with_cluster_id(self.id);
+            .with_cluster_size(self.cluster_size)
+            .with_cluster_id(self.id)
+            .with_run(self.run_id, self.generation)
+            .with_checksum(self.checksum);
 
         let sector_size = sec.get_sector_size();
-        let nr_sector = CLUSTER_SIZE / sector_size;
+        let nr_sector = self.cluster_size / sector_size;
 
         for i in 0..nr_sector {
             sec.sector_id = i;
@@ -67,16 +153,39 @@ impl ClusterSchema {
     }
 
     pub fn check(&self) -> Vec<u64> {
+        self.check_run(None).0
+    }
+
+    // Returns the sectors that failed verification and, of the others,
+    // those not written by fill run `run` as (run id, generation).
+    pub fn check_run(&self, run: Option<(Uuid, u64)>) -> (Vec<u64>, Vec<u64>) {
+        let (stale, bad): (Vec<_>, Vec<_>) = self
+            .verdicts(run)
+            .into_iter()
+            .partition(|(_, verdict)| verdict.is_stale());
+
+        let ids = |v: Vec<(u64, Verdict)>| v.into_iter().map(|(i, _)| i).collect();
+        (ids(bad), ids(stale))
+    }
+
+    // The sectors that are not intact with what was found in them, see
+    // `SectorSchema::verdict`.
+    pub fn verdicts(&self, run: Option<(Uuid, u64)>) -> Vec<(u64, Verdict)> {
         let mut vec = Vec::new();
-        let mut sec = SectorSchema::new();
+        let mut sec = SectorSchema::new()
+            .with_sector_size(self.sector_size)
+            .with_disk_size(self.disk_size)
+            .with_cluster_size(self.cluster_size)
+            .with_cluster_id(self.id);
 
         let sector_size = sec.get_sector_size();
-        let nr_sector = CLUSTER_SIZE / sector_size;
+        let nr_sector = self.cluster_size / sector_size;
 
         for i in 0..nr_sector {
-            let ok = sec.check(&self.buf, (sector_size * i) as usize);
-            if !ok {
-                vec.push(i);
+            sec.sector_id = i;
+            let verdict = sec.verdict(&self.buf, (sector_size * i) as usize, run);
+            if verdict != Verdict::Intact {
+                vec.push((i, verdict));
             }
         }
 
@@ -85,10 +194,9 @@ impl ClusterSchema {
 
     pub fn inject_error(&mut self) -> bool {
         let mut rng = rand::thread_rng();
-        let sec = SectorSchema::new();
 
-        let sector_size = sec.get_sector_size();
-        let nr_sector = CLUSTER_SIZE / sector_size;
+        let sector_size = self.sector_size;
+        let nr_sector = self.cluster_size / sector_size;
 
         let sector_id = rng.gen_range(0..nr_sector);
 
@@ -1,3 +1,4 @@
+pub mod report;
 pub mod schema;
 
 #[cfg(test)]
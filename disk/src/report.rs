@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+
+use block::error::BlockError;
+use block::extent::{self, Extent};
+use block::info::DeviceInfo;
+use sector::schema::{Uuid, DEFAULT_SECTOR_SIZE};
+use sector::verdict::Verdict;
+
+// Outcome of a verification pass over a disk. I/O errors are recorded
+// per cluster so one bad region doesn't hide the state of the rest.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub path: String,
+    pub device: DeviceInfo,
+    pub nr_cluster: u64,
+    pub cluster_size: u64,
+    pub sector_size: u64,
+    pub checked: u64,
+    pub bad_sectors: Vec<(u64, Vec<u64>)>,
+    // What was found in each of them, by cluster and sector id.
+    pub verdicts: Vec<(u64, u64, Verdict)>,
+    // Run id and generation the disk was checked against, if any was
+    // found, and the sectors left intact from other fills.
+    pub run: Option<(Uuid, u64)>,
+    pub stale_sectors: Vec<(u64, Vec<u64>)>,
+    pub io_errors: Vec<(u64, BlockError)>,
+    // What the backend says holds data, see `BlockDevice::get_allocation`.
+    pub allocation: Vec<Extent>,
+    // Clusters with no data at all, which were not verified.
+    pub unallocated: Vec<u64>,
+    // Clusters with no data that did not read back as zeroes.
+    pub nonzero_holes: Vec<u64>,
+}
+
+impl CheckReport {
+    pub fn new(device: DeviceInfo, nr_cluster: u64, cluster_size: u64) -> Self {
+        CheckReport {
+            path: device.path.clone(),
+            device,
+            nr_cluster,
+            cluster_size,
+            sector_size: DEFAULT_SECTOR_SIZE,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.bad_sectors.is_empty()
+            && self.stale_sectors.is_empty()
+            && self.io_errors.is_empty()
+            && self.unallocated.is_empty()
+            && self.nonzero_holes.is_empty()
+    }
+
+    pub fn show_info(&self) {
+        println!();
+        self.device.show_info();
+
+        println!(
+            ">>> check {}: {}/{} clusters of {} bytes in {} byte sectors checked, {} with bad sectors, {} with I/O errors",
+            self.path,
+            self.checked,
+            self.nr_cluster,
+            self.cluster_size,
+            self.sector_size,
+            self.bad_sectors.len(),
+            self.io_errors.len()
+        );
+
+        if let Some((run_id, generation)) = self.run {
+            let nr_stale: usize = self.stale_sectors.iter().map(|(_, s)| s.len()).sum();
+            println!(
+                ">>> run {} generation {}: {} stale sectors in {} clusters",
+                run_id,
+                generation,
+                nr_stale,
+                self.stale_sectors.len()
+            );
+        }
+
+        if !self.verdicts.is_empty() {
+            let mut counts = BTreeMap::new();
+            for (_, _, verdict) in self.verdicts.iter() {
+                *counts.entry(verdict.name()).or_insert(0) += 1;
+            }
+            let counts: Vec<String> = counts
+                .iter()
+                .map(|(name, n)| format!("{} {}", n, name))
+                .collect();
+            println!(">>> bad sectors: {}", counts.join(", "));
+        }
+
+        let (data, zero, hole) = extent::totals(&self.allocation);
+        println!(
+            ">>> allocation: {} bytes data, {} bytes zero, {} bytes holes in {} extents, {} clusters unallocated",
+            data,
+            zero,
+            hole,
+            self.allocation.len(),
+            self.unallocated.len()
+        );
+
+        for (cluster_id, sectors) in self.stale_sectors.iter() {
+            println!(
+                ">>> cluster {}: {} sectors from another run or generation",
+                cluster_id,
+                sectors.len()
+            );
+        }
+
+        for cluster_id in self.nonzero_holes.iter() {
+            println!(">>> cluster {}: unallocated but not zeroes", cluster_id);
+        }
+
+        for (cluster_id, e) in self.io_errors.iter() {
+            println!(">>> cluster {}: {}", cluster_id, e);
+        }
+    }
+}
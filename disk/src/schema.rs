@@ -1,134 +1,796 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
 use block::device::BlockDevice;
-use cluster::schema::ClusterSchema;
-use sector::schema::SectorSchema;
+use block::error::BlockError;
+use block::extent::{Allocation, Extent};
+use block::throttle::Limits;
+use cluster::schema::{check_cluster_size, ClusterSchema, DEFAULT_CLUSTER_SIZE};
+use sector::checksum::Checksum;
+use sector::schema::{SectorSchema, Uuid, DEFAULT_SECTOR_SIZE};
+use sector::verdict::Verdict;
+
+use crate::report::CheckReport;
 
 pub struct DiskSchema {
-    path: String,
+    blk: BlockDevice,
+    cluster_size: u64,
+    sector_size: u64,
+    verify_holes: bool,
+    // Run to stamp fills with and to expect when checking, if given.
+    run_id: Option<Uuid>,
+    own_run: Uuid,
+    generation: OnceLock<u64>,
+    checksum: Checksum,
 }
 
+// Clusters whose first sector is looked at to find the latest run.
+const RUN_SAMPLES: u64 = 64;
+
 impl DiskSchema {
-    pub fn new(path: &str) -> Self {
+    pub fn new(path: &str) -> Result<Self, BlockError> {
+        let blk = BlockDevice::new(path)?;
+
+        Ok(DiskSchema::from_device(blk))
+    }
+
+    // See `BlockDevice::new_exclusive`.
+    pub fn new_exclusive(path: &str) -> Result<Self, BlockError> {
+        let blk = BlockDevice::new_exclusive(path)?;
+
+        Ok(DiskSchema::from_device(blk))
+    }
+
+    pub fn from_device(blk: BlockDevice) -> Self {
         DiskSchema {
-            path: path.to_string(),
+            blk,
+            cluster_size: DEFAULT_CLUSTER_SIZE,
+            sector_size: DEFAULT_SECTOR_SIZE,
+            verify_holes: false,
+            run_id: None,
+            own_run: Uuid::new_v4(),
+            generation: OnceLock::new(),
+            checksum: Checksum::default(),
         }
     }
 
-    fn show_sector(&self, cluster_id: u64, sector_id: u64) {
-        let blk = BlockDevice::new(self.path.as_str()).unwrap();
-        let disk_size = blk.get_disk_size();
+    // See `BlockDevice::with_queue_depth`.
+    pub fn with_queue_depth(self, depth: usize) -> Self {
+        DiskSchema {
+            blk: self.blk.with_queue_depth(depth),
+            ..self
+        }
+    }
 
-        let mut clu = ClusterSchema::new().with_disk_size(disk_size);
-        let cluster_size = clu.get_cluster_size();
+    // See `BlockDevice::with_force`.
+    pub fn with_force(self, force: bool) -> Self {
+        DiskSchema {
+            blk: self.blk.with_force(force),
+            ..self
+        }
+    }
 
-        let nr_cluster = disk_size / cluster_size;
+    // See `BlockDevice::with_limits`.
+    pub fn with_limits(self, limits: Limits) -> Self {
+        DiskSchema {
+            blk: self.blk.with_limits(limits),
+            ..self
+        }
+    }
 
-        if cluster_id >= nr_cluster {
-            return;
+    // See `BlockDevice::with_reattach`.
+    pub fn with_reattach(self, timeout: Duration, same_path: bool) -> Self {
+        DiskSchema {
+            blk: self.blk.with_reattach(timeout, same_path),
+            ..self
         }
+    }
 
-        blk.read_direct_at(&mut clu.buf, cluster_id * cluster_size);
+    // Fill and check in clusters of `cluster_size` bytes, e.g. to match
+    // the cluster size of a qcow2 image. A disk must be checked with
+    // the cluster size it was filled with.
+    pub fn with_cluster_size(self, cluster_size: u64) -> Result<Self, BlockError> {
+        check_cluster_size(cluster_size, self.sector_size)?;
 
-        let mut sec = SectorSchema::new();
-        sec.deserialize(&clu.buf, (sector_id * sec.get_sector_size()) as usize);
-        sec.show_info();
+        Ok(DiskSchema {
+            cluster_size,
+            ..self
+        })
     }
 
-    pub fn check_disk(&self, cluster_id: u64) {
-        let blk = BlockDevice::new(self.path.as_str()).unwrap();
-        let disk_size = blk.get_disk_size();
+    pub fn get_cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
 
-        let mut clu = ClusterSchema::new().with_disk_size(disk_size);
-        let cluster_size = clu.get_cluster_size();
+    // Stamp sectors of `sector_size` bytes, e.g. 4096 on 4Kn disks so a
+    // torn write within a physical sector is caught. Like the cluster
+    // size it must be the same for fill and check.
+    pub fn with_sector_size(self, sector_size: u64) -> Result<Self, BlockError> {
+        check_cluster_size(self.cluster_size, sector_size)?;
 
-        let nr_cluster = disk_size / cluster_size;
+        Ok(DiskSchema {
+            sector_size,
+            ..self
+        })
+    }
 
-        if cluster_id >= nr_cluster {
-            return;
-        }
+    pub fn get_sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    // Also read the clusters that hold no data according to the
+    // allocation map, to see that they really read back as zeroes.
+    pub fn with_verify_holes(mut self, verify_holes: bool) -> Self {
+        self.verify_holes = verify_holes;
+
+        self
+    }
+
+    // See `ClusterSchema::with_checksum`.
+    pub fn with_checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+
+        self
+    }
+
+    pub fn get_checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    // Stamp fills with `run_id` instead of a fresh one, and check
+    // against its latest generation instead of the latest run's.
+    pub fn with_run_id(mut self, run_id: Uuid) -> Self {
+        self.run_id = Some(run_id);
 
-        blk.read_direct_at(&mut clu.buf, cluster_id * cluster_size);
-        let err_sectors = clu.check();
-        if !err_sectors.is_empty() {
-            println!("\n>>> check error: {:?} - {:?}", cluster_id, err_sectors);
-            for i in 0..err_sectors.len() {
-                self.show_sector(cluster_id, err_sectors[i]);
+        self
+    }
+
+    // Run id and generation this schema fills with. The generation is
+    // one past the latest found on the disk, so it keeps increasing
+    // from one fill to the next.
+    pub fn get_run(&self) -> (Uuid, u64) {
+        let generation = self
+            .generation
+            .get_or_init(|| self.latest_run(None).map_or(1, |(_, g)| g + 1));
+
+        (self.run_id.unwrap_or(self.own_run), *generation)
+    }
+
+    // The newest (run id, generation) stamped in the first sector of
+    // clusters spread over the disk, of run `run_id` if given.
+    fn latest_run(&self, run_id: Option<Uuid>) -> Option<(Uuid, u64)> {
+        let nr_cluster = self.blk.get_disk_size() / self.cluster_size;
+        let samples = nr_cluster.min(RUN_SAMPLES);
+
+        let mut sec = SectorSchema::new().with_sector_size(self.sector_size);
+        let mut buf = vec![0; self.sector_size as usize];
+        let mut latest: Option<(Uuid, u64)> = None;
+        for i in 0..samples {
+            let cluster_id = i * nr_cluster / samples;
+            if self
+                .blk
+                .read_direct_at(&mut buf, cluster_id * self.cluster_size)
+                .is_err()
+            {
+                continue;
+            }
+
+            let ok = sec.check(&buf, 0) && sec.cluster_id == cluster_id && sec.sector_id == 0;
+            if !ok || run_id.is_some_and(|r| r != sec.run_id) {
+                continue;
+            }
+            if latest.is_none_or(|(_, g)| sec.generation > g) {
+                latest = Some((sec.run_id, sec.generation));
             }
         }
+
+        latest
     }
 
-    pub fn check_whole_disk(&self) {
-        let blk = BlockDevice::new(self.path.as_str()).unwrap();
-        let disk_size = blk.get_disk_size();
+    // What a check expects: the run this schema filled the disk with,
+    // or else the latest one on it.
+    fn expected_run(&self) -> Option<(Uuid, u64)> {
+        if self.generation.get().is_some() {
+            return Some(self.get_run());
+        }
 
-        let mut clu = ClusterSchema::new().with_disk_size(disk_size);
+        self.latest_run(self.run_id)
+            .or_else(|| self.run_id.map(|r| (r, 0)))
+    }
+
+    pub fn get_device(&self) -> &BlockDevice {
+        &self.blk
+    }
+
+    // A cluster buffer for this disk positioned at `cluster_id`.
+    fn cluster(&self, cluster_id: u64) -> Result<ClusterSchema, BlockError> {
+        let disk_size = self.blk.get_disk_size();
+
+        let clu = ClusterSchema::new()
+            .with_cluster_size(self.cluster_size)
+            .with_sector_size(self.sector_size)
+            .with_disk_size(disk_size)
+            .with_id(cluster_id);
         let cluster_size = clu.get_cluster_size();
 
         let nr_cluster = disk_size / cluster_size;
+        if cluster_id >= nr_cluster {
+            return Err(BlockError::OutOfRange {
+                offset: clu.get_offset(),
+                len: cluster_size,
+                size: disk_size,
+            });
+        }
 
-        for i in 0..nr_cluster {
-            blk.read_direct_at(&mut clu.buf, i * cluster_size);
-            let err_sectors = clu.check();
-            if !err_sectors.is_empty() {
-                println!("\n>>> check error: {:?} - {:?}", i, err_sectors);
-                for j in 0..err_sectors.len() {
-                    self.show_sector(i, err_sectors[j]);
-                }
+        Ok(clu)
+    }
+
+    // One cluster buffer per request the device keeps in flight.
+    fn cluster_batch(&self) -> Vec<ClusterSchema> {
+        let disk_size = self.blk.get_disk_size();
+
+        (0..self.blk.get_queue_depth().max(1))
+            .map(|_| {
+                ClusterSchema::new()
+                    .with_cluster_size(self.cluster_size)
+                    .with_sector_size(self.sector_size)
+                    .with_disk_size(disk_size)
+            })
+            .collect()
+    }
+
+    // Shows the sector as found, and where its payload differs from
+    // the one `run` would have written, or the run in its own header.
+    fn show_sector(
+        &self,
+        cluster_id: u64,
+        sector_id: u64,
+        run: Option<(Uuid, u64)>,
+    ) -> Result<(), BlockError> {
+        let mut clu = self.cluster(cluster_id)?;
+        clu.load(&self.blk)?;
+
+        let pos = (sector_id * self.sector_size) as usize;
+        let mut sec = SectorSchema::new().with_sector_size(self.sector_size);
+        sec.deserialize(&clu.buf, pos);
+        sec.show_info();
+
+        let (run_id, generation) = run.unwrap_or((sec.run_id, sec.generation));
+        let mut expected = SectorSchema::new()
+            .with_sector_size(self.sector_size)
+            .with_cluster_id(cluster_id)
+            .with_run(run_id, generation);
+        expected.sector_id = sector_id;
+
+        let ranges = expected.payload_diff(&clu.buf, pos);
+        let nr_bytes: usize = ranges.iter().map(|r| r.len()).sum();
+        if ranges.is_empty() {
+            println!(">>> payload intact");
+        } else {
+            println!(">>> payload differs in {} bytes: {:?}", nr_bytes, ranges);
+        }
+
+        Ok(())
+    }
+
+    fn show_errors(&self, cluster_id: u64, bad: &[(u64, Verdict)], run: Option<(Uuid, u64)>) {
+        let err_sectors: Vec<u64> = bad.iter().map(|(i, _)| *i).collect();
+        println!("\n>>> check error: {:?} - {:?}", cluster_id, err_sectors);
+        for (sector_id, verdict) in bad {
+            println!(">>> sector {}: {}", sector_id, verdict);
+            if let Err(e) = self.show_sector(cluster_id, *sector_id, run) {
+                println!(">>> cannot show sector {}: {}", sector_id, e);
             }
         }
     }
 
-    pub fn fill_disk(&self, cluster_id: u64) {
-        let blk = BlockDevice::new(self.path.as_str()).unwrap();
+    // Returns the ids of the sectors in `cluster_id` that failed
+    // verification.
+    pub fn check_disk(&self, cluster_id: u64) -> Result<Vec<u64>, BlockError> {
+        let mut clu = self.cluster(cluster_id)?;
+        clu.load(&self.blk)?;
+
+        let bad = clu.verdicts(None);
+        if !bad.is_empty() {
+            self.show_errors(cluster_id, &bad, self.expected_run());
+        }
+
+        Ok(bad.into_iter().map(|(i, _)| i).collect())
+    }
+
+    pub fn check_whole_disk(&self) -> CheckReport {
+        let blk = &self.blk;
         let disk_size = blk.get_disk_size();
 
-        let mut clu = ClusterSchema::new().with_disk_size(disk_size);
-        let cluster_size = clu.get_cluster_size();
+        let mut batch = self.cluster_batch();
+        let cluster_size = batch[0].get_cluster_size();
 
         let nr_cluster = disk_size / cluster_size;
-        if cluster_id >= nr_cluster {
-            return;
+        let mut report = CheckReport::new(blk.get_info(), nr_cluster, cluster_size);
+        report.sector_size = self.sector_size;
+        report.run = self.expected_run();
+
+        let len = nr_cluster * cluster_size;
+        report.allocation = blk.get_allocation(0, len).unwrap_or_else(|e| {
+            println!(">>> no allocation map: {}", e);
+            vec![Extent::new(0, len, Allocation::Data)]
+        });
+
+        // Clusters without any data are not worth reading, the data
+        // that should be there is gone either way.
+        let mut todo = Vec::new();
+        let mut k = 0;
+        for i in 0..nr_cluster {
+            let (start, end) = (i * cluster_size, (i + 1) * cluster_size);
+            let allocation = &report.allocation;
+            while k < allocation.len() && allocation[k].end() <= start {
+                k += 1;
+            }
+            let data = allocation[k..]
+                .iter()
+                .take_while(|e| e.offset < end)
+                .any(|e| e.state.is_data());
+
+            if !data {
+                report.unallocated.push(i);
+            }
+            if data || self.verify_holes {
+                todo.push((i, data));
+            }
         }
 
-        clu.set_id(cluster_id);
+        for reqs in todo.chunks(batch.len()) {
+            let n = reqs.len();
+
+            let mut bufs: Vec<(u64, &mut [u8])> = batch[..n]
+                .iter_mut()
+                .zip(reqs)
+                .map(|(clu, (i, _))| {
+                    clu.set_id(*i);
+                    (clu.get_offset(), clu.buf.as_mut_slice())
+                })
+                .collect();
+            let results = blk.read_batch(&mut bufs);
+
+            for ((clu, ret), (i, data)) in batch[..n].iter().zip(results).zip(reqs) {
+                let i = *i;
+                if let Err(e) = ret {
+                    let fatal = e.is_fatal();
+                    report.io_errors.push((i, e));
+                    if fatal {
+                        return report;
+                    }
+                    continue;
+                }
+
+                if !data {
+                    if clu.buf.iter().any(|b| *b != 0) {
+                        report.nonzero_holes.push(i);
+                    }
+                    continue;
+                }
+                report.checked += 1;
+
+                let (stale, bad): (Vec<_>, Vec<_>) = clu
+                    .verdicts(report.run)
+                    .into_iter()
+                    .partition(|(_, verdict)| verdict.is_stale());
+                if !bad.is_empty() {
+                    self.show_errors(i, &bad, report.run);
+                    let err_sectors = bad.iter().map(|(s, _)| *s).collect();
+                    report.bad_sectors.push((i, err_sectors));
+                    report
+                        .verdicts
+                        .extend(bad.into_iter().map(|(s, verdict)| (i, s, verdict)));
+                }
+                if !stale.is_empty() {
+                    let stale = stale.into_iter().map(|(s, _)| s).collect();
+                    report.stale_sectors.push((i, stale));
+                }
+            }
+        }
+
+        report
+    }
+
+    pub fn fill_disk(&self, cluster_id: u64) -> Result<(), BlockError> {
+        let (run_id, generation) = self.get_run();
+        let mut clu = self
+            .cluster(cluster_id)?
+            .with_run(run_id, generation)
+            .with_checksum(self.checksum);
+
         clu.fill();
-        blk.write_direct_at(&clu.buf, cluster_id * cluster_size);
+        clu.store(&self.blk)
     }
 
-    pub fn fill_whole_disk(&self) {
-        let blk = BlockDevice::new(self.path.as_str()).unwrap();
+    // Returns the number of clusters written, once they are flushed to
+    // stable storage, so that a check after the VM or host stops sees
+    // all of them.
+    pub fn fill_whole_disk(&self) -> Result<u64, BlockError> {
+        let blk = &self.blk;
         let disk_size = blk.get_disk_size();
 
-        let mut clu = ClusterSchema::new().with_disk_size(disk_size);
-        let cluster_size = clu.get_cluster_size();
+        let (run_id, generation) = self.get_run();
+        let mut batch: Vec<ClusterSchema> = self
+            .cluster_batch()
+            .into_iter()
+            .map(|clu| {
+                clu.with_run(run_id, generation)
+                    .with_checksum(self.checksum)
+            })
+            .collect();
+        let cluster_size = batch[0].get_cluster_size();
 
         let nr_cluster = disk_size / cluster_size;
 
-        for i in 0..nr_cluster {
-            clu.set_id(i);
-            clu.fill();
-            blk.write_direct_at(&clu.buf, i * cluster_size);
+        let mut start = 0;
+        while start < nr_cluster {
+            let n = std::cmp::min(batch.len() as u64, nr_cluster - start) as usize;
+
+            for (j, clu) in batch[..n].iter_mut().enumerate() {
+                clu.set_id(start + j as u64);
+                clu.fill();
+            }
+
+            let reqs: Vec<(u64, &[u8])> = batch[..n]
+                .iter()
+                .map(|clu| (clu.get_offset(), clu.buf.as_slice()))
+                .collect();
+            for ret in blk.write_batch(&reqs) {
+                ret?;
+            }
+
+            start += n as u64;
         }
+        blk.flush()?;
+
+        Ok(nr_cluster)
     }
 
-    pub fn inject_cluster_error(&self, cluster_id: u64) -> bool {
-        let blk = BlockDevice::new(self.path.as_str()).unwrap();
-        let disk_size = blk.get_disk_size();
+    // Byte range covering `count` clusters from `cluster_id`.
+    fn cluster_span(&self, cluster_id: u64, count: u64) -> Result<(u64, u64), BlockError> {
+        let first = self.cluster(cluster_id)?;
+        if count > 0 {
+            self.cluster(cluster_id + count - 1)?;
+        }
 
-        let mut clu = ClusterSchema::new().with_disk_size(disk_size);
-        let cluster_size = clu.get_cluster_size();
+        Ok((first.get_offset(), count * first.get_cluster_size()))
+    }
 
-        let nr_cluster = disk_size / cluster_size;
+    // Discard `count` clusters from `cluster_id`, as a guest TRIM would.
+    pub fn discard_clusters(
+        &self,
+        cluster_id: u64,
+        count: u64,
+        secure: bool,
+    ) -> Result<(), BlockError> {
+        let (offset, len) = self.cluster_span(cluster_id, count)?;
+
+        if secure {
+            self.blk.secure_discard(offset, len)?;
+        } else {
+            self.blk.discard(offset, len)?;
+        }
+        self.blk.flush()
+    }
 
-        if cluster_id >= nr_cluster {
-            return false;
+    pub fn zero_clusters(&self, cluster_id: u64, count: u64) -> Result<(), BlockError> {
+        let (offset, len) = self.cluster_span(cluster_id, count)?;
+
+        self.blk.write_zeroes(offset, len)?;
+        self.blk.flush()
+    }
+
+    // Returns the clusters from `cluster_id` that do not read back as
+    // zeroes. After a discard that only holds if the device advertises
+    // `discard_zeroes_data`.
+    pub fn check_zeroed(&self, cluster_id: u64, count: u64) -> Result<Vec<u64>, BlockError> {
+        self.cluster_span(cluster_id, count)?;
+
+        let mut vec = Vec::new();
+        for i in cluster_id..(cluster_id + count) {
+            let mut clu = self.cluster(i)?;
+            clu.load(&self.blk)?;
+
+            if clu.buf.iter().any(|b| *b != 0) {
+                vec.push(i);
+            }
         }
 
-        blk.read_direct_at(&mut clu.buf, cluster_id * cluster_size);
+        Ok(vec)
+    }
+
+    pub fn inject_cluster_error(&self, cluster_id: u64) -> Result<bool, BlockError> {
+        let mut clu = self.cluster(cluster_id)?;
+        clu.load(&self.blk)?;
 
         let inject_ok = clu.inject_error();
 
-        blk.write_direct_at(&clu.buf, cluster_id * cluster_size);
+        clu.store(&self.blk)?;
+
+        Ok(inject_ok)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use block::backend::Backend;
+    use block::fault::{Fault, FaultDevice};
+    use block::group::{DeviceGroup, Layout};
+    use block::image::ImageFile;
+    use block::memory::MemoryDisk;
+    use block::qcow2::{self, Qcow2Image};
+    use block::temp::TempPath;
+    use std::sync::Arc;
+
+    const DISK_SIZE: u64 = 4 * 1024 * 1024;
+
+    #[test]
+    fn fill_and_check_memory_disk() {
+        let disk = DiskSchema::from_device(BlockDevice::memory(DISK_SIZE));
+        assert_eq!(disk.fill_whole_disk().unwrap(), 4);
+        assert_eq!(disk.get_device().get_stats().flush.ops, 1);
+
+        let report = disk.check_whole_disk();
+        assert_eq!(report.checked, 4);
+        assert!(report.is_clean());
+
+        assert!(disk.inject_cluster_error(2).unwrap());
+        assert!(disk.check_disk(1).unwrap().is_empty());
+        assert!(disk.check_disk(4).is_err());
+    }
+
+    #[test]
+    fn fill_and_check_image_file() {
+        let tmp = TempPath::new("image.img");
+        let path = &*tmp;
+        ImageFile::create(path, DISK_SIZE).unwrap();
 
-        inject_ok
+        let disk = DiskSchema::new(path).unwrap();
+        disk.fill_whole_disk().unwrap();
+        assert!(disk.check_whole_disk().is_clean());
+    }
+
+    #[test]
+    fn fill_and_check_qcow2() {
+        let tmp = TempPath::new("image.qcow2");
+        let path = &*tmp;
+        Qcow2Image::create(path, DISK_SIZE, qcow2::DEFAULT_CLUSTER_BITS, None).unwrap();
+
+        let disk = DiskSchema::new(path).unwrap();
+        assert_eq!(disk.get_device().get_info().kind, "qcow2 image");
+        disk.fill_whole_disk().unwrap();
+        drop(disk);
+
+        // Offline, as after the guest has shut down.
+        let disk = DiskSchema::new(path).unwrap();
+        assert!(disk.check_whole_disk().is_clean());
+    }
+
+    #[test]
+    fn check_skips_holes() {
+        let tmp = TempPath::new("holes.qcow2");
+        let path = &*tmp;
+        Qcow2Image::create(path, DISK_SIZE, qcow2::DEFAULT_CLUSTER_BITS, None).unwrap();
+
+        let disk = DiskSchema::new(path).unwrap();
+        disk.fill_disk(0).unwrap();
+        disk.fill_disk(2).unwrap();
+
+        let report = disk.check_whole_disk();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.unallocated, vec![1, 3]);
+        assert!(report.bad_sectors.is_empty());
+        assert!(!report.is_clean());
+
+        let report = disk.with_verify_holes(true).check_whole_disk();
+        assert_eq!(report.unallocated, vec![1, 3]);
+        assert!(report.nonzero_holes.is_empty());
+    }
+
+    #[test]
+    fn fill_and_check_group() {
+        let members = (0..2).map(|_| BlockDevice::memory(DISK_SIZE / 2)).collect();
+        let group = DeviceGroup::new(Layout::Stripe { chunk: 64 * 1024 }, members).unwrap();
+        let disk = DiskSchema::from_device(BlockDevice::from_backend(Arc::new(group)));
+
+        assert_eq!(disk.fill_whole_disk().unwrap(), 4);
+        assert!(disk.check_whole_disk().is_clean());
+    }
+
+    #[test]
+    fn fill_and_check_cluster_sizes() {
+        let disk = DiskSchema::from_device(BlockDevice::memory(DISK_SIZE));
+        assert!(disk.with_cluster_size(2048).is_err());
+        let disk = DiskSchema::from_device(BlockDevice::memory(DISK_SIZE));
+        assert!(disk.with_cluster_size(64 * 1024 + 100).is_err());
+
+        let blk = BlockDevice::memory(DISK_SIZE);
+        let small = DiskSchema::from_device(blk.slice(0, DISK_SIZE).unwrap())
+            .with_cluster_size(64 * 1024)
+            .unwrap();
+        assert_eq!(small.fill_whole_disk().unwrap(), 64);
+        let report = small.check_whole_disk();
+        assert_eq!(report.checked, 64);
+        assert!(report.is_clean());
+
+        // Clusters are stamped with their own id, so a check with
+        // another cluster size finds nothing in place.
+        let big = DiskSchema::from_device(blk);
+        assert!(!big.check_whole_disk().is_clean());
+    }
+
+    #[test]
+    fn fill_and_check_4k_sectors() {
+        let disk = DiskSchema::from_device(BlockDevice::memory(DISK_SIZE))
+            .with_cluster_size(4096)
+            .unwrap();
+        assert!(disk.with_sector_size(8192).is_err());
+
+        let blk = BlockDevice::memory(DISK_SIZE);
+        let native = DiskSchema::from_device(blk.slice(0, DISK_SIZE).unwrap())
+            .with_sector_size(4096)
+            .unwrap();
+        native.fill_whole_disk().unwrap();
+        let report = native.check_whole_disk();
+        assert_eq!(report.sector_size, 4096);
+        assert!(report.is_clean());
+
+        // Only the first half of a 4K sector made it to the disk.
+        blk.write_direct_at(&[0; 2048], DEFAULT_CLUSTER_SIZE + 4096 + 2048)
+            .unwrap();
+        let report = native.check_whole_disk();
+        assert_eq!(report.bad_sectors, vec![(1, vec![1])]);
+        assert_eq!(
+            report.verdicts,
+            vec![(1, 1, Verdict::Torn { offset: 2048 })]
+        );
+
+        let legacy = DiskSchema::from_device(blk);
+        assert!(legacy.check_whole_disk().stale_sectors.is_empty());
+        assert!(!legacy.check_whole_disk().is_clean());
+    }
+
+    #[test]
+    fn stale_clusters_from_earlier_run() {
+        let mem: Arc<dyn Backend> = Arc::new(MemoryDisk::new(DISK_SIZE));
+        let schema = || DiskSchema::from_device(BlockDevice::from_backend(Arc::clone(&mem)));
+
+        let first = schema();
+        first.fill_whole_disk().unwrap();
+        let (run_id, generation) = first.get_run();
+        assert_eq!(generation, 1);
+
+        let second = schema();
+        second.fill_disk(2).unwrap();
+        assert_eq!(second.get_run().1, 2);
+
+        // The latest run is expected, what is left of the first one is
+        // stale but not corrupt.
+        let report = schema().check_whole_disk();
+        assert_eq!(report.run, Some(second.get_run()));
+        assert!(report.bad_sectors.is_empty());
+        let stale: Vec<u64> = report.stale_sectors.iter().map(|(i, _)| *i).collect();
+        assert_eq!(stale, vec![0, 1, 3]);
+        assert!(!report.is_clean());
+
+        let report = schema().with_run_id(run_id).check_whole_disk();
+        assert_eq!(report.run, Some((run_id, generation)));
+        assert_eq!(report.stale_sectors.len(), 1);
+        assert_eq!(report.stale_sectors[0].0, 2);
+    }
+
+    #[test]
+    fn fill_and_check_checksums() {
+        let blk = BlockDevice::memory(DISK_SIZE);
+        let view = || blk.slice(0, DISK_SIZE).unwrap();
+
+        for checksum in [Checksum::Crc32c, Checksum::Xxh3, Checksum::Blake3] {
+            let disk = DiskSchema::from_device(view()).with_checksum(checksum);
+            disk.fill_whole_disk().unwrap();
+            assert!(disk.check_whole_disk().is_clean(), "{}", checksum);
+
+            view()
+                .write_direct_at(&[0xff; 16], DEFAULT_CLUSTER_SIZE + 100)
+                .unwrap();
+            let report = DiskSchema::from_device(view()).check_whole_disk();
+            assert_eq!(report.bad_sectors.len(), 1, "{}", checksum);
+        }
+    }
+
+    #[test]
+    fn fill_and_check_slice() {
+        let blk = BlockDevice::memory(DISK_SIZE);
+        let disk = DiskSchema::from_device(blk.slice(1024 * 1024, 2 * 1024 * 1024).unwrap());
+        assert_eq!(disk.fill_whole_disk().unwrap(), 2);
+        assert!(disk.check_whole_disk().is_clean());
+
+        // Cluster 0 of the window is cluster 1 of the disk.
+        let whole = DiskSchema::from_device(blk);
+        assert_eq!(whole.check_zeroed(0, 4).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn discard_and_zero_clusters() {
+        let tmp = TempPath::new("zero.img");
+        let path = &*tmp;
+        ImageFile::create(path, DISK_SIZE).unwrap();
+
+        let disk = DiskSchema::new(path).unwrap();
+        disk.fill_whole_disk().unwrap();
+        assert_eq!(disk.check_zeroed(0, 4).unwrap(), vec![0, 1, 2, 3]);
+
+        assert!(disk.get_device().discard_zeroes_data());
+        disk.discard_clusters(1, 1, false).unwrap();
+        disk.zero_clusters(3, 1).unwrap();
+        assert_eq!(disk.check_zeroed(0, 4).unwrap(), vec![0, 2]);
+
+        assert!(disk.discard_clusters(1, 1, true).is_err());
+        assert!(disk.zero_clusters(3, 2).is_err());
+    }
+
+    #[test]
+    fn fill_and_check_with_queue_depth() {
+        let tmp = TempPath::new("qd.img");
+        let path = &*tmp;
+        ImageFile::create(path, DISK_SIZE + 4096).unwrap();
+
+        // Falls back to pread where io_uring is not permitted.
+        let disk = DiskSchema::new(path).unwrap().with_queue_depth(3);
+        assert_eq!(disk.fill_whole_disk().unwrap(), 4);
+
+        let report = disk.check_whole_disk();
+        assert_eq!(report.checked, 4);
+        assert!(report.is_clean());
+    }
+
+    // Faults confined to cluster 1 of a memory disk.
+    fn faulty(inner: &Arc<dyn Backend>, fault: Fault) -> DiskSchema {
+        let dev = FaultDevice::new(Arc::clone(inner), 42)
+            .with_fault(fault, 1.0)
+            .with_range(DEFAULT_CLUSTER_SIZE, DEFAULT_CLUSTER_SIZE);
+
+        DiskSchema::from_device(BlockDevice::from_backend(Arc::new(dev)))
+    }
+
+    #[test]
+    fn check_catches_read_faults() {
+        let inner: Arc<dyn Backend> = Arc::new(MemoryDisk::new(DISK_SIZE));
+        let disk = DiskSchema::from_device(BlockDevice::from_backend(Arc::clone(&inner)));
+        disk.fill_whole_disk().unwrap();
+
+        for fault in [Fault::Eio, Fault::ShortRead] {
+            let report = faulty(&inner, fault).check_whole_disk();
+            assert_eq!(report.checked, 3, "{}", fault);
+            assert_eq!(report.io_errors.len(), 1, "{}", fault);
+            assert_eq!(report.io_errors[0].0, 1, "{}", fault);
+        }
+
+        let report = faulty(&inner, Fault::BitFlip).check_whole_disk();
+        assert_eq!(report.checked, 4);
+        assert_eq!(report.bad_sectors.len(), 1);
+        assert_eq!(report.bad_sectors[0].0, 1);
+        assert_eq!(report.bad_sectors[0].1.len(), 1);
+
+        assert!(disk.check_whole_disk().is_clean());
+    }
+
+    #[test]
+    fn check_catches_write_faults() {
+        for fault in [
+            Fault::DroppedWrite,
+            Fault::TornWrite,
+            Fault::MisdirectedWrite,
+        ] {
+            let inner: Arc<dyn Backend> = Arc::new(MemoryDisk::new(DISK_SIZE));
+            faulty(&inner, fault).fill_whole_disk().unwrap();
+
+            let disk = DiskSchema::from_device(BlockDevice::from_backend(inner));
+            let report = disk.check_whole_disk();
+            assert!(report.io_errors.is_empty(), "{}", fault);
+            assert!(report.bad_sectors.iter().any(|(i, _)| *i == 1), "{}", fault);
+        }
     }
 }
@@ -0,0 +1,96 @@
+use std::fmt;
+use std::str::FromStr;
+
+use sha2::{Digest, Sha256};
+
+// How sectors are checksummed. The value is recorded in the sector
+// header; stamps from before there was a choice read back as zero,
+// which is SHA-256.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Checksum {
+    #[default]
+    Sha256 = 0,
+    // CRC32C uses the SSE 4.2 or ARMv8 CRC instructions where present.
+    Crc32c = 1,
+    // XXH3 64-bit.
+    Xxh3 = 2,
+    Blake3 = 3,
+}
+
+pub const CHECKSUMS: &[Checksum] = &[
+    Checksum::Sha256,
+    Checksum::Crc32c,
+    Checksum::Xxh3,
+    Checksum::Blake3,
+];
+
+impl Checksum {
+    pub fn from_u32(id: u32) -> Option<Self> {
+        CHECKSUMS.iter().copied().find(|c| *c as u32 == id)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Checksum::Sha256 => "sha256",
+            Checksum::Crc32c => "crc32c",
+            Checksum::Xxh3 => "xxh3",
+            Checksum::Blake3 => "blake3",
+        }
+    }
+
+    // Length of the digest in bytes.
+    pub fn digest_len(&self) -> usize {
+        match self {
+            Checksum::Sha256 | Checksum::Blake3 => 32,
+            Checksum::Crc32c => 4,
+            Checksum::Xxh3 => 8,
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Checksum::Sha256 => Sha256::digest(data).to_vec(),
+            Checksum::Crc32c => crc32c::crc32c(data).to_be_bytes().to_vec(),
+            Checksum::Xxh3 => xxhash_rust::xxh3::xxh3_64(data).to_be_bytes().to_vec(),
+            Checksum::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CHECKSUMS
+            .iter()
+            .copied()
+            .find(|c| c.name() == s)
+            .ok_or_else(|| format!("unknown checksum {:?}", s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn digests() {
+        // Check values of the algorithms for "123456789".
+        let data = b"123456789";
+        assert_eq!(Checksum::Crc32c.digest(data), 0xe3069283u32.to_be_bytes());
+        assert_eq!(Checksum::Sha256.digest(data)[..4], [0x15, 0xe2, 0xb0, 0xd3]);
+
+        for c in CHECKSUMS {
+            assert_eq!(c.digest(data).len(), c.digest_len());
+            assert_eq!(Checksum::from_u32(*c as u32), Some(*c));
+            assert_eq!(c.name().parse::<Checksum>(), Ok(*c));
+        }
+        assert!("md5".parse::<Checksum>().is_err());
+    }
+}
@@ -1,4 +1,7 @@
+pub mod checksum;
+pub mod payload;
 pub mod schema;
+pub mod verdict;
 
 #[cfg(test)]
 mod tests {
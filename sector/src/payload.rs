@@ -0,0 +1,89 @@
+use std::ops::Range;
+
+use uuid::Uuid;
+
+// Generates the bytes between the header and the digest of a sector.
+// The stream only depends on where and by which fill the sector was
+// written, so a check can produce it again, and it doesn't compress
+// or dedup. SplitMix64, which is plenty for test data.
+pub struct Payload {
+    state: u64,
+}
+
+const GAMMA: u64 = 0x9e3779b97f4a7c15;
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl Payload {
+    pub fn new(run_id: Uuid, cluster_id: u64, sector_id: u64, generation: u64) -> Self {
+        let (hi, lo) = run_id.as_u64_pair();
+
+        let state = [hi, lo, cluster_id, sector_id, generation]
+            .iter()
+            .fold(0u64, |s, v| mix(s.wrapping_add(GAMMA) ^ v));
+
+        Payload { state }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GAMMA);
+        mix(self.state)
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_be_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+// Ranges of byte offsets at which `actual` differs from `expected`.
+pub fn diff(expected: &[u8], actual: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+
+    for (i, (e, a)) in expected.iter().zip(actual).enumerate() {
+        if e == a {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(r) if r.end == i => r.end += 1,
+            _ => ranges.push(i..(i + 1)),
+        }
+    }
+
+    ranges
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn regenerate_and_diff() {
+        let run_id = Uuid::new_v4();
+        let mut a = vec![0; 100];
+        let mut b = vec![0; 100];
+        Payload::new(run_id, 1, 2, 3).fill(&mut a);
+        Payload::new(run_id, 1, 2, 3).fill(&mut b);
+        assert_eq!(a, b);
+        assert!(diff(&a, &b).is_empty());
+
+        // Every part of the seed matters.
+        Payload::new(run_id, 1, 3, 2).fill(&mut b);
+        assert_ne!(a, b);
+        Payload::new(run_id, 1, 2, 4).fill(&mut b);
+        assert_ne!(a, b);
+
+        b.copy_from_slice(&a);
+        b[10..20].fill(!a[10]);
+        b[99] ^= 0x10;
+        let ranges = diff(&a, &b);
+        assert_eq!(ranges.last(), Some(&(99..100)));
+        assert!(ranges[0].start >= 10 && ranges[0].end <= 20);
+    }
+}
@@ -1,14 +1,65 @@
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use chrono::prelude::{DateTime, Local};
 use sha2::{Digest, Sha256};
 
+use crate::checksum::Checksum;
+use crate::payload::{self, Payload};
+use crate::verdict::{bit_diff, is_pattern, Verdict, MAX_BITROT_BITS};
+
 use byteorder::{BigEndian, ByteOrder};
 use positioned_io::WriteAt;
 
+pub use uuid::Uuid;
+
 const MAX_STRING_LENGTH: usize = 68;
-const SECTOR_SIZE: u64 = 512;
+
+pub const DEFAULT_SECTOR_SIZE: u64 = 512;
+pub const MIN_SECTOR_SIZE: u64 = 512;
+pub const MAX_SECTOR_SIZE: u64 = 1024 * 1024;
+
+// v1 stamps were only ever 512 bytes.
+const V1_SECTOR_SIZE: usize = 512;
 
 const MAGIC: u32 = 0x434653fb; // CFS
-const VERSION: u32 = 1;
+
+// v1: text timestamp after the fixed fields, SHA-256 as a hex string
+// in the last MAX_STRING_LENGTH bytes. Still read, no longer written.
+pub const VERSION_1: u32 = 1;
+// v2: run id, write generation, timestamp and checksum algorithm in
+// binary after the fixed fields, the binary digest at the start of the
+// last DIGEST_LENGTH bytes and zeroes after it.
+pub const VERSION_2: u32 = 2;
+const VERSION: u32 = VERSION_2;
+
+// Where v2 records the checksum algorithm, and the end of the v2
+// header padded to 8 bytes.
+const CHECKSUM_OFFSET: usize = 88;
+// Where v2 records the run id and generation, and the timestamp.
+const RUN_OFFSET: usize = 56;
+const TIMESTAMP_OFFSET: usize = 80;
+const HEADER_LENGTH: usize = 96;
+// Room for the longest digest.
+const DIGEST_LENGTH: usize = 32;
+
+// v2 sectors with this flag have the space between the header and the
+// digest filled by `Payload`, those without it have zeroes there.
+pub const FLAG_PAYLOAD: u64 = 1 << 0;
+
+// Whether sectors of `sector_size` bytes can be stamped: a power of
+// two, with room for the header and digest.
+pub fn check_sector_size(sector_size: u64) -> Result<(), String> {
+    if !sector_size.is_power_of_two() || !(MIN_SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&sector_size)
+    {
+        return Err(format!(
+            "sector size {} is not a power of two between {} and {} bytes",
+            sector_size, MIN_SECTOR_SIZE, MAX_SECTOR_SIZE
+        ));
+    }
+
+    Ok(())
+}
 
 #[derive(Debug, Default)]
 pub struct SectorSchema {
@@ -20,30 +71,59 @@ pub struct SectorSchema {
     pub disk_size: u64,     // 8
     cluster_size: u64,      // 8
     sector_size: u64,       // 8
-    pub local_time: String, // [u8; MAX_STRING_LENGTH]
-    pub reversed: String,
+    pub run_id: Uuid,       // 16, v2
+    pub generation: u64,    // 8, v2
+    pub timestamp: u64,     // 8, v2, nanoseconds since the epoch
+    pub checksum: Checksum, // 4, v2, + 4 padding
+    pub local_time: String, // v1: [u8; MAX_STRING_LENGTH], v2: from timestamp
+
+    // sector tail, v1: hex string in [u8; MAX_STRING_LENGTH]
+    pub digest: Vec<u8>,
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn format_nanos(nanos: u64) -> String {
+    let utc = DateTime::from_timestamp_nanos(nanos as i64);
+
+    utc.with_timezone(&Local).to_string()
+}
+
+// "1f3a..." -> [0x1f, 0x3a, ...], empty if it isn't hex.
+fn from_hex(s: &str) -> Vec<u8> {
+    if !s.is_ascii() || !s.len().is_multiple_of(2) {
+        return Vec::new();
+    }
 
-    // sector tail
-    pub sha256: String, // [u8; MAX_STRING_LENGTH]
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..(i + 2)], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .unwrap_or_default()
 }
 
 impl SectorSchema {
     pub fn new() -> Self {
-        let now: DateTime<Local> = Local::now();
-        let sec = SectorSchema {
+        let timestamp = now_nanos();
+
+        SectorSchema {
             magic: MAGIC,
             version: VERSION,
-            flags: 0,
+            flags: FLAG_PAYLOAD,
             cluster_id: 0,
             sector_id: 0,
             disk_size: 0,
             cluster_size: 0,
-            sector_size: SECTOR_SIZE,
-            local_time: now.to_string(),
+            sector_size: DEFAULT_SECTOR_SIZE,
+            timestamp,
+            local_time: format_nanos(timestamp),
             ..Default::default()
-        };
-
-        sec
+        }
     }
 
     pub fn with_disk_size(mut self, disk_size: u64) -> Self {
@@ -64,31 +144,97 @@ impl SectorSchema {
         self
     }
 
+    // Fill run that writes the sector and how many fills of the disk
+    // came before it, so a check can tell leftovers of an earlier run
+    // from corruption.
+    pub fn with_run(mut self, run_id: Uuid, generation: u64) -> Self {
+        self.run_id = run_id;
+        self.generation = generation;
+
+        self
+    }
+
+    pub fn with_checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+
+        self
+    }
+
+    // The header stays at the start and the digest at the end, see
+    // `check_sector_size` for the sizes that work.
+    pub fn with_sector_size(mut self, sector_size: u64) -> Self {
+        self.sector_size = sector_size;
+
+        self
+    }
+
     pub fn get_sector_size(&self) -> u64 {
         self.sector_size
     }
 
-    fn cacle_hash(&self) -> String {
-        let buf_len = (SECTOR_SIZE as usize) - MAX_STRING_LENGTH;
+    // Bytes covered by the digest, and where the digest is stored.
+    fn digest_range(&self) -> (usize, usize) {
+        let sector_size = self.sector_size as usize;
+
+        match self.version {
+            VERSION_1 => (V1_SECTOR_SIZE - MAX_STRING_LENGTH, MAX_STRING_LENGTH),
+            _ => (sector_size - DIGEST_LENGTH, DIGEST_LENGTH),
+        }
+    }
+
+    // Where the payload is in a v2 sector.
+    fn payload_range(&self) -> Range<usize> {
+        HEADER_LENGTH..(self.sector_size as usize - DIGEST_LENGTH)
+    }
+
+    // The payload this stamp is written with.
+    pub fn payload(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.payload_range().len()];
+        if self.flags & FLAG_PAYLOAD != 0 {
+            Payload::new(
+                self.run_id,
+                self.cluster_id,
+                self.sector_id,
+                self.generation,
+            )
+            .fill(&mut buf);
+        }
+
+        buf
+    }
+
+    // Byte ranges within the sector at `pos` whose payload differs
+    // from what this stamp would have written there.
+    pub fn payload_diff(&self, buf: &[u8], pos: usize) -> Vec<Range<usize>> {
+        let range = self.payload_range();
+        let actual = &buf[(pos + range.start)..(pos + range.end)];
+
+        payload::diff(&self.payload(), actual)
+            .into_iter()
+            .map(|r| (r.start + range.start)..(r.end + range.start))
+            .collect()
+    }
+
+    // Of the stamp as `serialize` writes it.
+    fn cacle_hash(&self) -> Vec<u8> {
+        let buf_len = (self.sector_size as usize) - DIGEST_LENGTH;
 
         let mut buf = vec![0; buf_len];
 
         self.head_to_vec(&mut buf, 0);
+        buf[self.payload_range()].copy_from_slice(&self.payload());
 
-        let mut hasher = Sha256::new();
-        hasher.update(&buf);
-
-        format!("{:x}", hasher.finalize())
+        self.checksum.digest(&buf)
     }
 
     pub fn update_hash(&mut self) -> &mut Self {
-        self.sha256 = self.cacle_hash();
+        self.digest = self.cacle_hash();
         self
     }
 
     pub fn update_time(&mut self) -> &mut Self {
-        let now: DateTime<Local> = Local::now();
-        self.local_time = now.to_string();
+        self.timestamp = now_nanos();
+        self.local_time = format_nanos(self.timestamp);
 
         self
     }
@@ -97,7 +243,7 @@ impl SectorSchema {
         BigEndian::write_u32(&mut buf[pos..], self.magic);
         pos += std::mem::size_of_val(&self.magic);
 
-        BigEndian::write_u32(&mut buf[pos..], self.version);
+        BigEndian::write_u32(&mut buf[pos..], VERSION);
         pos += std::mem::size_of_val(&self.version);
 
         BigEndian::write_u64(&mut buf[pos..], self.flags);
@@ -118,19 +264,33 @@ impl SectorSchema {
         BigEndian::write_u64(&mut buf[pos..], self.sector_size);
         pos += std::mem::size_of_val(&self.sector_size);
 
-        buf.write_all_at(pos as u64, self.local_time.as_bytes())
-            .unwrap();
+        buf[pos..(pos + 16)].copy_from_slice(self.run_id.as_bytes());
+        pos += 16;
+
+        BigEndian::write_u64(&mut buf[pos..], self.generation);
+        pos += std::mem::size_of_val(&self.generation);
+
+        BigEndian::write_u64(&mut buf[pos..], self.timestamp);
+        pos += std::mem::size_of_val(&self.timestamp);
+
+        BigEndian::write_u32(&mut buf[pos..], self.checksum as u32);
     }
 
+    // Always in the current format.
     pub fn serialize(&self, buf: &mut Vec<u8>, mut pos: usize) {
+        // The hash is taken over a zeroed sector, so clear whatever a
+        // previous fill left behind before writing the new stamp.
+        buf[pos..(pos + self.sector_size as usize)].fill(0);
+
         self.head_to_vec(buf, pos);
+        let range = self.payload_range();
+        buf[(pos + range.start)..(pos + range.end)].copy_from_slice(&self.payload());
 
-        pos = pos + (SECTOR_SIZE as usize) - MAX_STRING_LENGTH;
-        buf.write_all_at(pos as u64, self.sha256.as_bytes())
-            .unwrap();
+        pos = pos + (self.sector_size as usize) - DIGEST_LENGTH;
+        buf.write_all_at(pos as u64, &self.digest).unwrap();
     }
 
-    pub fn deserialize(&mut self, buf: &Vec<u8>, mut pos: usize) {
+    pub fn deserialize(&mut self, buf: &[u8], mut pos: usize) {
         let start_pos = pos;
 
         self.magic = BigEndian::read_u32(&buf[pos..]);
@@ -154,37 +314,453 @@ impl SectorSchema {
         self.cluster_size = BigEndian::read_u64(&buf[pos..]);
         pos += std::mem::size_of_val(&self.cluster_size);
 
-        self.sector_size = BigEndian::read_u64(&buf[pos..]);
+        // The layout only depends on the size we were built with, not
+        // on what the stamp claims.
         pos += std::mem::size_of_val(&self.sector_size);
+        let (digest_pos, digest_len) = self.digest_range();
+        let digest_pos = start_pos + digest_pos;
+
+        if self.version == VERSION_1 {
+            self.run_id = Uuid::nil();
+            self.generation = 0;
+            self.timestamp = 0;
+            self.checksum = Checksum::Sha256;
+
+            let s = &buf[pos..(pos + MAX_STRING_LENGTH)];
+            self.local_time = String::from_utf8_lossy(s)
+                .trim_end_matches('\0')
+                .to_string();
+
+            let s = std::str::from_utf8(&buf[digest_pos..(digest_pos + digest_len)])
+                .unwrap_or("")
+                .trim_end_matches('\0');
+            self.digest = from_hex(s);
+        } else {
+            let mut run_id = [0; 16];
+            run_id.copy_from_slice(&buf[pos..(pos + 16)]);
+            self.run_id = Uuid::from_bytes(run_id);
+            pos += 16;
+
+            self.generation = BigEndian::read_u64(&buf[pos..]);
+            pos += std::mem::size_of_val(&self.generation);
+
+            self.timestamp = BigEndian::read_u64(&buf[pos..]);
+            self.local_time = format_nanos(self.timestamp);
+            pos += std::mem::size_of_val(&self.timestamp);
+
+            // Unknown algorithms fail `check`.
+            let id = BigEndian::read_u32(&buf[pos..]);
+            self.checksum = Checksum::from_u32(id).unwrap_or_default();
+
+            let len = self.checksum.digest_len().min(digest_len);
+            self.digest = buf[digest_pos..(digest_pos + len)].to_vec();
+        }
+    }
 
-        let s = &buf[pos..(pos + MAX_STRING_LENGTH)];
-        self.local_time = String::from_utf8_lossy(s).to_string();
-        pos += MAX_STRING_LENGTH;
-
-        let s = &buf[pos..(start_pos + (SECTOR_SIZE as usize) - MAX_STRING_LENGTH)];
-        self.reversed = String::from_utf8_lossy(s).to_string();
+    // Whether the sector at `pos` holds an intact stamp of either
+    // version, which is then deserialized into `self`.
+    pub fn check(&mut self, buf: &[u8], pos: usize) -> bool {
+        self.deserialize(buf, pos);
+        if self.magic != MAGIC || !matches!(self.version, VERSION_1 | VERSION_2) {
+            return false;
+        }
+
+        let (len, digest_len) = self.digest_range();
+        let data = &buf[pos..(pos + len)];
+        if self.version == VERSION_1 {
+            return self.digest == Sha256::digest(data).as_slice();
+        }
+
+        let id = BigEndian::read_u32(&buf[(pos + CHECKSUM_OFFSET)..]);
+        let padding = &buf[(pos + len + self.digest.len())..(pos + len + digest_len)];
+
+        Checksum::from_u32(id) == Some(self.checksum)
+            && padding.iter().all(|b| *b == 0)
+            && self.digest == self.checksum.digest(data)
+    }
 
-        let pos = start_pos + (SECTOR_SIZE as usize) - MAX_STRING_LENGTH;
-        let s = &buf[pos..(pos + MAX_STRING_LENGTH)];
+    // What the sector at `pos` holds compared to the stamp this schema
+    // would write there, expecting fill run `run` as (run id,
+    // generation) if given.
+    pub fn verdict(&self, buf: &[u8], pos: usize, run: Option<(Uuid, u64)>) -> Verdict {
+        let sector = &buf[pos..(pos + self.sector_size as usize)];
+
+        let mut found = SectorSchema::new().with_sector_size(self.sector_size);
+        if found.check(buf, pos) {
+            if (found.cluster_id, found.sector_id) != (self.cluster_id, self.sector_id) {
+                return Verdict::Misplaced {
+                    cluster_id: found.cluster_id,
+                    sector_id: found.sector_id,
+                };
+            }
+
+            return match run {
+                Some((run_id, _)) if run_id != found.run_id => Verdict::WrongRun {
+                    run_id: found.run_id,
+                    generation: found.generation,
+                },
+                Some((_, generation)) if generation != found.generation => Verdict::Stale {
+                    generation: found.generation,
+                },
+                _ => Verdict::Intact,
+            };
+        }
+
+        if sector.iter().all(|b| *b == 0) {
+            return Verdict::AllZero;
+        }
+        if is_pattern(sector) {
+            return Verdict::NeverWritten;
+        }
+
+        if let Some(bits) = self.bitrot(sector, run) {
+            return Verdict::BitRot { bits };
+        }
+
+        if found.magic == MAGIC && found.version == VERSION_2 {
+            if let Some(offset) = self.tear(&found, sector, run) {
+                return Verdict::Torn { offset };
+            }
+        }
+
+        Verdict::Garbage
+    }
 
-        self.sha256 = std::str::from_utf8(s)
-            .unwrap_or("None")
-            .trim_end_matches('\0')
-            .to_string();
+    // Header fields that can't be known from where the sector is: the
+    // time and checksum algorithm, and the run if none is expected.
+    fn unknown_fields(run: Option<(Uuid, u64)>) -> Range<usize> {
+        match run {
+            Some(_) => TIMESTAMP_OFFSET..(CHECKSUM_OFFSET + 4),
+            None => RUN_OFFSET..(CHECKSUM_OFFSET + 4),
+        }
     }
 
-    pub fn check(&mut self, buf: &Vec<u8>, pos: usize) -> bool {
-        let mut hasher = Sha256::new();
-        let sha256_buf = &buf[pos..(pos + SECTOR_SIZE as usize - MAX_STRING_LENGTH)];
-        hasher.update(sha256_buf);
-        let sha256_string = format!("{:x}", hasher.finalize());
+    // The bits in which `sector` differs from the stamp that was most
+    // likely written there, if there are only a few. What is known is
+    // compared first. The rest is taken from the header as found, and
+    // then with each of its bits flipped in turn, until a stamp comes
+    // close enough, digest and all.
+    fn bitrot(&self, sector: &[u8], run: Option<(Uuid, u64)>) -> Option<Vec<usize>> {
+        let unknown = Self::unknown_fields(run);
 
-        self.deserialize(buf, pos);
+        let (run_id, generation) = run.unwrap_or_default();
+        let expected = SectorSchema {
+            magic: MAGIC,
+            flags: FLAG_PAYLOAD,
+            cluster_id: self.cluster_id,
+            sector_id: self.sector_id,
+            disk_size: self.disk_size,
+            cluster_size: self.cluster_size,
+            sector_size: self.sector_size,
+            run_id,
+            generation,
+            ..Default::default()
+        };
+        let mut header = vec![0; HEADER_LENGTH];
+        expected.head_to_vec(&mut header, 0);
+
+        let known = [
+            (&header[..unknown.start], &sector[..unknown.start]),
+            (&header[unknown.end..], &sector[unknown.end..HEADER_LENGTH]),
+        ];
+        let mut budget = MAX_BITROT_BITS;
+        for (a, b) in known {
+            budget -= bit_diff(a, b, budget)?.len();
+        }
+
+        // Only the run decides the payload, which is cheaper to compare
+        // than the digest is to compute.
+        let payload = &sector[self.payload_range()];
+        let mut close: Option<((Uuid, u64), bool)> = None;
+
+        let mut found = sector[..HEADER_LENGTH].to_vec();
+        let flips = unknown.flat_map(|i| (0..8).map(move |b| (i, 1 << b)));
+        std::iter::once((0, 0)).chain(flips).find_map(|(i, mask)| {
+            found[i] ^= mask;
+            let stamp = self.rebuild(&found, run);
+            found[i] ^= mask;
+            let mut stamp = stamp?;
+
+            let key = (stamp.run_id, stamp.generation);
+            if close.is_none_or(|(k, _)| k != key) {
+                let near = bit_diff(&stamp.payload(), payload, budget).is_some();
+                close = Some((key, near));
+            }
+            if close.is_some_and(|(_, near)| !near) {
+                return None;
+            }
+
+            stamp.update_hash();
+            let mut buf = vec![0; self.sector_size as usize];
+            stamp.serialize(&mut buf, 0);
+
+            bit_diff(&buf, sector, MAX_BITROT_BITS)
+        })
+    }
 
-        self.sha256 == sha256_string
+    // The stamp this schema would write with the fields it can't know
+    // taken from `header`, if it names a known checksum.
+    fn rebuild(&self, header: &[u8], run: Option<(Uuid, u64)>) -> Option<SectorSchema> {
+        let (run_id, generation) = run.unwrap_or_else(|| {
+            let run_id = &header[RUN_OFFSET..(RUN_OFFSET + 16)];
+            (
+                Uuid::from_slice(run_id).unwrap(),
+                BigEndian::read_u64(&header[(RUN_OFFSET + 16)..]),
+            )
+        });
+        let checksum = Checksum::from_u32(BigEndian::read_u32(&header[CHECKSUM_OFFSET..]))?;
+
+        Some(SectorSchema {
+            magic: MAGIC,
+            version: VERSION,
+            flags: FLAG_PAYLOAD,
+            cluster_id: self.cluster_id,
+            sector_id: self.sector_id,
+            disk_size: self.disk_size,
+            cluster_size: self.cluster_size,
+            sector_size: self.sector_size,
+            run_id,
+            generation,
+            timestamp: BigEndian::read_u64(&header[TIMESTAMP_OFFSET..]),
+            checksum,
+            ..Default::default()
+        })
+    }
+
+    // Where the write the header of `found` belongs to ends in `sector`,
+    // if what follows is from another write: the payload of the header
+    // stops matching there and hardly matches after it. If it doesn't
+    // match at all, only the header is from another write when the
+    // payload is the expected one.
+    fn tear(&self, found: &SectorSchema, sector: &[u8], run: Option<(Uuid, u64)>) -> Option<usize> {
+        let range = found.payload_range();
+        let actual = &sector[range.clone()];
+
+        let own = found.payload();
+        let offset = own.iter().zip(actual).position(|(a, b)| a != b)?;
+        if offset == 0 {
+            let (run_id, generation) = run?;
+            let expected = SectorSchema {
+                flags: FLAG_PAYLOAD,
+                cluster_id: self.cluster_id,
+                sector_id: self.sector_id,
+                sector_size: self.sector_size,
+                run_id,
+                generation,
+                ..Default::default()
+            };
+
+            return (expected.payload() == actual).then_some(range.start);
+        }
+
+        let same = own[offset..]
+            .iter()
+            .zip(&actual[offset..])
+            .filter(|(a, b)| a == b)
+            .count();
+
+        (same * 16 < actual.len() - offset).then_some(range.start + offset)
     }
 
     pub fn show_info(&self) {
         println!("{:?}\n", self);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn v2_round_trip() {
+        let run_id = Uuid::new_v4();
+        let mut sec = SectorSchema::new()
+            .with_disk_size(1 << 30)
+            .with_cluster_size(1 << 20)
+            .with_cluster_id(7)
+            .with_run(run_id, 3);
+        sec.sector_id = 5;
+        sec.update_hash();
+
+        let mut buf = vec![0xff; 1024];
+        sec.serialize(&mut buf, 512);
+
+        let mut out = SectorSchema::new();
+        assert!(out.check(&buf, 512));
+        assert_eq!(out.version, VERSION_2);
+        assert_eq!((out.cluster_id, out.sector_id), (7, 5));
+        assert_eq!(out.run_id, run_id);
+        assert_eq!(out.generation, 3);
+        assert_eq!(out.timestamp, sec.timestamp);
+        assert_eq!(out.digest.len(), DIGEST_LENGTH);
+
+        assert!(sec.payload_diff(&buf, 512).is_empty());
+        assert_ne!(sec.payload(), vec![0; 512 - HEADER_LENGTH - DIGEST_LENGTH]);
+
+        buf[512 + 100] ^= 1;
+        buf[512 + 200..512 + 210].copy_from_slice(&[0; 10]);
+        assert!(!out.check(&buf, 512));
+        let ranges = sec.payload_diff(&buf, 512);
+        assert_eq!(ranges[0], 100..101);
+        assert!(ranges[1..].iter().all(|r| r.start >= 200 && r.end <= 210));
+        assert!(!out.check(&vec![0; 512], 0));
+    }
+
+    #[test]
+    fn native_4k_sectors() {
+        assert!(check_sector_size(4096).is_ok());
+        assert!(check_sector_size(256).is_err());
+        assert!(check_sector_size(1536).is_err());
+
+        let mut sec = SectorSchema::new().with_sector_size(4096);
+        sec.update_hash();
+        let mut buf = vec![0; 8192];
+        sec.serialize(&mut buf, 4096);
+
+        let mut out = SectorSchema::new().with_sector_size(4096);
+        assert!(out.check(&buf, 4096));
+        assert_eq!(out.get_sector_size(), 4096);
+
+        // A torn write that kept the old tail of the sector.
+        buf[4096 + 2048..].fill(0);
+        assert!(!out.check(&buf, 4096));
+        // The first 512 bytes on their own are no stamp.
+        assert!(!SectorSchema::new().check(&buf, 4096));
+    }
+
+    #[test]
+    fn verdicts() {
+        let run_id = Uuid::new_v4();
+        let stamp = |generation| {
+            let mut sec = SectorSchema::new()
+                .with_disk_size(1 << 30)
+                .with_cluster_size(1 << 20)
+                .with_cluster_id(7)
+                .with_run(run_id, generation);
+            sec.sector_id = 5;
+            sec.update_hash();
+            let mut buf = vec![0; 512];
+            sec.serialize(&mut buf, 0);
+            (sec, buf)
+        };
+        let (sec, buf) = stamp(3);
+        let run = Some((run_id, 3));
+
+        assert_eq!(sec.verdict(&buf, 0, run), Verdict::Intact);
+        assert_eq!(sec.verdict(&buf, 0, None), Verdict::Intact);
+        let stale = Verdict::Stale { generation: 3 };
+        assert_eq!(sec.verdict(&buf, 0, Some((run_id, 4))), stale);
+        let other = Some((Uuid::new_v4(), 3));
+        let wrong = Verdict::WrongRun {
+            run_id,
+            generation: 3,
+        };
+        assert_eq!(sec.verdict(&buf, 0, other), wrong);
+
+        let mut elsewhere = SectorSchema::new().with_cluster_id(7);
+        elsewhere.sector_id = 6;
+        let misplaced = Verdict::Misplaced {
+            cluster_id: 7,
+            sector_id: 5,
+        };
+        assert_eq!(elsewhere.verdict(&buf, 0, run), misplaced);
+
+        assert_eq!(sec.verdict(&[0; 512], 0, run), Verdict::AllZero);
+        assert_eq!(sec.verdict(&[0xff; 512], 0, run), Verdict::NeverWritten);
+
+        let mut bad = buf.clone();
+        bad[20] ^= 0x02;
+        bad[300] ^= 0x08;
+        let bits = vec![20 * 8 + 1, 300 * 8 + 3];
+        assert_eq!(sec.verdict(&bad, 0, run), Verdict::BitRot { bits });
+
+        // In fields that a damaged sector can't be checked against,
+        // with and without an expected run.
+        let mut bad = buf.clone();
+        bad[82] ^= 0x04;
+        bad[450] ^= 0x80;
+        let bits = vec![82 * 8 + 2, 450 * 8 + 7];
+        assert_eq!(sec.verdict(&bad, 0, run), Verdict::BitRot { bits });
+        let mut bad = buf.clone();
+        bad[CHECKSUM_OFFSET + 3] ^= 0x02;
+        let bits = vec![(CHECKSUM_OFFSET + 3) * 8 + 1];
+        assert_eq!(sec.verdict(&bad, 0, run), Verdict::BitRot { bits });
+        let mut bad = buf.clone();
+        bad[RUN_OFFSET + 20] ^= 0x10;
+        bad[20] ^= 0x02;
+        let bits = vec![20 * 8 + 1, (RUN_OFFSET + 20) * 8 + 4];
+        assert_eq!(sec.verdict(&bad, 0, None), Verdict::BitRot { bits });
+
+        // The second half of the sector is from the next fill.
+        let (_, next) = stamp(4);
+        let mut bad = buf.clone();
+        bad[256..].copy_from_slice(&next[256..]);
+        assert_eq!(sec.verdict(&bad, 0, run), Verdict::Torn { offset: 256 });
+        // Only the header is.
+        let mut bad = buf.clone();
+        bad[..HEADER_LENGTH].copy_from_slice(&next[..HEADER_LENGTH]);
+        let torn = Verdict::Torn {
+            offset: HEADER_LENGTH,
+        };
+        assert_eq!(sec.verdict(&bad, 0, run), torn);
+
+        let junk: Vec<u8> = (0..512).map(|i| (i * 7 % 251) as u8).collect();
+        assert_eq!(sec.verdict(&junk, 0, run), Verdict::Garbage);
+    }
+
+    #[test]
+    fn checksums() {
+        for checksum in crate::checksum::CHECKSUMS {
+            let mut sec = SectorSchema::new().with_checksum(*checksum);
+            sec.update_hash();
+            let mut buf = vec![0; 512];
+            sec.serialize(&mut buf, 0);
+
+            let mut out = SectorSchema::new();
+            assert!(out.check(&buf, 0), "{}", checksum);
+            assert_eq!(out.checksum, *checksum);
+
+            // Flips in the data, the digest and the padding after it.
+            for pos in [300, 480, 511] {
+                let mut bad = buf.clone();
+                bad[pos] ^= 0x10;
+                assert!(!out.check(&bad, 0), "{} {}", checksum, pos);
+            }
+        }
+    }
+
+    // As written by v1: text time after the fixed fields and the hex
+    // SHA-256 of everything before it at the end.
+    fn v1_sector(cluster_id: u64, sector_id: u64) -> Vec<u8> {
+        let mut buf = vec![0; 512];
+        let fields = [0, cluster_id, sector_id, 1 << 30, 1 << 20, 512];
+
+        BigEndian::write_u32(&mut buf[0..], MAGIC);
+        BigEndian::write_u32(&mut buf[4..], VERSION_1);
+        for (i, v) in fields.iter().enumerate() {
+            BigEndian::write_u64(&mut buf[(8 + 8 * i)..], *v);
+        }
+        let time = b"2023-01-01 00:00:00.000000000 +08:00";
+        buf[56..(56 + time.len())].copy_from_slice(time);
+
+        let hex = format!("{:x}", Sha256::digest(&buf[..444]));
+        buf[444..508].copy_from_slice(hex.as_bytes());
+
+        buf
+    }
+
+    #[test]
+    fn v1_still_readable() {
+        let mut buf = v1_sector(3, 9);
+        let mut sec = SectorSchema::new();
+
+        assert!(sec.check(&buf, 0));
+        assert_eq!(sec.version, VERSION_1);
+        assert_eq!((sec.cluster_id, sec.sector_id), (3, 9));
+        assert!(sec.run_id.is_nil());
+        assert!(sec.local_time.starts_with("2023-01-01"));
+
+        buf[200] = 1;
+        assert!(!sec.check(&buf, 0));
+    }
+}
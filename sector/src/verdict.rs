@@ -0,0 +1,99 @@
+use std::fmt;
+
+use uuid::Uuid;
+
+// More flipped bits than this is no longer bit-rot.
+pub const MAX_BITROT_BITS: usize = 16;
+
+// What was found in a sector compared to what should be there, see
+// `SectorSchema::verdict`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Intact,
+    // Zeroes, e.g. a lost write to a thin device or a discard.
+    AllZero,
+    // A repeated byte pattern that none of our stamps has, e.g. the
+    // 0xff of erased flash or what a backend initialised the disk with.
+    NeverWritten,
+    // An intact stamp of the expected run from another fill of it.
+    Stale { generation: u64 },
+    // An intact stamp of another run.
+    WrongRun { run_id: Uuid, generation: u64 },
+    // An intact stamp meant for another place on the disk.
+    Misplaced { cluster_id: u64, sector_id: u64 },
+    // The bytes from `offset` on come from another write than the
+    // header.
+    Torn { offset: usize },
+    // A few bits differ from the stamp that should be there, as bit
+    // offsets within the sector.
+    BitRot { bits: Vec<usize> },
+    Garbage,
+}
+
+impl Verdict {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Verdict::Intact => "intact",
+            Verdict::AllZero => "all-zero",
+            Verdict::NeverWritten => "never-written",
+            Verdict::Stale { .. } => "stale",
+            Verdict::WrongRun { .. } => "wrong-run",
+            Verdict::Misplaced { .. } => "misplaced",
+            Verdict::Torn { .. } => "torn",
+            Verdict::BitRot { .. } => "bit-rot",
+            Verdict::Garbage => "garbage",
+        }
+    }
+
+    // Whether the sector holds an intact stamp of its own place, just
+    // not from the expected fill.
+    pub fn is_stale(&self) -> bool {
+        matches!(self, Verdict::Stale { .. } | Verdict::WrongRun { .. })
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Stale { generation } => write!(f, "stale, generation {}", generation),
+            Verdict::WrongRun { run_id, generation } => {
+                write!(f, "wrong run {} generation {}", run_id, generation)
+            }
+            Verdict::Misplaced {
+                cluster_id,
+                sector_id,
+            } => write!(
+                f,
+                "misplaced, stamped for cluster {} sector {}",
+                cluster_id, sector_id
+            ),
+            Verdict::Torn { offset } => write!(f, "torn at byte {}", offset),
+            Verdict::BitRot { bits } => write!(f, "bit-rot at bits {:?}", bits),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+// Whether `buf` is its first 8 bytes over and over.
+pub fn is_pattern(buf: &[u8]) -> bool {
+    buf.chunks(8).all(|c| c == &buf[..c.len()])
+}
+
+// Offsets of the bits in which `a` and `b` differ, as long as there
+// are no more than `max`.
+pub fn bit_diff(a: &[u8], b: &[u8], max: usize) -> Option<Vec<usize>> {
+    let mut bits = Vec::new();
+
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        let mut d = x ^ y;
+        while d != 0 {
+            bits.push(i * 8 + d.trailing_zeros() as usize);
+            if bits.len() > max {
+                return None;
+            }
+            d &= d - 1;
+        }
+    }
+
+    Some(bits)
+}
@@ -1,9 +1,14 @@
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::sync::Arc;
 use std::{thread, time};
 
 use block::device::BlockDevice;
+use block::error::BlockError;
+use block::group::{DeviceGroup, Layout};
+use block::throttle::Limits;
 use disk::schema::DiskSchema;
-use sector::schema::SectorSchema;
+use sector::checksum::Checksum;
+use sector::schema::{SectorSchema, Uuid};
 use stress::schema::StressSchema;
 
 use vncclient::{argparse, vnc};
@@ -18,6 +23,103 @@ fn run_stress(nr_cpu: usize) {
     s.kill();
 }
 
+fn get_queue_depth(matches: &ArgMatches) -> usize {
+    match matches.get_one::<String>("queue-depth") {
+        Some(depth) => match depth.parse::<usize>() {
+            Ok(depth) => depth,
+            Err(_) => {
+                println!("error: option <queue-depth> need a integer");
+                1
+            }
+        },
+        None => 1,
+    }
+}
+
+// Returns 0, i.e. unlimited, if the option is absent or malformed.
+fn get_limit(matches: &ArgMatches, name: &str) -> u64 {
+    match matches.get_one::<String>(name) {
+        Some(limit) => match limit.parse::<u64>() {
+            Ok(limit) => limit,
+            Err(_) => {
+                println!("error: option <{}> need a integer", name);
+                0
+            }
+        },
+        None => 0,
+    }
+}
+
+// Open the disks given with -D: one on its own, or several as a group
+// laid out as --layout says. Members are set up for the subcommand
+// before they are grouped, as the group can't do that for them.
+fn open_disk(matches: &ArgMatches) -> Result<(DiskSchema, Option<Arc<DeviceGroup>>), BlockError> {
+    let paths: Vec<&str> = matches.values_of("device").unwrap().collect();
+    if paths.len() == 1 {
+        return Ok((DiskSchema::new_exclusive(paths[0])?, None));
+    }
+
+    let depth = matches
+        .subcommand_matches("disk-write")
+        .or_else(|| matches.subcommand_matches("disk-check"))
+        .map_or(1, get_queue_depth);
+    let force = matches
+        .subcommand_matches("disk-write")
+        .or_else(|| matches.subcommand_matches("disk-inject-fault"))
+        .is_some_and(|m| m.is_present("force"));
+
+    let mut members = Vec::new();
+    for path in paths {
+        let mut blk = BlockDevice::new_exclusive(path)?
+            .with_queue_depth(depth)
+            .with_force(force);
+        if let Some((timeout, same_path)) = get_reattach(matches) {
+            blk = blk.with_reattach(timeout, same_path);
+        }
+        members.push(blk);
+    }
+
+    let layout = match matches.value_of("layout").unwrap() {
+        "concat" => Layout::Concat,
+        "mirror" => Layout::Mirror,
+        _ => Layout::Stripe {
+            chunk: matches.value_of("chunk").unwrap().parse().unwrap_or(0),
+        },
+    };
+    let group = DeviceGroup::new(layout, members)?.with_compare(matches.is_present("compare"));
+    let group = Arc::new(group);
+
+    let blk = BlockDevice::from_backend(group.clone());
+    Ok((DiskSchema::from_device(blk), Some(group)))
+}
+
+// How long to wait for an unplugged disk to come back, if at all, and
+// whether to take any disk at its path for it.
+fn get_reattach(matches: &ArgMatches) -> Option<(time::Duration, bool)> {
+    let secs = matches.get_one::<String>("reattach")?;
+    let same_path = matches.is_present("reattach-same-path");
+    match secs.parse::<u64>() {
+        Ok(secs) => Some((time::Duration::from_secs(secs), same_path)),
+        Err(_) => {
+            println!("error: option <reattach> need a integer");
+            None
+        }
+    }
+}
+
+// Print what happened to the disks under test while they were watched
+// for hotplug.
+fn show_timelines(disk: &DiskSchema, group: &Option<Arc<DeviceGroup>>) {
+    let mut timelines: Vec<_> = disk.get_device().get_timeline().into_iter().collect();
+    if let Some(group) = group {
+        timelines.extend(group.get_members().iter().filter_map(|m| m.get_timeline()));
+    }
+
+    for timeline in timelines {
+        timeline.show_info();
+    }
+}
+
 fn main() {
     let opts = argparse::parse().unwrap();
 
@@ -25,10 +127,75 @@ fn main() {
 }
 
 fn main2() {
-    let dev_path = "/dev/nbd0";
-    let disk = DiskSchema::new(dev_path);
-
     let matches = App::new("KVM virtualization development tools.")
+        .arg(
+            Arg::with_name("device")
+                .short('D')
+                .long("device")
+                .takes_value(true)
+                .default_value("/dev/nbd0")
+                .multiple_occurrences(true)
+                .help("Disk to test: a block device, an image file or an NBD URI. Repeat to test several as a group"),
+        )
+        .arg(
+            Arg::with_name("layout")
+                .long("layout")
+                .takes_value(true)
+                .possible_values(["concat", "stripe", "mirror"])
+                .default_value("stripe")
+                .help("How a group of disks is used as one"),
+        )
+        .arg(
+            Arg::with_name("chunk")
+                .long("chunk")
+                .takes_value(true)
+                .default_value("65536")
+                .help("Bytes of each disk in turn in a stripe"),
+        )
+        .arg(
+            Arg::with_name("compare")
+                .long("compare")
+                .help("Read all disks of a mirror and report where they differ"),
+        )
+        .arg(
+            Arg::with_name("cluster-size")
+                .long("cluster-size")
+                .takes_value(true)
+                .default_value("1048576")
+                .help("Fill and check in clusters of N bytes, from 4096 to 64 MiB"),
+        )
+        .arg(
+            Arg::with_name("sector-size")
+                .long("sector-size")
+                .takes_value(true)
+                .default_value("512")
+                .help("Stamp sectors of N bytes, a power of two such as 4096 for 4Kn disks"),
+        )
+        .arg(
+            Arg::with_name("checksum")
+                .long("checksum")
+                .takes_value(true)
+                .possible_values(["sha256", "crc32c", "xxh3", "blake3"])
+                .default_value("sha256")
+                .help("Checksum sectors are filled with; checks use the one in each sector"),
+        )
+        .arg(
+            Arg::with_name("run")
+                .long("run")
+                .takes_value(true)
+                .help("Fill with run id UUID, or check against it instead of the latest run"),
+        )
+        .arg(
+            Arg::with_name("reattach")
+                .long("reattach")
+                .takes_value(true)
+                .help("Wait up to N seconds for an unplugged disk to come back, then resume"),
+        )
+        .arg(
+            Arg::with_name("reattach-same-path")
+                .long("reattach-same-path")
+                .help("With --reattach, take any disk at the same path back for one without serial or WWN"),
+        )
         .subcommand(
             SubCommand::with_name("stress")
                 .about("Imposes certain types of compute stress on your system.")
@@ -60,28 +227,122 @@ fn main2() {
                 ),
         )
         .subcommand(
-            SubCommand::with_name("disk-write").arg(
-                Arg::with_name("debug")
-                    .short('d')
-                    .help("print debug information verbosely"),
-            ),
+            SubCommand::with_name("disk-write")
+                .arg(
+                    Arg::with_name("debug")
+                        .short('d')
+                        .help("print debug information verbosely"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .short('f')
+                        .long("force")
+                        .help("Overwrite the disk even if it looks in use"),
+                )
+                .arg(
+                    Arg::with_name("queue-depth")
+                        .short('Q')
+                        .long("queue-depth")
+                        .takes_value(true)
+                        .help("Keep N requests in flight using io_uring"),
+                )
+                .arg(
+                    Arg::with_name("iops")
+                        .long("iops")
+                        .takes_value(true)
+                        .help("Write at most N requests per second"),
+                )
+                .arg(
+                    Arg::with_name("bps")
+                        .long("bps")
+                        .takes_value(true)
+                        .help("Write at most N bytes per second"),
+                ),
         )
         .subcommand(
-            SubCommand::with_name("disk-check").arg(
-                Arg::with_name("debug")
-                    .short('d')
-                    .help("print debug information verbosely"),
-            ),
+            SubCommand::with_name("disk-check")
+                .arg(
+                    Arg::with_name("debug")
+                        .short('d')
+                        .help("print debug information verbosely"),
+                )
+                .arg(
+                    Arg::with_name("queue-depth")
+                        .short('Q')
+                        .long("queue-depth")
+                        .takes_value(true)
+                        .help("Keep N requests in flight using io_uring"),
+                )
+                .arg(
+                    Arg::with_name("iops")
+                        .long("iops")
+                        .takes_value(true)
+                        .help("Read at most N requests per second"),
+                )
+                .arg(
+                    Arg::with_name("bps")
+                        .long("bps")
+                        .takes_value(true)
+                        .help("Read at most N bytes per second"),
+                )
+                .arg(
+                    Arg::with_name("verify-holes")
+                        .long("verify-holes")
+                        .help("Also read clusters without data to see they are zeroes"),
+                ),
         )
         .subcommand(
-            SubCommand::with_name("disk-inject-fault").arg(
-                Arg::with_name("debug")
-                    .short('d')
-                    .help("print debug information verbosely"),
-            ),
+            SubCommand::with_name("disk-inject-fault")
+                .arg(
+                    Arg::with_name("debug")
+                        .short('d')
+                        .help("print debug information verbosely"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .short('f')
+                        .long("force")
+                        .help("Overwrite the disk even if it looks in use"),
+                ),
         )
         .get_matches();
 
+    let reattach = get_reattach(&matches);
+    let cluster_size = matches
+        .value_of("cluster-size")
+        .unwrap()
+        .parse()
+        .unwrap_or(0);
+    let sector_size = matches
+        .value_of("sector-size")
+        .unwrap()
+        .parse()
+        .unwrap_or(0);
+    let (mut disk, group) = match open_disk(&matches).and_then(|(disk, group)| {
+        let disk = disk
+            .with_cluster_size(cluster_size)?
+            .with_sector_size(sector_size)?;
+        Ok((disk, group))
+    }) {
+        Ok(disk) => disk,
+        Err(e) => {
+            println!("error: {}", e);
+            return;
+        }
+    };
+    if let Some(checksum) = matches.value_of("checksum") {
+        disk = disk.with_checksum(checksum.parse().unwrap_or(Checksum::Sha256));
+    }
+    if let Some(run) = matches.value_of("run") {
+        match Uuid::parse_str(run) {
+            Ok(run_id) => disk = disk.with_run_id(run_id),
+            Err(e) => {
+                println!("error: option <run>: {}", e);
+                return;
+            }
+        }
+    }
+
     if let Some(matches) = matches.subcommand_matches("stress") {
         if matches.is_present("quiet") {
             println!("Quiet level...");
@@ -103,7 +364,31 @@ fn main2() {
         } else {
             println!("Printing normally...");
         }
-        disk.fill_whole_disk();
+
+        let mut disk = disk
+            .with_queue_depth(get_queue_depth(matches))
+            .with_force(matches.is_present("force"))
+            .with_limits(Limits {
+                write_iops: get_limit(matches, "iops"),
+                write_bps: get_limit(matches, "bps"),
+                ..Default::default()
+            });
+        if let (Some((timeout, same_path)), None) = (reattach, &group) {
+            disk = disk.with_reattach(timeout, same_path);
+        }
+        let (run_id, generation) = disk.get_run();
+        println!(
+            ">>> fill run {} generation {} checksum {}",
+            run_id,
+            generation,
+            disk.get_checksum()
+        );
+        if let Err(e) = disk.fill_whole_disk() {
+            println!("\n>>> fill error: {}", e);
+        }
+        show_timelines(&disk, &group);
+        disk.get_device().get_stats().show_info();
+        disk.get_device().get_throttle_stats().show_info();
     } else if let Some(matches) = matches.subcommand_matches("disk-check") {
         if matches.is_present("debug") {
             println!("Printing debug info...");
@@ -111,7 +396,25 @@ fn main2() {
             println!("Printing normally...");
         }
 
-        disk.check_whole_disk();
+        let mut disk = disk
+            .with_queue_depth(get_queue_depth(matches))
+            .with_limits(Limits {
+                read_iops: get_limit(matches, "iops"),
+                read_bps: get_limit(matches, "bps"),
+                ..Default::default()
+            })
+            .with_verify_holes(matches.is_present("verify-holes"));
+        if let (Some((timeout, same_path)), None) = (reattach, &group) {
+            disk = disk.with_reattach(timeout, same_path);
+        }
+        let report = disk.check_whole_disk();
+        report.show_info();
+        show_timelines(&disk, &group);
+        if let Some(group) = group.filter(|g| g.get_layout() == Layout::Mirror) {
+            group.show_divergences();
+        }
+        disk.get_device().get_stats().show_info();
+        disk.get_device().get_throttle_stats().show_info();
     } else if let Some(matches) = matches.subcommand_matches("disk-inject-fault") {
         if matches.is_present("debug") {
             println!("Printing debug info...");
@@ -119,8 +422,11 @@ fn main2() {
             println!("Printing normally...");
         }
 
-        let inject_ok = disk.inject_cluster_error(0);
-        println!("\n>>> inject:{:?}", inject_ok);
+        let disk = disk.with_force(matches.is_present("force"));
+        match disk.inject_cluster_error(0) {
+            Ok(inject_ok) => println!("\n>>> inject:{:?}", inject_ok),
+            Err(e) => println!("\n>>> inject error: {}", e),
+        }
     }
 }
 
@@ -132,7 +438,7 @@ fn main_bak() {
     let blk = BlockDevice::new(dev_path).unwrap();
 
     let mut buf = vec![0; 4096];
-    let read_len = blk.read_direct_at(&mut buf, 512 * 512);
+    let read_len = blk.read_direct_at(&mut buf, 512 * 512).unwrap();
     println!("\n>>> Read len: {}, buf len: {}", read_len, buf.len());
 
     let mut sec = SectorSchema::new();